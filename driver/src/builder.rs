@@ -0,0 +1,319 @@
+//! Declarative setup of an `Interface`.
+
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use crate::device::gsusb::GS_CAN_FEATURE_HW_TIMESTAMP;
+use crate::device::{Backend, Device, DeviceOptions, UsbContext};
+use crate::{calculate_bit_timing, Channel, Error, Interface, TimeBase, TimestampMode};
+
+/// Selects which attached device an `InterfaceBuilder` opens.
+#[derive(Debug, Clone, PartialEq)]
+pub enum DeviceSelector {
+    /// The first device found.
+    First,
+    /// The nth device found, zero indexed.
+    Index(usize),
+    /// The device with the given USB serial number.
+    Serial(String),
+}
+
+/// Builder for an `Interface`.
+///
+/// The complete configuration is checked against the device's features when
+/// `build` is called, and all problems are reported at once:
+///
+/// ```no_run
+/// use cantact::{Channel, Interface};
+///
+/// let mut i = Interface::builder()
+///     .channel(0, Channel {
+///         bitrate: 500_000,
+///         enabled: true,
+///         loopback: false,
+///         monitor: true,
+///         fd: false,
+///         data_bitrate: 0,
+///         sample_point: Some(0.875),
+///         sjw: None,
+///         one_shot: false,
+///         triple_sample: false,
+///         name: None,
+///     })
+///     .build()
+///     .unwrap();
+/// i.start(|f| println!("{:?}", f)).unwrap();
+/// ```
+#[derive(Debug, Clone)]
+pub struct InterfaceBuilder {
+    selector: DeviceSelector,
+    channels: BTreeMap<usize, Channel>,
+    control_timeout: Duration,
+    send_timeout: Duration,
+    rx_queue_size: Option<usize>,
+    timestamp_mode: TimestampMode,
+    time_base: Option<TimeBase>,
+}
+
+impl Default for InterfaceBuilder {
+    fn default() -> InterfaceBuilder {
+        InterfaceBuilder::new()
+    }
+}
+
+impl InterfaceBuilder {
+    /// Returns a builder for the first device found, with no channels enabled.
+    pub fn new() -> InterfaceBuilder {
+        let options = DeviceOptions::default();
+        InterfaceBuilder {
+            selector: DeviceSelector::First,
+            channels: BTreeMap::new(),
+            control_timeout: Duration::from_millis(options.control_timeout_ms as u64),
+            send_timeout: Duration::from_millis(options.send_timeout_ms as u64),
+            rx_queue_size: options.rx_queue_size,
+            timestamp_mode: TimestampMode::Host,
+            time_base: None,
+        }
+    }
+
+    /// Select the device to open.
+    pub fn device(mut self, selector: DeviceSelector) -> InterfaceBuilder {
+        self.selector = selector;
+        self
+    }
+
+    /// Set the configuration of a channel. Channels without a configuration
+    /// are disabled.
+    pub fn channel(mut self, channel: usize, config: Channel) -> InterfaceBuilder {
+        self.channels.insert(channel, config);
+        self
+    }
+
+    /// Set the timeout for configuration requests to the device.
+    pub fn control_timeout(mut self, timeout: Duration) -> InterfaceBuilder {
+        self.control_timeout = timeout;
+        self
+    }
+
+    /// Set the timeout for transmitting a frame to the device.
+    pub fn send_timeout(mut self, timeout: Duration) -> InterfaceBuilder {
+        self.send_timeout = timeout;
+        self
+    }
+
+    /// Limit the number of received frames waiting to be passed to the
+    /// receive callback. When the queue is full, received frames are dropped.
+    /// By default, the queue is unbounded.
+    pub fn rx_queue_size(mut self, size: usize) -> InterfaceBuilder {
+        self.rx_queue_size = Some(size);
+        self
+    }
+
+    /// Set the source of timestamps for received frames.
+    pub fn timestamp_mode(mut self, mode: TimestampMode) -> InterfaceBuilder {
+        self.timestamp_mode = mode;
+        self
+    }
+
+    /// Measure timestamps from `base`, which may be shared with other
+    /// interfaces. See `Interface::set_time_base`.
+    pub fn time_base(mut self, base: TimeBase) -> InterfaceBuilder {
+        self.time_base = Some(base);
+        self
+    }
+
+    /// Open the device and apply the configuration, returning an interface
+    /// that is ready to start.
+    ///
+    /// If the configuration is not valid for the device,
+    /// `Error::InvalidConfiguration` is returned containing every problem found.
+    pub fn build(self) -> Result<Interface, Error> {
+        let options = DeviceOptions {
+            control_timeout_ms: self.control_timeout.as_millis() as u32,
+            send_timeout_ms: self.send_timeout.as_millis() as u32,
+            rx_queue_size: self.rx_queue_size,
+        };
+        let dev = Device::open(UsbContext::new()?, &self.selector, &options)?;
+        self.build_with_backend(Box::new(dev))
+    }
+
+    fn build_with_backend(self, dev: Box<dyn Backend>) -> Result<Interface, Error> {
+        let mut i = Interface::from_backend(dev)?;
+        self.apply(&mut i)?;
+        Ok(i)
+    }
+
+    /// Check the configuration against an interface that is already open,
+    /// without changing the interface or the device. The device selector and
+    /// the device options are not used.
+    ///
+    /// If the configuration is not valid for the device,
+    /// `Error::InvalidConfiguration` is returned containing every problem found.
+    pub fn check(&self, i: &Interface) -> Result<(), Error> {
+        let errors = self.validate(i);
+        if !errors.is_empty() {
+            return Err(Error::InvalidConfiguration(errors));
+        }
+        Ok(())
+    }
+
+    // applies a valid configuration to an open interface
+    fn apply(&self, i: &mut Interface) -> Result<(), Error> {
+        self.check(i)?;
+
+        i.set_timestamp_mode(self.timestamp_mode);
+        if let Some(base) = self.time_base {
+            i.set_time_base(base);
+        }
+        for n in 0..i.channels() {
+            match self.channels.get(&n) {
+                Some(ch) => i.reconfigure(n, ch.clone())?,
+                None => i.set_enabled(n, false)?,
+            }
+        }
+        Ok(())
+    }
+
+    // returns every problem with the configuration for this interface
+    fn validate(&self, i: &Interface) -> Vec<Error> {
+        let mut errors = Vec::new();
+        if self.timestamp_mode == TimestampMode::Hardware
+            && (i.features() & GS_CAN_FEATURE_HW_TIMESTAMP) == 0
+        {
+            errors.push(Error::UnsupportedFeature("Hardware timestamp"));
+        }
+        for (n, ch) in self.channels.iter() {
+            let wrap = |e| Error::ChannelConfig(*n, Box::new(e));
+            if *n >= i.channels() {
+                errors.push(wrap(Error::InvalidChannel));
+                continue;
+            }
+            if let Err(e) = i.mode_flags(ch) {
+                errors.push(wrap(e));
+            }
+            match calculate_bit_timing(i.can_clock, ch.bitrate, ch.sample_point, ch.sjw) {
+                Ok(bt) => {
+                    if let Err(e) = i.bit_timing_limits.check(&bt) {
+                        errors.push(wrap(e));
+                    }
+                }
+                Err(e) => errors.push(wrap(e)),
+            }
+            if ch.fd {
+                if let Err(e) = calculate_bit_timing(i.can_clock, ch.data_bitrate, None, None) {
+                    errors.push(wrap(e));
+                }
+            }
+        }
+        errors
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::virt::VirtualDevice;
+
+    fn channel(bitrate: u32) -> Channel {
+        Channel {
+            bitrate,
+            enabled: true,
+            loopback: false,
+            monitor: false,
+            fd: false,
+            data_bitrate: 0,
+            sample_point: None,
+            sjw: None,
+            one_shot: false,
+            triple_sample: false,
+            name: None,
+        }
+    }
+
+    #[test]
+    fn test_build() {
+        let i = InterfaceBuilder::new()
+            .channel(1, channel(250_000))
+            .timestamp_mode(TimestampMode::None)
+            .build_with_backend(Box::new(VirtualDevice::new(2)))
+            .unwrap();
+
+        assert!(!i.channels[0].enabled);
+        assert!(i.channels[1].enabled);
+        assert_eq!(i.channels[1].bitrate, 250_000);
+        assert_eq!(i.timestamp_mode, TimestampMode::None);
+    }
+
+    #[test]
+    fn test_invalid_configuration() {
+        let mut fd = channel(500_000);
+        fd.fd = true;
+        fd.data_bitrate = 0;
+
+        let result = InterfaceBuilder::new()
+            .channel(0, channel(0))
+            .channel(1, fd)
+            .channel(2, channel(500_000))
+            .build_with_backend(Box::new(VirtualDevice::new(2)));
+
+        match result {
+            Err(Error::InvalidConfiguration(errors)) => {
+                assert_eq!(errors.len(), 3);
+                assert!(matches!(
+                    &errors[0],
+                    Error::ChannelConfig(0, e) if matches!(**e, Error::InvalidBitrate(0))
+                ));
+                assert!(matches!(
+                    &errors[1],
+                    Error::ChannelConfig(1, e) if matches!(**e, Error::InvalidBitrate(0))
+                ));
+                assert!(matches!(
+                    &errors[2],
+                    Error::ChannelConfig(2, e) if matches!(**e, Error::InvalidChannel)
+                ));
+            }
+            r => panic!("unexpected result: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_bit_timing_limits() {
+        // the virtual device supports an SJW of at most 4 time quanta
+        let mut ch = channel(500_000);
+        ch.sjw = Some(5);
+
+        let result = InterfaceBuilder::new()
+            .channel(0, ch)
+            .build_with_backend(Box::new(VirtualDevice::new(2)));
+
+        match result {
+            Err(Error::InvalidConfiguration(errors)) => {
+                assert_eq!(errors.len(), 1);
+                assert!(matches!(
+                    &errors[0],
+                    Error::ChannelConfig(0, e) if matches!(**e, Error::InvalidBitTiming(_))
+                ));
+                assert!(errors[0].to_string().starts_with("channel 0: "));
+            }
+            r => panic!("unexpected result: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_check() {
+        let i = Interface::from_backend(Box::new(VirtualDevice::new(2))).unwrap();
+        let bitrates = |i: &Interface| i.channels.iter().map(|c| c.bitrate).collect::<Vec<_>>();
+        let before = bitrates(&i);
+
+        InterfaceBuilder::new()
+            .channel(0, channel(250_000))
+            .check(&i)
+            .unwrap();
+        assert!(InterfaceBuilder::new()
+            .channel(2, channel(250_000))
+            .check(&i)
+            .is_err());
+        // checking does not change the interface
+        assert_eq!(bitrates(&i), before);
+    }
+}
@@ -29,6 +29,10 @@ extern "C" {
 	__declspec(dllimport) int32_t cantact_stop(cantacthnd hnd);
 
 	__declspec(dllimport) int32_t cantact_transmit(cantacthnd hnd, const struct CantactFrame f);
+	// send f and wait up to timeout_ms for a response received on the channel of f,
+	// with the same ext flag as f and (id & response_mask) == (response_id & response_mask).
+	// returns 0 on success, -2 on timeout, or -1 on any other error
+	__declspec(dllimport) int32_t cantact_transact(cantacthnd hnd, const struct CantactFrame f, uint32_t response_id, uint32_t response_mask, uint32_t timeout_ms, struct CantactFrame* response);
 
 	__declspec(dllimport) int32_t cantact_set_bitrate(cantacthnd hnd, uint8_t channel, uint32_t bitrate);
 	__declspec(dllimport) int32_t cantact_set_enabled(cantacthnd hnd, uint8_t channel, uint8_t enabled);
@@ -8,7 +8,8 @@
 
 #![allow(clippy::missing_safety_doc)]
 
-use crate::{Frame, Interface};
+use crate::{Error, Frame, Interface};
+use std::time::Duration;
 
 /// A CAN frame in a C representation
 #[repr(C)]
@@ -43,6 +44,22 @@ impl CFrame {
             err: if f.err { 1 } else { 0 },
         }
     }
+    fn to_frame(&self) -> Frame {
+        Frame {
+            channel: self.channel,
+            can_id: self.id,
+            can_dlc: self.dlc,
+            data: self.data.to_vec(),
+            ext: self.ext > 0,
+            fd: self.fd > 0,
+            brs: self.brs > 0,
+            esi: self.esi > 0,
+            loopback: false,
+            rtr: self.rtr > 0,
+            err: self.err > 0,
+            timestamp: None,
+        }
+    }
 }
 
 /// Interface state. A pointer to this struct is provided when initializing the
@@ -71,7 +88,7 @@ pub extern "C" fn cantact_init() -> *mut CInterface {
 /// After calling, the pointer is no longer valid.
 #[no_mangle]
 pub unsafe extern "C" fn cantact_deinit(ptr: *mut CInterface) -> i32 {
-    Box::from_raw(ptr);
+    drop(Box::from_raw(ptr));
     0
 }
 
@@ -82,7 +99,7 @@ pub unsafe extern "C" fn cantact_set_rx_callback(
     ptr: *mut CInterface,
     cb: Option<extern "C" fn(*const CFrame)>,
 ) -> i32 {
-    let mut ci = &mut *ptr;
+    let ci = &mut *ptr;
     ci.c_rx_cb = cb;
     0
 }
@@ -104,7 +121,7 @@ pub unsafe extern "C" fn cantact_open(ptr: *mut CInterface) -> i32 {
 /// can be performed.
 #[no_mangle]
 pub unsafe extern "C" fn cantact_close(ptr: *mut CInterface) -> i32 {
-    let mut ci = &mut *ptr;
+    let ci = &mut *ptr;
     ci.i = None;
     0
 }
@@ -170,6 +187,47 @@ pub unsafe extern "C" fn cantact_transmit(ptr: *mut CInterface, cf: CFrame) -> i
     0
 }
 
+/// Transmit a frame and wait for a response. Can only be called if the device
+/// is running.
+///
+/// The response is the first frame received on the channel of `cf`, with the
+/// same `ext` flag as `cf`, and with
+/// `(id & response_mask) == (response_id & response_mask)`. It is written to
+/// `response`, which must point to a valid CFrame.
+///
+/// Returns 0 on success, -2 if no response is received within `timeout_ms`,
+/// or -1 on any other error.
+#[no_mangle]
+pub unsafe extern "C" fn cantact_transact(
+    ptr: *mut CInterface,
+    cf: CFrame,
+    response_id: u32,
+    response_mask: u32,
+    timeout_ms: u32,
+    response: *mut CFrame,
+) -> i32 {
+    let ci = &mut *ptr;
+    let i = match &ci.i {
+        Some(i) => i,
+        None => return -1,
+    };
+    let request = cf.to_frame();
+    let (channel, ext) = (request.channel, request.ext);
+    let matcher = move |f: &Frame| {
+        f.channel == channel
+            && f.ext == ext
+            && (f.can_id & response_mask) == (response_id & response_mask)
+    };
+    match i.transact(request, matcher, Duration::from_millis(timeout_ms as u64)) {
+        Ok(f) => {
+            *response = CFrame::from_frame(f);
+            0
+        }
+        Err(Error::Timeout) => -2,
+        Err(_) => -1,
+    }
+}
+
 /// Sets the bitrate for a chanel to the given value in bits per second.
 #[no_mangle]
 pub unsafe extern "C" fn cantact_set_bitrate(
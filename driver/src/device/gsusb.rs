@@ -113,14 +113,14 @@ impl BitTiming {
 pub(crate) struct BitTimingConsts {
     pub(crate) feature: u32,
     pub(crate) fclk_can: u32,
-    tseg1_min: u32,
-    tseg1_max: u32,
-    tseg2_min: u32,
-    tseg2_max: u32,
-    sjw_max: u32,
-    brp_min: u32,
-    brp_max: u32,
-    brp_inc: u32,
+    pub(crate) tseg1_min: u32,
+    pub(crate) tseg1_max: u32,
+    pub(crate) tseg2_min: u32,
+    pub(crate) tseg2_max: u32,
+    pub(crate) sjw_max: u32,
+    pub(crate) brp_min: u32,
+    pub(crate) brp_max: u32,
+    pub(crate) brp_inc: u32,
 }
 impl BitTimingConsts {
     pub(crate) fn from_le_bytes(bs: &[u8]) -> BitTimingConsts {
@@ -173,20 +173,35 @@ pub(crate) struct HostFrame {
     pub reserved: u8,
 
     pub data: [u8; 64],
+
+    // device timestamp in microseconds, sent after the data when the channel
+    // is in hardware timestamp mode
+    pub timestamp_us: Option<u32>,
 }
 impl HostFrame {
+    // `bs` holds exactly the bytes received, at least the 12 byte header
     pub(crate) fn from_le_bytes(bs: &[u8]) -> HostFrame {
+        let flags = bs[10];
+        let data_len = if (flags & GS_CAN_FLAG_FD) != 0 { 64 } else { 8 };
+        let data_end = std::cmp::min(12 + data_len, bs.len());
         let mut data: [u8; 64] = [0u8; 64];
         // copy data bytes to array
-        data[..(bs.len() - 12)].clone_from_slice(&bs[12..]);
+        data[..(data_end - 12)].clone_from_slice(&bs[12..data_end]);
+        // the timestamp follows the complete data field
+        let timestamp_us = if data_end == 12 + data_len && bs.len() >= data_end + 4 {
+            Some(u32_from_le_bytes(&bs[data_end..]))
+        } else {
+            None
+        };
         HostFrame {
             echo_id: u32_from_le_bytes(&bs[0..4]),
             can_id: u32_from_le_bytes(&bs[4..8]),
             can_dlc: bs[8],
             channel: bs[9],
-            flags: bs[10],
+            flags,
             reserved: bs[11],
             data,
+            timestamp_us,
         }
     }
     pub(crate) fn to_le_bytes(&self) -> Vec<u8> {
@@ -197,9 +212,10 @@ impl HostFrame {
         data.push(self.channel);
         data.push(self.flags);
         data.push(self.reserved);
-        if ((self.flags & GS_CAN_FLAG_FD) != 0 || self.can_dlc > 8) {
+        if (self.flags & GS_CAN_FLAG_FD) != 0 || self.can_dlc > 8 {
             data.extend_from_slice(&self.data);
-        } else { // legacy gs_host_frame is limited to 8 bytes of data
+        } else {
+            // legacy gs_host_frame is limited to 8 bytes of data
             data.extend_from_slice(&self.data[..8]);
         }
         data
@@ -1,18 +1,23 @@
 #![allow(dead_code)]
-use crossbeam_channel::{unbounded, Receiver, Sender, TryRecvError};
-use libc::c_void;
+use crate::DeviceSelector;
+use crossbeam_channel::{bounded, unbounded, Receiver, Sender, TryRecvError};
+use libc::{c_void, timeval};
 use libusb1_sys::constants::*;
 use libusb1_sys::*;
+use std::ffi::CStr;
+use std::fmt;
 use std::mem;
 use std::mem::size_of;
 use std::ptr;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::sync::RwLock;
 use std::thread;
+use std::time::{Duration, Instant};
 
 pub mod gsusb;
 pub(crate) use gsusb::*;
+pub(crate) mod virt;
 
 // CANtact USB VID / PID
 const USB_VID: u16 = 0x1d50;
@@ -22,10 +27,17 @@ const USB_PID: u16 = 0x606f;
 const CTRL_BUF_SIZE: usize = 64;
 // number of bulk in transfers
 const BULK_IN_TRANSFER_COUNT: usize = 32;
-// buffer size for bulk in transfer
-const BULK_IN_BUF_SIZE: usize = 76;
+// buffer size for bulk in transfer, large enough for a CAN-FD frame followed
+// by a hardware timestamp
+const BULK_IN_BUF_SIZE: usize = 80;
+// size of the host frame fields before the data
+const HOST_FRAME_HEADER_SIZE: usize = 12;
 // timeout for bulk in transfers
 const BULK_IN_TIMEOUT_MS: u32 = 5000;
+// how often the libusb event thread checks if it should exit
+const EVENT_POLL_INTERVAL_MS: u32 = 100;
+// buffer size for string descriptors
+const STRING_DESC_BUF_SIZE: usize = 256;
 
 #[derive(Debug)]
 pub enum Error {
@@ -33,6 +45,72 @@ pub enum Error {
     DeviceNotFound,
     TransferAllocFailed,
     InvalidControlResponse,
+    TransferFailed(i32),
+}
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Libusb(func, e) => {
+                let name = unsafe { CStr::from_ptr(libusb_error_name(*e)) };
+                write!(f, "{} failed: {}", func, name.to_string_lossy())
+            }
+            Error::DeviceNotFound => write!(f, "device not found"),
+            Error::TransferAllocFailed => write!(f, "could not allocate a USB transfer"),
+            Error::InvalidControlResponse => write!(f, "invalid response from device"),
+            Error::TransferFailed(status) => {
+                let reason = match *status {
+                    LIBUSB_TRANSFER_TIMED_OUT => "timed out",
+                    LIBUSB_TRANSFER_CANCELLED => "cancelled",
+                    LIBUSB_TRANSFER_STALL => "stalled",
+                    LIBUSB_TRANSFER_NO_DEVICE => "device disconnected",
+                    LIBUSB_TRANSFER_OVERFLOW => "overflow",
+                    _ => "error",
+                };
+                write!(f, "USB transfer failed ({})", reason)
+            }
+        }
+    }
+}
+
+/// Options applied when opening a USB device.
+pub(crate) struct DeviceOptions {
+    pub(crate) control_timeout_ms: u32,
+    pub(crate) send_timeout_ms: u32,
+    /// Maximum number of received frames waiting to be handled. When the
+    /// queue is full, newly received frames are dropped.
+    /// The queue is unbounded if this is None.
+    pub(crate) rx_queue_size: Option<usize>,
+}
+impl Default for DeviceOptions {
+    fn default() -> DeviceOptions {
+        DeviceOptions {
+            control_timeout_ms: 1000,
+            send_timeout_ms: 1000,
+            rx_queue_size: None,
+        }
+    }
+}
+
+/// Operations required from a device implementing the GSUSB protocol.
+///
+/// This is implemented by `Device` for USB hardware, and by `VirtualDevice`
+/// for testing without hardware.
+pub(crate) trait Backend: Send {
+    fn set_bit_timing(&mut self, channel: u16, timing: BitTiming) -> Result<(), Error>;
+    fn set_data_bit_timing(&mut self, channel: u16, timing: BitTiming) -> Result<(), Error>;
+    fn set_mode(&mut self, channel: u16, device_mode: Mode) -> Result<(), Error>;
+    fn get_device_config(&mut self) -> Result<DeviceConfig, Error>;
+    fn get_bit_timing_consts(&mut self) -> Result<BitTimingConsts, Error>;
+
+    /// Start receiving frames from the device.
+    fn start_transfers(&mut self) -> Result<(), Error>;
+    /// Stop receiving frames from the device. When this returns, no more
+    /// frames will be sent to the receive channel.
+    fn stop_transfers(&mut self) -> Result<(), Error>;
+
+    fn send(&mut self, frame: HostFrame) -> Result<(), Error>;
+    /// Returns the channel that received frames are sent to.
+    fn can_rx_recv(&self) -> Receiver<HostFrame>;
 }
 
 #[derive(Debug)]
@@ -44,13 +122,13 @@ unsafe impl Send for UsbContext {}
 unsafe impl Sync for UsbContext {}
 
 impl UsbContext {
-    pub(crate) fn new() -> UsbContext {
+    pub(crate) fn new() -> Result<UsbContext, Error> {
         let mut context = mem::MaybeUninit::<*mut libusb_context>::uninit();
         match unsafe { libusb_init(context.as_mut_ptr()) } {
-            LIBUSB_SUCCESS => UsbContext {
+            LIBUSB_SUCCESS => Ok(UsbContext {
                 ctx: unsafe { context.assume_init() },
-            },
-            _ => panic!("could not initialize libusb context"),
+            }),
+            e => Err(Error::Libusb("libusb_init", e)),
         }
     }
     fn as_ptr(&self) -> *mut libusb_context {
@@ -67,34 +145,47 @@ pub(crate) struct Device {
     ctx: Arc<UsbContext>,
     hnd: ptr::NonNull<libusb_device_handle>,
     running: Arc<AtomicBool>,
+    event_thread: Option<thread::JoinHandle<()>>,
+
+    control_timeout_ms: u32,
+    send_timeout_ms: u32,
 
     ctrl_transfer: ptr::NonNull<libusb_transfer>,
     ctrl_buf: [u8; CTRL_BUF_SIZE],
     ctrl_transfer_pending: RwLock<bool>,
+    ctrl_transfer_status: i32,
 
     out_transfer: ptr::NonNull<libusb_transfer>,
     out_buf: Vec<u8>,
     out_transfer_pending: RwLock<bool>,
+    out_transfer_status: i32,
 
     in_transfers: [*mut libusb_transfer; BULK_IN_TRANSFER_COUNT],
     in_bufs: [[u8; BULK_IN_BUF_SIZE]; BULK_IN_TRANSFER_COUNT],
+    // number of bulk in transfers that libusb still owns
+    in_transfers_pending: AtomicUsize,
+    // set when bulk in transfers should not be resubmitted
+    in_transfers_stopping: AtomicBool,
 
     can_rx_send: Sender<HostFrame>,
-    pub can_rx_recv: Receiver<HostFrame>,
+    can_rx_recv: Receiver<HostFrame>,
 }
 
+// the device is only accessed through raw pointers by libusb callbacks
+// while transfers it owns are pending
+unsafe impl Send for Device {}
+
 extern "system" fn ctrl_cb(xfer: *mut libusb_transfer) {
     let dev_ptr = unsafe { (*xfer).user_data as *mut Device };
     let dev = unsafe { &mut *dev_ptr };
-
-    let _status = unsafe { (*xfer).status };
+    dev.ctrl_transfer_status = unsafe { (*xfer).status };
 
     *dev.ctrl_transfer_pending.write().unwrap() = false;
 }
 extern "system" fn bulk_out_cb(xfer: *mut libusb_transfer) {
     let dev_ptr = unsafe { (*xfer).user_data as *mut Device };
     let dev = unsafe { &mut *dev_ptr };
-    let _status = unsafe { (*xfer).status };
+    dev.out_transfer_status = unsafe { (*xfer).status };
 
     *dev.out_transfer_pending.write().unwrap() = false;
 }
@@ -105,24 +196,153 @@ extern "system" fn bulk_in_cb(xfer: *mut libusb_transfer) {
     let status = unsafe { (*xfer).status };
 
     if status == LIBUSB_TRANSFER_COMPLETED {
-        let frame_data = unsafe { std::slice::from_raw_parts((*xfer).buffer, BULK_IN_BUF_SIZE) };
-        let f = HostFrame::from_le_bytes(frame_data);
-        dev.can_rx_send.send(f).unwrap();
+        let len = unsafe { (*xfer).actual_length } as usize;
+        // transfers too short to hold a frame header are ignored
+        if len >= HOST_FRAME_HEADER_SIZE {
+            let frame_data = unsafe { std::slice::from_raw_parts((*xfer).buffer, len) };
+            let f = HostFrame::from_le_bytes(frame_data);
+            // frames are dropped if the receive queue is full
+            let _ = dev.can_rx_send.try_send(f);
+        }
     }
-    if status != LIBUSB_TRANSFER_CANCELLED {
-        // resubmit the transfer unless it was cancelled
-        unsafe {
-            libusb_submit_transfer(xfer);
+
+    // resubmit the transfer unless it was cancelled, the device is gone,
+    // or transfers are being stopped
+    let resubmit = status != LIBUSB_TRANSFER_CANCELLED
+        && status != LIBUSB_TRANSFER_NO_DEVICE
+        && !dev.in_transfers_stopping.load(Ordering::SeqCst);
+    if resubmit && unsafe { libusb_submit_transfer(xfer) } == LIBUSB_SUCCESS {
+        return;
+    }
+    // libusb no longer owns this transfer
+    dev.in_transfers_pending.fetch_sub(1, Ordering::SeqCst);
+}
+
+// reads an ASCII string descriptor, returning None if it can't be read
+fn read_string_descriptor(hnd: *mut libusb_device_handle, index: u8) -> Option<String> {
+    if index == 0 {
+        // device does not provide this string
+        return None;
+    }
+    let mut buf = [0u8; STRING_DESC_BUF_SIZE];
+    let len = unsafe {
+        libusb_get_string_descriptor_ascii(hnd, index, buf.as_mut_ptr(), buf.len() as i32)
+    };
+    if len < 0 {
+        return None;
+    }
+    Some(String::from_utf8_lossy(&buf[..len as usize]).into_owned())
+}
+
+// calls `f` for each attached CANtact device until it returns Some
+fn find_device<T>(
+    ctx: &UsbContext,
+    mut f: impl FnMut(*mut libusb_device, &libusb_device_descriptor) -> Option<T>,
+) -> Result<Option<T>, Error> {
+    let mut list = ptr::null();
+    let count = unsafe { libusb_get_device_list(ctx.as_ptr(), &mut list) };
+    if count < 0 {
+        return Err(Error::Libusb("libusb_get_device_list", count as i32));
+    }
+    let devs = unsafe { std::slice::from_raw_parts(list, count as usize) };
+
+    let mut result = None;
+    for dev in devs {
+        let mut desc = mem::MaybeUninit::<libusb_device_descriptor>::uninit();
+        if unsafe { libusb_get_device_descriptor(*dev, desc.as_mut_ptr()) } != LIBUSB_SUCCESS {
+            continue;
+        }
+        let desc = unsafe { desc.assume_init() };
+        if desc.idVendor != USB_VID || desc.idProduct != USB_PID {
+            continue;
+        }
+        result = f(*dev, &desc);
+        if result.is_some() {
+            break;
         }
     }
+
+    unsafe { libusb_free_device_list(list, 1) };
+    Ok(result)
+}
+
+// opens the first CANtact device matching the selector
+fn open_device(
+    ctx: &UsbContext,
+    selector: &DeviceSelector,
+) -> Result<*mut libusb_device_handle, Error> {
+    // index among CANtact devices only
+    let mut index = 0;
+    let hnd = find_device(ctx, |dev, desc| {
+        let this_index = index;
+        index += 1;
+
+        let mut hnd = ptr::null_mut();
+        if unsafe { libusb_open(dev, &mut hnd) } != LIBUSB_SUCCESS {
+            // no permission to open this device, or it was removed
+            return None;
+        }
+        let selected = match selector {
+            DeviceSelector::First => true,
+            DeviceSelector::Index(i) => *i == this_index,
+            DeviceSelector::Serial(serial) => {
+                read_string_descriptor(hnd, desc.iSerialNumber).as_ref() == Some(serial)
+            }
+        };
+        if selected {
+            return Some(hnd);
+        }
+        unsafe { libusb_close(hnd) };
+        None
+    })?;
+    hnd.ok_or(Error::DeviceNotFound)
+}
+
+/// USB identity and location of an attached CANtact device.
+pub(crate) struct UsbDeviceInfo {
+    pub(crate) serial: Option<String>,
+    pub(crate) bus: u8,
+    pub(crate) address: u8,
+    pub(crate) ports: Vec<u8>,
+}
+
+// lists attached CANtact devices, in the order used by DeviceSelector::Index
+pub(crate) fn list_usb_devices(ctx: &UsbContext) -> Result<Vec<UsbDeviceInfo>, Error> {
+    let mut devices = Vec::new();
+    find_device(ctx, |dev, desc| -> Option<()> {
+        let mut ports = [0u8; 7];
+        let n = unsafe { libusb_get_port_numbers(dev, ports.as_mut_ptr(), ports.len() as i32) };
+
+        // the serial number can only be read if the device can be opened
+        let mut serial = None;
+        let mut hnd = ptr::null_mut();
+        if unsafe { libusb_open(dev, &mut hnd) } == LIBUSB_SUCCESS {
+            serial = read_string_descriptor(hnd, desc.iSerialNumber);
+            unsafe { libusb_close(hnd) };
+        }
+
+        devices.push(UsbDeviceInfo {
+            serial,
+            bus: unsafe { libusb_get_bus_number(dev) },
+            address: unsafe { libusb_get_device_address(dev) },
+            ports: ports[..std::cmp::max(n, 0) as usize].to_vec(),
+        });
+        None
+    })?;
+    Ok(devices)
 }
 
 impl Device {
     pub(crate) fn new(ctx: UsbContext) -> Result<Device, Error> {
-        let hnd = unsafe { libusb_open_device_with_vid_pid(ctx.as_ptr(), USB_VID, USB_PID) };
-        if hnd.is_null() {
-            return Err(Error::DeviceNotFound);
-        }
+        Device::open(ctx, &DeviceSelector::First, &DeviceOptions::default())
+    }
+
+    pub(crate) fn open(
+        ctx: UsbContext,
+        selector: &DeviceSelector,
+        options: &DeviceOptions,
+    ) -> Result<Device, Error> {
+        let hnd = open_device(&ctx, selector)?;
 
         match unsafe { libusb_detach_kernel_driver(hnd, 0) } {
             LIBUSB_SUCCESS => {}
@@ -140,80 +360,69 @@ impl Device {
         if ctrl_transfer.is_null() {
             return Err(Error::TransferAllocFailed);
         }
+        let out_transfer = unsafe { libusb_alloc_transfer(0) };
+        if out_transfer.is_null() {
+            unsafe { libusb_free_transfer(ctrl_transfer) };
+            return Err(Error::TransferAllocFailed);
+        }
 
         let in_bufs: [[u8; BULK_IN_BUF_SIZE]; BULK_IN_TRANSFER_COUNT] =
             [[0u8; BULK_IN_BUF_SIZE]; BULK_IN_TRANSFER_COUNT];
 
-        let (send, recv) = unbounded();
+        let (send, recv) = match options.rx_queue_size {
+            Some(n) => bounded(n),
+            None => unbounded(),
+        };
 
-        let d = Device {
+        let mut d = Device {
             ctx: Arc::new(ctx),
             hnd: unsafe { ptr::NonNull::new_unchecked(hnd) },
             running: Arc::new(AtomicBool::new(true)),
+            event_thread: None,
+
+            control_timeout_ms: options.control_timeout_ms,
+            send_timeout_ms: options.send_timeout_ms,
 
             ctrl_transfer: unsafe { ptr::NonNull::new_unchecked(ctrl_transfer) },
             ctrl_buf: [0u8; CTRL_BUF_SIZE],
             ctrl_transfer_pending: RwLock::from(false),
+            ctrl_transfer_status: LIBUSB_TRANSFER_COMPLETED,
 
-            out_transfer: unsafe { ptr::NonNull::new_unchecked(ctrl_transfer) },
+            out_transfer: unsafe { ptr::NonNull::new_unchecked(out_transfer) },
             out_buf: vec![],
             out_transfer_pending: RwLock::from(false),
+            out_transfer_status: LIBUSB_TRANSFER_COMPLETED,
 
             in_transfers: [ptr::null_mut(); BULK_IN_TRANSFER_COUNT],
             in_bufs,
+            in_transfers_pending: AtomicUsize::new(0),
+            in_transfers_stopping: AtomicBool::new(false),
 
             can_rx_send: send,
             can_rx_recv: recv,
         };
 
         // start the libusb event thread
+        // events are handled with a timeout so the thread can notice when
+        // the device is dropped
         let ctx = d.ctx.clone();
         let running = d.running.clone();
-        thread::spawn(move || {
+        d.event_thread = Some(thread::spawn(move || {
+            // the field types of timeval differ between platforms
+            let tv = timeval {
+                tv_sec: 0 as _,
+                tv_usec: (EVENT_POLL_INTERVAL_MS * 1000) as _,
+            };
             while running.load(Ordering::SeqCst) {
                 unsafe {
-                    libusb_handle_events(ctx.as_ptr());
+                    libusb_handle_events_timeout_completed(ctx.as_ptr(), &tv, ptr::null_mut());
                 }
             }
-        });
+        }));
 
         Ok(d)
     }
 
-    pub(crate) fn start_transfers(&mut self) -> Result<(), Error> {
-        // create the in transfers, fill the transfers, and submit them
-        for i in 0..BULK_IN_TRANSFER_COUNT {
-            let xfer = unsafe { libusb_alloc_transfer(0) };
-            if xfer.is_null() {
-                return Err(Error::TransferAllocFailed);
-            }
-            self.in_transfers[i] = xfer;
-            self.fill_bulk_in_transfer(i);
-
-            match unsafe { libusb_submit_transfer(self.in_transfers[i]) } {
-                LIBUSB_SUCCESS => {}
-                e => return Err(Error::Libusb("start_transfers: libusb_submit_transfer", e)),
-            };
-        }
-        Ok(())
-    }
-
-    pub(crate) fn stop_transfers(&self) -> Result<(), Error> {
-        // cancel all bulk in transfers
-        for xfer in self.in_transfers.iter() {
-            if xfer.is_null() {
-                // ignore null transfers
-                continue;
-            }
-            match unsafe { libusb_cancel_transfer(*xfer) } {
-                LIBUSB_SUCCESS => {}
-                LIBUSB_ERROR_NOT_FOUND => { /* already destroyed */ }
-                e => return Err(Error::Libusb("libusb_cancel_transfer", e)),
-            }
-        }
-        Ok(())
-    }
-
     fn fill_control_transfer(
         &mut self,
         request_type: u8,
@@ -222,7 +431,7 @@ impl Device {
         index: u16,
         data: &[u8],
     ) {
-        let mut transfer = unsafe { &mut *self.ctrl_transfer.as_ptr() };
+        let transfer = unsafe { &mut *self.ctrl_transfer.as_ptr() };
 
         // clear buffer
         self.ctrl_buf = [0u8; CTRL_BUF_SIZE];
@@ -242,7 +451,7 @@ impl Device {
         transfer.dev_handle = self.hnd.as_ptr();
         transfer.endpoint = 0;
         transfer.transfer_type = LIBUSB_TRANSFER_TYPE_CONTROL;
-        transfer.timeout = 1000;
+        transfer.timeout = self.control_timeout_ms;
         transfer.buffer = self.ctrl_buf.as_mut_ptr();
         transfer.length = self.ctrl_buf.len() as i32;
         transfer.callback = ctrl_cb;
@@ -250,13 +459,13 @@ impl Device {
     }
 
     fn fill_bulk_out_transfer(&mut self, transfer: *mut libusb_transfer) {
-        let mut transfer = unsafe { &mut *transfer };
+        let transfer = unsafe { &mut *transfer };
         let buf = &mut self.out_buf;
 
         transfer.dev_handle = self.hnd.as_ptr();
         transfer.endpoint = 0x02; // bulk out ep
         transfer.transfer_type = LIBUSB_TRANSFER_TYPE_BULK;
-        transfer.timeout = 1000;
+        transfer.timeout = self.send_timeout_ms;
         transfer.buffer = buf.as_mut_ptr();
         transfer.length = buf.len() as i32;
         transfer.callback = bulk_out_cb;
@@ -264,7 +473,7 @@ impl Device {
     }
 
     fn fill_bulk_in_transfer(&mut self, idx: usize) {
-        let mut transfer = unsafe { &mut *self.in_transfers[idx] };
+        let transfer = unsafe { &mut *self.in_transfers[idx] };
         let buf = &mut self.in_bufs[idx];
 
         transfer.dev_handle = self.hnd.as_ptr();
@@ -284,11 +493,17 @@ impl Device {
         *self.ctrl_transfer_pending.write().unwrap() = true;
         match unsafe { libusb_submit_transfer(self.ctrl_transfer.as_ptr()) } {
             LIBUSB_SUCCESS => {}
-            e => return Err(Error::Libusb("control_out: libusb_submit_transfer", e)),
+            e => {
+                *self.ctrl_transfer_pending.write().unwrap() = false;
+                return Err(Error::Libusb("control_out: libusb_submit_transfer", e));
+            }
         }
 
         // wait for transfer to complete
         while *self.ctrl_transfer_pending.read().unwrap() {}
+        if self.ctrl_transfer_status != LIBUSB_TRANSFER_COMPLETED {
+            return Err(Error::TransferFailed(self.ctrl_transfer_status));
+        }
 
         Ok(())
     }
@@ -300,11 +515,17 @@ impl Device {
         *self.ctrl_transfer_pending.write().unwrap() = true;
         match unsafe { libusb_submit_transfer(self.ctrl_transfer.as_ptr()) } {
             LIBUSB_SUCCESS => {}
-            e => return Err(Error::Libusb("control_in: libusb_submit_transfer", e)),
+            e => {
+                *self.ctrl_transfer_pending.write().unwrap() = false;
+                return Err(Error::Libusb("control_in: libusb_submit_transfer", e));
+            }
         }
 
         // wait for transfer to complete
         while *self.ctrl_transfer_pending.read().unwrap() {}
+        if self.ctrl_transfer_status != LIBUSB_TRANSFER_COMPLETED {
+            return Err(Error::TransferFailed(self.ctrl_transfer_status));
+        }
         let xfer_len = unsafe { (*self.ctrl_transfer.as_ptr()).actual_length } as usize;
         if xfer_len < len {
             // we didn't get the full struct we asked for
@@ -319,22 +540,6 @@ impl Device {
         self.control_out(UsbBreq::HostFormat, channel, &val.to_le_bytes())
     }
 
-    pub(crate) fn set_bit_timing(&mut self, channel: u16, timing: BitTiming) -> Result<(), Error> {
-        self.control_out(UsbBreq::BitTiming, channel, &timing.to_le_bytes())
-    }
-
-    pub(crate) fn set_data_bit_timing(
-        &mut self,
-        channel: u16,
-        timing: BitTiming,
-    ) -> Result<(), Error> {
-        self.control_out(UsbBreq::DataBitTiming, channel, &timing.to_le_bytes())
-    }
-
-    pub(crate) fn set_mode(&mut self, channel: u16, device_mode: Mode) -> Result<(), Error> {
-        self.control_out(UsbBreq::Mode, channel, &device_mode.to_le_bytes())
-    }
-
     pub(crate) fn set_identify(&mut self, val: u32) -> Result<(), Error> {
         let channel = 0;
         self.control_out(UsbBreq::Identify, channel, &val.to_le_bytes())
@@ -346,13 +551,48 @@ impl Device {
         self.control_out(UsbBreq::Berr, channel, &val.to_le_bytes())
     }
 
-    pub(crate) fn get_device_config(&mut self) -> Result<DeviceConfig, Error> {
+    pub(crate) fn get_timestamp(&mut self) -> Result<u32, Error> {
+        let channel = 0;
+        let data = self.control_in(UsbBreq::Timestamp, channel, size_of::<u32>())?;
+        let bytes = [data[0], data[1], data[2], data[3]];
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    pub(crate) fn try_recv(&self) -> Option<HostFrame> {
+        match self.can_rx_recv.try_recv() {
+            Ok(f) => Some(f),
+            Err(TryRecvError::Empty) => None,
+            Err(TryRecvError::Disconnected) => None,
+        }
+    }
+    pub(crate) fn recv(&self) -> HostFrame {
+        match self.can_rx_recv.recv() {
+            Ok(f) => f,
+            Err(e) => panic!("{}", e),
+        }
+    }
+}
+
+impl Backend for Device {
+    fn set_bit_timing(&mut self, channel: u16, timing: BitTiming) -> Result<(), Error> {
+        self.control_out(UsbBreq::BitTiming, channel, &timing.to_le_bytes())
+    }
+
+    fn set_data_bit_timing(&mut self, channel: u16, timing: BitTiming) -> Result<(), Error> {
+        self.control_out(UsbBreq::DataBitTiming, channel, &timing.to_le_bytes())
+    }
+
+    fn set_mode(&mut self, channel: u16, device_mode: Mode) -> Result<(), Error> {
+        self.control_out(UsbBreq::Mode, channel, &device_mode.to_le_bytes())
+    }
+
+    fn get_device_config(&mut self) -> Result<DeviceConfig, Error> {
         let channel = 0;
         let data = self.control_in(UsbBreq::DeviceConfig, channel, size_of::<DeviceConfig>())?;
         Ok(DeviceConfig::from_le_bytes(&data))
     }
 
-    pub(crate) fn get_bit_timing_consts(&mut self) -> Result<BitTimingConsts, Error> {
+    fn get_bit_timing_consts(&mut self) -> Result<BitTimingConsts, Error> {
         let channel = 0;
         let data = self.control_in(
             UsbBreq::BitTimingConsts,
@@ -362,14 +602,69 @@ impl Device {
         Ok(BitTimingConsts::from_le_bytes(&data))
     }
 
-    pub(crate) fn get_timestamp(&mut self) -> Result<u32, Error> {
-        let channel = 0;
-        let data = self.control_in(UsbBreq::Timestamp, channel, size_of::<u32>())?;
-        let bytes = [data[0], data[1], data[2], data[3]];
-        Ok(u32::from_le_bytes(bytes))
+    fn start_transfers(&mut self) -> Result<(), Error> {
+        self.in_transfers_stopping.store(false, Ordering::SeqCst);
+
+        // create the in transfers, fill the transfers, and submit them
+        for i in 0..BULK_IN_TRANSFER_COUNT {
+            if self.in_transfers[i].is_null() {
+                let xfer = unsafe { libusb_alloc_transfer(0) };
+                if xfer.is_null() {
+                    return Err(Error::TransferAllocFailed);
+                }
+                self.in_transfers[i] = xfer;
+            }
+            self.fill_bulk_in_transfer(i);
+
+            self.in_transfers_pending.fetch_add(1, Ordering::SeqCst);
+            match unsafe { libusb_submit_transfer(self.in_transfers[i]) } {
+                LIBUSB_SUCCESS => {}
+                e => {
+                    self.in_transfers_pending.fetch_sub(1, Ordering::SeqCst);
+                    return Err(Error::Libusb("start_transfers: libusb_submit_transfer", e));
+                }
+            };
+        }
+        Ok(())
+    }
+
+    fn stop_transfers(&mut self) -> Result<(), Error> {
+        // prevent the callback from resubmitting transfers
+        self.in_transfers_stopping.store(true, Ordering::SeqCst);
+
+        // cancel all bulk in transfers, and wait for libusb to hand them back
+        // a transfer may be resubmitted by a callback that was already running
+        // when the stop flag was set, so keep cancelling until all are returned
+        let deadline = Instant::now() + Duration::from_millis(2 * BULK_IN_TIMEOUT_MS as u64);
+        while self.in_transfers_pending.load(Ordering::SeqCst) > 0 {
+            for xfer in self.in_transfers.iter() {
+                if xfer.is_null() {
+                    // ignore null transfers
+                    continue;
+                }
+                match unsafe { libusb_cancel_transfer(*xfer) } {
+                    LIBUSB_SUCCESS => {}
+                    LIBUSB_ERROR_NOT_FOUND => { /* not in flight */ }
+                    e => return Err(Error::Libusb("libusb_cancel_transfer", e)),
+                }
+            }
+            if Instant::now() > deadline {
+                return Err(Error::Libusb("stop_transfers", LIBUSB_ERROR_TIMEOUT));
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        // no transfers are owned by libusb, they can be freed
+        for xfer in self.in_transfers.iter_mut() {
+            if !xfer.is_null() {
+                unsafe { libusb_free_transfer(*xfer) };
+                *xfer = ptr::null_mut();
+            }
+        }
+        Ok(())
     }
 
-    pub(crate) fn send(&mut self, frame: HostFrame) -> Result<(), Error> {
+    fn send(&mut self, frame: HostFrame) -> Result<(), Error> {
         self.out_buf.clear();
         self.out_buf.append(&mut frame.to_le_bytes());
 
@@ -378,37 +673,42 @@ impl Device {
 
         match unsafe { libusb_submit_transfer(self.out_transfer.as_ptr()) } {
             LIBUSB_SUCCESS => {}
-            e => return Err(Error::Libusb("send: libusb_submit_transfer", e)),
+            e => {
+                *self.out_transfer_pending.write().unwrap() = false;
+                return Err(Error::Libusb("send: libusb_submit_transfer", e));
+            }
         }
 
         // wait for transfer to complete
         while *self.out_transfer_pending.read().unwrap() {}
 
-        Ok(())
-    }
-
-    pub(crate) fn try_recv(&self) -> Option<HostFrame> {
-        match self.can_rx_recv.try_recv() {
-            Ok(f) => Some(f),
-            Err(TryRecvError::Empty) => None,
-            Err(TryRecvError::Disconnected) => None,
+        match self.out_transfer_status {
+            LIBUSB_TRANSFER_COMPLETED => Ok(()),
+            status => Err(Error::TransferFailed(status)),
         }
     }
-    pub(crate) fn recv(&self) -> HostFrame {
-        match self.can_rx_recv.recv() {
-            Ok(f) => f,
-            Err(e) => panic!("{}", e),
-        }
+
+    fn can_rx_recv(&self) -> Receiver<HostFrame> {
+        self.can_rx_recv.clone()
     }
 }
 
 impl Drop for Device {
     fn drop(&mut self) {
-        // stop the threau
+        // return all bulk in transfers before the event thread stops
+        // on failure, transfers still owned by libusb can't be freed safely
+        // and are leaked
+        let _ = self.stop_transfers();
+
+        // stop the event thread
         self.running.store(false, Ordering::SeqCst);
+        if let Some(t) = self.event_thread.take() {
+            let _ = t.join();
+        }
 
-        self.stop_transfers().unwrap();
         unsafe {
+            libusb_free_transfer(self.ctrl_transfer.as_ptr());
+            libusb_free_transfer(self.out_transfer.as_ptr());
             libusb_release_interface(self.hnd.as_ptr(), 0);
             libusb_close(self.hnd.as_ptr());
         }
@@ -0,0 +1,159 @@
+//! A virtual device implementing the GSUSB protocol in software.
+//!
+//! The virtual device behaves like a device connected to an otherwise silent
+//! bus: every frame sent on a started channel is echoed back to the host, as
+//! a real device does once the frame has been transmitted.
+
+use super::*;
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+// CAN clock reported by the virtual device
+const VIRTUAL_CAN_CLOCK: u32 = 48_000_000;
+
+/// A control request that the virtual device fails, for testing error handling.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) enum Fault {
+    /// Setting the nominal or data bit timing of the channel.
+    BitTiming(u16),
+    /// Starting the channel.
+    Start(u16),
+}
+
+pub(crate) struct VirtualDevice {
+    channel_count: u8,
+    features: u32,
+    started: HashSet<u16>,
+    // started channels in hardware timestamp mode
+    timestamped: HashSet<u16>,
+    // start of the hardware timestamp counter
+    epoch: Instant,
+    receiving: bool,
+    faults: Arc<Mutex<HashSet<Fault>>>,
+
+    can_rx_send: Sender<HostFrame>,
+    can_rx_recv: Receiver<HostFrame>,
+}
+
+impl VirtualDevice {
+    /// Create a virtual device with the given number of channels.
+    pub(crate) fn new(channel_count: u8) -> VirtualDevice {
+        let (send, recv) = unbounded();
+        VirtualDevice {
+            channel_count,
+            features: GS_CAN_FEATURE_LISTEN_ONLY
+                | GS_CAN_FEATURE_LOOP_BACK
+                | GS_CAN_FEATURE_ONE_SHOT
+                | GS_CAN_FEATURE_TRIPLE_SAMPLE
+                | GS_CAN_FEATURE_FD
+                | GS_CAN_FEATURE_HW_TIMESTAMP,
+            started: HashSet::new(),
+            timestamped: HashSet::new(),
+            epoch: Instant::now(),
+            receiving: false,
+            faults: Arc::new(Mutex::new(HashSet::new())),
+            can_rx_send: send,
+            can_rx_recv: recv,
+        }
+    }
+
+    /// Returns a sender for injecting frames as if they were received from
+    /// another node on the bus.
+    pub(crate) fn bus(&self) -> Sender<HostFrame> {
+        self.can_rx_send.clone()
+    }
+
+    /// Returns the set of control requests that fail, which can be changed
+    /// while the device is in use.
+    pub(crate) fn faults(&self) -> Arc<Mutex<HashSet<Fault>>> {
+        Arc::clone(&self.faults)
+    }
+
+    fn check_channel(&self, channel: u16) -> Result<(), Error> {
+        if channel >= self.channel_count as u16 {
+            return Err(Error::InvalidControlResponse);
+        }
+        Ok(())
+    }
+
+    fn check_fault(&self, fault: Fault) -> Result<(), Error> {
+        if self.faults.lock().unwrap().contains(&fault) {
+            return Err(Error::InvalidControlResponse);
+        }
+        Ok(())
+    }
+}
+
+impl Backend for VirtualDevice {
+    fn set_bit_timing(&mut self, channel: u16, _timing: BitTiming) -> Result<(), Error> {
+        self.check_channel(channel)?;
+        self.check_fault(Fault::BitTiming(channel))
+    }
+
+    fn set_data_bit_timing(&mut self, channel: u16, _timing: BitTiming) -> Result<(), Error> {
+        self.check_channel(channel)?;
+        self.check_fault(Fault::BitTiming(channel))
+    }
+
+    fn set_mode(&mut self, channel: u16, device_mode: Mode) -> Result<(), Error> {
+        self.check_channel(channel)?;
+        if device_mode.mode == CanMode::Start as u32 {
+            self.check_fault(Fault::Start(channel))?;
+            self.started.insert(channel);
+            if device_mode.flags & GS_CAN_MODE_HW_TIMESTAMP != 0 {
+                self.timestamped.insert(channel);
+            }
+        } else {
+            self.started.remove(&channel);
+            self.timestamped.remove(&channel);
+        }
+        Ok(())
+    }
+
+    fn get_device_config(&mut self) -> Result<DeviceConfig, Error> {
+        let mut bs = [0u8; 12];
+        // icount is zero indexed
+        bs[3] = self.channel_count - 1;
+        Ok(DeviceConfig::from_le_bytes(&bs))
+    }
+
+    fn get_bit_timing_consts(&mut self) -> Result<BitTimingConsts, Error> {
+        let mut bs = Vec::new();
+        bs.extend_from_slice(&self.features.to_le_bytes());
+        bs.extend_from_slice(&VIRTUAL_CAN_CLOCK.to_le_bytes());
+        // tseg1, tseg2, sjw and brp limits typical of bxCAN style controllers
+        for v in &[1u32, 16, 1, 8, 4, 1, 1024, 1] {
+            bs.extend_from_slice(&v.to_le_bytes());
+        }
+        Ok(BitTimingConsts::from_le_bytes(&bs))
+    }
+
+    fn start_transfers(&mut self) -> Result<(), Error> {
+        self.receiving = true;
+        Ok(())
+    }
+
+    fn stop_transfers(&mut self) -> Result<(), Error> {
+        self.receiving = false;
+        Ok(())
+    }
+
+    fn send(&mut self, mut frame: HostFrame) -> Result<(), Error> {
+        if !self.started.contains(&(frame.channel as u16)) {
+            return Err(Error::TransferFailed(LIBUSB_TRANSFER_ERROR));
+        }
+        if self.receiving {
+            // echo the frame back as transmitted
+            if self.timestamped.contains(&(frame.channel as u16)) {
+                frame.timestamp_us = Some(self.epoch.elapsed().as_micros() as u32);
+            }
+            let _ = self.can_rx_send.send(frame);
+        }
+        Ok(())
+    }
+
+    fn can_rx_recv(&self) -> Receiver<HostFrame> {
+        self.can_rx_recv.clone()
+    }
+}
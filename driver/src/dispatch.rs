@@ -0,0 +1,420 @@
+//! Routing of received frames to subscribers by CAN identifier.
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+
+use crossbeam_channel::{unbounded, Receiver, Sender};
+
+use crate::Frame;
+
+// maximum number of (channel, ext, id) routes cached, the oldest route is
+// evicted when a new one is added
+const MAX_CACHED_ROUTES: usize = 4096;
+
+// (channel, ext, id) of the frames a cached route is used for
+type RouteKey = (u8, bool, u32);
+
+/// Identifiers matched by a subscription.
+///
+/// Standard and extended identifiers are distinct, so every filter except
+/// `Any` only matches frames with the given `ext` flag.
+#[derive(Debug, Clone, PartialEq)]
+pub enum IdFilter {
+    /// A single identifier.
+    Id {
+        /// Identifier to match.
+        id: u32,
+        /// Match extended (true) or standard (false) frames.
+        ext: bool,
+    },
+    /// An inclusive range of identifiers.
+    Range {
+        /// First identifier to match.
+        start: u32,
+        /// Last identifier to match.
+        end: u32,
+        /// Match extended (true) or standard (false) frames.
+        ext: bool,
+    },
+    /// Identifiers where `can_id & mask == id & mask`.
+    Mask {
+        /// Identifier to compare against.
+        id: u32,
+        /// Bits of the identifier to compare.
+        mask: u32,
+        /// Match extended (true) or standard (false) frames.
+        ext: bool,
+    },
+    /// Any identifier, standard or extended.
+    Any,
+}
+impl IdFilter {
+    /// Returns true if the identifier, extended if `ext` is true, matches
+    /// this filter.
+    pub fn matches(&self, can_id: u32, ext: bool) -> bool {
+        match *self {
+            IdFilter::Id { id, ext: e } => e == ext && can_id == id,
+            IdFilter::Range { start, end, ext: e } => e == ext && (start..=end).contains(&can_id),
+            IdFilter::Mask { id, mask, ext: e } => e == ext && can_id & mask == id & mask,
+            IdFilter::Any => true,
+        }
+    }
+}
+
+/// Handle identifying a subscription, used to unsubscribe.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SubscriptionId(u64);
+
+enum Sink {
+    Callback(Box<dyn FnMut(Frame) + Send>),
+    Channel(Sender<Frame>),
+}
+impl Sink {
+    fn deliver(&mut self, f: Frame) {
+        match self {
+            Sink::Callback(cb) => cb(f),
+            // the subscriber may have dropped its receiver without unsubscribing
+            Sink::Channel(s) => {
+                let _ = s.send(f);
+            }
+        }
+    }
+}
+
+struct Subscription {
+    channel: Option<u8>,
+    filter: IdFilter,
+    sink: Arc<Mutex<Sink>>,
+}
+impl Subscription {
+    fn matches(&self, channel: u8, ext: bool, can_id: u32) -> bool {
+        self.channel.is_none_or(|c| c == channel) && self.filter.matches(can_id, ext)
+    }
+}
+
+#[derive(Default)]
+struct Routes {
+    next_id: u64,
+    subscriptions: Vec<(SubscriptionId, Subscription)>,
+    // receive frames that no subscription matched
+    unmatched: Vec<(SubscriptionId, Arc<Mutex<Sink>>)>,
+    // sinks for each (channel, ext, id) seen since the subscriptions last
+    // changed, and the order the routes were cached in
+    cache: HashMap<RouteKey, Vec<Arc<Mutex<Sink>>>>,
+    cache_order: VecDeque<RouteKey>,
+}
+impl Routes {
+    fn next_id(&mut self) -> SubscriptionId {
+        self.next_id += 1;
+        SubscriptionId(self.next_id)
+    }
+
+    fn clear_cache(&mut self) {
+        self.cache.clear();
+        self.cache_order.clear();
+    }
+
+    fn sinks(&mut self, channel: u8, ext: bool, can_id: u32) -> Vec<Arc<Mutex<Sink>>> {
+        let key = (channel, ext, can_id);
+        if let Some(sinks) = self.cache.get(&key) {
+            return sinks.clone();
+        }
+
+        let mut sinks: Vec<Arc<Mutex<Sink>>> = self
+            .subscriptions
+            .iter()
+            .filter(|(_, s)| s.matches(channel, ext, can_id))
+            .map(|(_, s)| Arc::clone(&s.sink))
+            .collect();
+        if sinks.is_empty() {
+            sinks = self.unmatched.iter().map(|(_, s)| Arc::clone(s)).collect();
+        }
+
+        if self.cache.len() >= MAX_CACHED_ROUTES {
+            if let Some(oldest) = self.cache_order.pop_front() {
+                self.cache.remove(&oldest);
+            }
+        }
+        self.cache.insert(key, sinks.clone());
+        self.cache_order.push_back(key);
+        sinks
+    }
+}
+
+/// Routes received frames to subscribers based on channel and identifier.
+///
+/// Subscribers register interest in an identifier, range, or id/mask pair,
+/// and receive matching frames on a callback or a channel. The routes for
+/// each channel and identifier are cached, so the number of subscriptions
+/// does not affect the cost of dispatching a frame once its identifier has
+/// been seen.
+///
+/// Dispatchers are cheap to clone, and clones share subscriptions:
+///
+/// ```no_run
+/// use cantact::{Dispatcher, IdFilter, Interface};
+///
+/// let d = Dispatcher::new();
+/// let filter = IdFilter::Range {
+///     start: 0x100,
+///     end: 0x1FF,
+///     ext: false,
+/// };
+/// let (_, engine) = d.subscribe_channel(Some(0), filter);
+///
+/// let mut i = Interface::new().unwrap();
+/// i.start(d.handler()).unwrap();
+/// let f = engine.recv().unwrap();
+/// ```
+#[derive(Clone, Default)]
+pub struct Dispatcher {
+    routes: Arc<Mutex<Routes>>,
+}
+
+impl Dispatcher {
+    /// Create a dispatcher with no subscriptions.
+    pub fn new() -> Dispatcher {
+        Dispatcher::default()
+    }
+
+    /// Call `callback` for each frame matching the filter, on the given
+    /// channel or on any channel if `channel` is None.
+    ///
+    /// The callback is called from the thread dispatching frames, and must
+    /// not block for long.
+    pub fn subscribe(
+        &self,
+        channel: Option<u8>,
+        filter: IdFilter,
+        callback: impl FnMut(Frame) + Send + 'static,
+    ) -> SubscriptionId {
+        self.add(channel, filter, Sink::Callback(Box::new(callback)))
+    }
+
+    /// Subscribe to frames matching the filter, returning a receiver for them.
+    pub fn subscribe_channel(
+        &self,
+        channel: Option<u8>,
+        filter: IdFilter,
+    ) -> (SubscriptionId, Receiver<Frame>) {
+        let (send, recv) = unbounded();
+        (self.add(channel, filter, Sink::Channel(send)), recv)
+    }
+
+    /// Call `callback` for each frame that matches no other subscription.
+    pub fn subscribe_unmatched(
+        &self,
+        callback: impl FnMut(Frame) + Send + 'static,
+    ) -> SubscriptionId {
+        let mut routes = self.routes.lock().unwrap();
+        let id = routes.next_id();
+        let sink = Arc::new(Mutex::new(Sink::Callback(Box::new(callback))));
+        routes.unmatched.push((id, sink));
+        routes.clear_cache();
+        id
+    }
+
+    /// Remove a subscription. Returns false if it did not exist.
+    pub fn unsubscribe(&self, id: SubscriptionId) -> bool {
+        let mut routes = self.routes.lock().unwrap();
+        let count = routes.subscriptions.len() + routes.unmatched.len();
+        routes.subscriptions.retain(|(i, _)| *i != id);
+        routes.unmatched.retain(|(i, _)| *i != id);
+        routes.clear_cache();
+        count != routes.subscriptions.len() + routes.unmatched.len()
+    }
+
+    /// Deliver a frame to every matching subscriber.
+    pub fn dispatch(&self, f: Frame) {
+        // subscribers are called without holding the routes lock, so they
+        // can subscribe and unsubscribe
+        let sinks = self
+            .routes
+            .lock()
+            .unwrap()
+            .sinks(f.channel, f.ext, f.can_id);
+        if let Some((last, rest)) = sinks.split_last() {
+            for sink in rest {
+                sink.lock().unwrap().deliver(f.clone());
+            }
+            last.lock().unwrap().deliver(f);
+        }
+    }
+
+    /// Returns a receive callback for `Interface::start` that dispatches
+    /// every received frame.
+    pub fn handler(&self) -> impl FnMut(Frame) + Sync + Send + 'static {
+        let d = self.clone();
+        move |f: Frame| d.dispatch(f)
+    }
+
+    fn add(&self, channel: Option<u8>, filter: IdFilter, sink: Sink) -> SubscriptionId {
+        let mut routes = self.routes.lock().unwrap();
+        let id = routes.next_id();
+        routes.subscriptions.push((
+            id,
+            Subscription {
+                channel,
+                filter,
+                sink: Arc::new(Mutex::new(sink)),
+            },
+        ));
+        routes.clear_cache();
+        id
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn frame(channel: u8, can_id: u32) -> Frame {
+        Frame {
+            channel,
+            can_id,
+            ..Frame::default()
+        }
+    }
+
+    fn ext_frame(channel: u8, can_id: u32) -> Frame {
+        Frame {
+            ext: true,
+            ..frame(channel, can_id)
+        }
+    }
+
+    #[test]
+    fn test_filters() {
+        let d = Dispatcher::new();
+        let (_, id) = d.subscribe_channel(
+            None,
+            IdFilter::Id {
+                id: 0x123,
+                ext: false,
+            },
+        );
+        let (_, range) = d.subscribe_channel(
+            Some(1),
+            IdFilter::Range {
+                start: 0x100,
+                end: 0x1FF,
+                ext: false,
+            },
+        );
+        let (_, mask) = d.subscribe_channel(
+            Some(0),
+            IdFilter::Mask {
+                id: 0x700,
+                mask: 0x7F0,
+                ext: false,
+            },
+        );
+
+        for &(ch, can_id) in &[(0, 0x123), (1, 0x123), (1, 0x1FF), (0, 0x705), (0, 0x715)] {
+            d.dispatch(frame(ch, can_id));
+        }
+
+        let ids = |r: &Receiver<Frame>| r.try_iter().map(|f| f.can_id).collect::<Vec<_>>();
+        assert_eq!(ids(&id), vec![0x123, 0x123]);
+        assert_eq!(ids(&range), vec![0x123, 0x1FF]);
+        assert_eq!(ids(&mask), vec![0x705]);
+    }
+
+    #[test]
+    fn test_unsubscribe_and_unmatched() {
+        let d = Dispatcher::new();
+        let unmatched = Arc::new(AtomicUsize::new(0));
+        let u = Arc::clone(&unmatched);
+        let catch_all = d.subscribe_unmatched(move |_| {
+            u.fetch_add(1, Ordering::SeqCst);
+        });
+        let (sub, recv) = d.subscribe_channel(None, IdFilter::Any);
+
+        d.dispatch(frame(0, 0x10));
+        assert_eq!(recv.try_iter().count(), 1);
+        assert_eq!(unmatched.load(Ordering::SeqCst), 0);
+
+        assert!(d.unsubscribe(sub));
+        assert!(!d.unsubscribe(sub));
+        d.dispatch(frame(0, 0x10));
+        assert_eq!(recv.try_iter().count(), 0);
+        assert_eq!(unmatched.load(Ordering::SeqCst), 1);
+
+        assert!(d.unsubscribe(catch_all));
+        d.dispatch(frame(0, 0x10));
+        assert_eq!(unmatched.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_many_subscriptions() {
+        let d = Dispatcher::new();
+        let receivers: Vec<Receiver<Frame>> = (0..500)
+            .map(|id| {
+                d.subscribe_channel(Some(0), IdFilter::Id { id, ext: false })
+                    .1
+            })
+            .collect();
+
+        for id in 0..500 {
+            d.dispatch(frame(0, id));
+            d.dispatch(frame(1, id));
+        }
+        for (id, r) in receivers.iter().enumerate() {
+            let frames: Vec<Frame> = r.try_iter().collect();
+            assert_eq!(frames.len(), 1);
+            assert_eq!(frames[0].can_id, id as u32);
+        }
+    }
+
+    #[test]
+    fn test_extended_ids() {
+        let d = Dispatcher::new();
+        let (_, std) = d.subscribe_channel(
+            None,
+            IdFilter::Id {
+                id: 0x123,
+                ext: false,
+            },
+        );
+        let (_, ext) = d.subscribe_channel(
+            None,
+            IdFilter::Id {
+                id: 0x123,
+                ext: true,
+            },
+        );
+        let (_, any) = d.subscribe_channel(None, IdFilter::Any);
+
+        // the cached route for the standard identifier must not be reused
+        // for the extended one
+        for _ in 0..2 {
+            d.dispatch(frame(0, 0x123));
+            d.dispatch(ext_frame(0, 0x123));
+        }
+
+        let flags = |r: &Receiver<Frame>| r.try_iter().map(|f| f.ext).collect::<Vec<_>>();
+        assert_eq!(flags(&std), vec![false, false]);
+        assert_eq!(flags(&ext), vec![true, true]);
+        assert_eq!(flags(&any), vec![false, true, false, true]);
+    }
+
+    #[test]
+    fn test_cache_eviction() {
+        let d = Dispatcher::new();
+        let (_, recv) = d.subscribe_channel(None, IdFilter::Any);
+
+        let count = MAX_CACHED_ROUTES as u32 + 10;
+        for id in 0..count {
+            d.dispatch(ext_frame(0, id));
+        }
+        assert_eq!(recv.try_iter().count(), count as usize);
+
+        // only the oldest routes are evicted when the cache is full
+        let routes = d.routes.lock().unwrap();
+        assert_eq!(routes.cache.len(), MAX_CACHED_ROUTES);
+        assert!(!routes.cache.contains_key(&(0, true, 0)));
+        assert!(routes.cache.contains_key(&(0, true, 10)));
+        assert!(routes.cache.contains_key(&(0, true, count - 1)));
+    }
+}
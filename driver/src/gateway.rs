@@ -0,0 +1,459 @@
+//! Forwarding of frames between two channels, with rules to drop, rewrite,
+//! or delay selected frames.
+
+use std::cmp::{Ordering, Reverse};
+use std::collections::BinaryHeap;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crossbeam_channel::{Receiver, RecvTimeoutError};
+use serde::{Deserialize, Serialize};
+
+use crate::{Error, Frame};
+
+// largest standard and extended identifiers
+const MAX_STD_ID: u32 = 0x7FF;
+const MAX_EXT_ID: u32 = 0x1FFF_FFFF;
+
+/// Direction a frame travels through a `Gateway`, between its sides A and B.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Direction {
+    /// Received on side A, sent on side B.
+    AToB,
+    /// Received on side B, sent on side A.
+    BToA,
+}
+
+/// A gateway rule. Frames are matched against rules in order, and the first
+/// rule matching a frame is applied to it. Frames matching no rule are
+/// forwarded unchanged.
+///
+/// Rules can be deserialized, for example from TOML:
+///
+/// ```toml
+/// [[rule]]
+/// name = "rewrite speed"
+/// direction = "a-to-b"
+/// id = 0x100
+/// set_id = 0x101
+/// data_mask = [0x00, 0xFF]
+/// data_value = [0x00, 0x12]
+/// delay_ms = 10
+/// ```
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Rule {
+    /// Name used when reporting the rule.
+    pub name: Option<String>,
+    /// Direction of the frames this rule applies to, or both if None.
+    pub direction: Option<Direction>,
+    /// Identifier to match, or any identifier if None.
+    pub id: Option<u32>,
+    /// Bits of the identifier compared with `id`. All bits by default.
+    pub id_mask: Option<u32>,
+    /// Only match extended (true) or standard (false) frames if set.
+    pub ext: Option<bool>,
+    /// Drop matching frames instead of forwarding them.
+    pub drop: bool,
+    /// Replace the identifier of matching frames.
+    pub set_id: Option<u32>,
+    /// Bits of each data byte replaced with the bits of `data_value`. Bytes
+    /// past the end of the mask, or of the frame, are left unchanged.
+    pub data_mask: Vec<u8>,
+    /// New values for the bits in `data_mask`.
+    pub data_value: Vec<u8>,
+    /// Delay before forwarding matching frames, in milliseconds.
+    pub delay_ms: u64,
+}
+
+impl Rule {
+    /// Returns true if the rule applies to a frame travelling in `dir`.
+    pub fn matches(&self, dir: Direction, f: &Frame) -> bool {
+        let mask = self.id_mask.unwrap_or(MAX_EXT_ID);
+        self.direction.is_none_or(|d| d == dir)
+            && self.ext.is_none_or(|e| e == f.ext)
+            && self.id.is_none_or(|id| f.can_id & mask == id & mask)
+    }
+
+    // returns a description of the first problem with the rule
+    fn validate(&self) -> Result<(), String> {
+        if self.data_mask.len() != self.data_value.len() {
+            return Err(String::from(
+                "data_mask and data_value must have the same length",
+            ));
+        }
+        if let Some(id) = self.set_id {
+            if id > MAX_EXT_ID {
+                return Err(format!("set_id 0x{:X} is not a valid identifier", id));
+            }
+            if id > MAX_STD_ID && self.ext != Some(true) {
+                return Err(format!(
+                    "set_id 0x{:X} is extended, so the rule must only match extended frames (ext = true)",
+                    id
+                ));
+            }
+        }
+        if self.drop && (self.set_id.is_some() || !self.data_mask.is_empty() || self.delay_ms > 0) {
+            return Err(String::from(
+                "rules that drop frames cannot also rewrite or delay them",
+            ));
+        }
+        Ok(())
+    }
+
+    // modifies a frame as described by the rule
+    fn apply(&self, f: &mut Frame) {
+        if let Some(id) = self.set_id {
+            f.can_id = id;
+        }
+        let len = f.data_len().min(f.data.len());
+        for (n, (mask, value)) in self.data_mask.iter().zip(&self.data_value).enumerate() {
+            if n >= len {
+                break;
+            }
+            f.data[n] = (f.data[n] & !mask) | (value & mask);
+        }
+    }
+}
+
+/// Counters for the frames travelling in one direction through a `Gateway`.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct DirectionStats {
+    /// Number of frames received.
+    pub received: u64,
+    /// Number of frames forwarded.
+    pub forwarded: u64,
+    /// Number of frames dropped by a rule.
+    pub dropped: u64,
+    /// Number of frames that could not be forwarded.
+    pub failed: u64,
+}
+
+/// Counters for a `Gateway`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct GatewayStats {
+    /// Frames received on side A.
+    pub a_to_b: DirectionStats,
+    /// Frames received on side B.
+    pub b_to_a: DirectionStats,
+    /// Number of frames each rule was applied to, in the order of the rules.
+    pub hits: Vec<u64>,
+}
+
+impl GatewayStats {
+    fn direction(&mut self, dir: Direction) -> &mut DirectionStats {
+        match dir {
+            Direction::AToB => &mut self.a_to_b,
+            Direction::BToA => &mut self.b_to_a,
+        }
+    }
+}
+
+// a frame waiting for its delay to pass, ordered by deadline then arrival
+struct Pending {
+    deadline: Instant,
+    seq: u64,
+    dir: Direction,
+    frame: Frame,
+}
+impl PartialEq for Pending {
+    fn eq(&self, other: &Pending) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+impl Eq for Pending {}
+impl PartialOrd for Pending {
+    fn partial_cmp(&self, other: &Pending) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+impl Ord for Pending {
+    fn cmp(&self, other: &Pending) -> Ordering {
+        (self.deadline, self.seq).cmp(&(other.deadline, other.seq))
+    }
+}
+
+/// Forwards frames in both directions between two sides, applying rules.
+///
+/// The sides can be two channels of one device, or channels of two devices.
+/// Received frames are passed to the gateway with the direction they travel,
+/// and the gateway calls `send` with frames to transmit. Delayed frames are
+/// held by the gateway until their deadline passes, so frames following a
+/// delayed frame are not held up.
+///
+/// ```no_run
+/// use cantact::{Direction, Gateway, Interface};
+/// use std::time::Duration;
+///
+/// let mut i = Interface::new().unwrap();
+/// let (send, recv) = crossbeam_channel::unbounded();
+/// i.start(move |f| {
+///     let dir = if f.channel == 0 { Direction::AToB } else { Direction::BToA };
+///     send.send((dir, f)).unwrap();
+/// })
+/// .unwrap();
+///
+/// let mut gw = Gateway::new(Vec::new()).unwrap();
+/// while gw.poll(&recv, Duration::from_millis(100), |dir, mut f| {
+///     f.channel = if dir == Direction::AToB { 1 } else { 0 };
+///     i.send(f)
+/// }) {}
+/// ```
+pub struct Gateway {
+    rules: Vec<Rule>,
+    stats: GatewayStats,
+    pending: BinaryHeap<Reverse<Pending>>,
+    seq: u64,
+}
+
+impl Gateway {
+    /// Create a gateway applying `rules`.
+    ///
+    /// If a rule is invalid, `Error::InvalidRule` is returned with the index
+    /// of the rule.
+    pub fn new(rules: Vec<Rule>) -> Result<Gateway, Error> {
+        for (n, r) in rules.iter().enumerate() {
+            r.validate().map_err(|e| Error::InvalidRule(n, e))?;
+        }
+        Ok(Gateway {
+            stats: GatewayStats {
+                hits: vec![0; rules.len()],
+                ..GatewayStats::default()
+            },
+            rules,
+            pending: BinaryHeap::new(),
+            seq: 0,
+        })
+    }
+
+    /// Returns the gateway's rules.
+    pub fn rules(&self) -> &[Rule] {
+        &self.rules
+    }
+
+    /// Returns the gateway's counters.
+    pub fn stats(&self) -> &GatewayStats {
+        &self.stats
+    }
+
+    /// Returns the number of delayed frames waiting to be sent.
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Apply the rules to a frame received at `now`, returning the frame to
+    /// forward and the time to forward it, or None if it is not forwarded.
+    ///
+    /// Loopback frames, which are echoes of frames sent by the device, and
+    /// error frames are never forwarded and are not counted.
+    pub fn process(
+        &mut self,
+        dir: Direction,
+        mut f: Frame,
+        now: Instant,
+    ) -> Option<(Frame, Instant)> {
+        if f.loopback || f.err {
+            return None;
+        }
+        self.stats.direction(dir).received += 1;
+
+        let mut deadline = now;
+        if let Some(n) = self.rules.iter().position(|r| r.matches(dir, &f)) {
+            self.stats.hits[n] += 1;
+            let rule = &self.rules[n];
+            if rule.drop {
+                self.stats.direction(dir).dropped += 1;
+                return None;
+            }
+            rule.apply(&mut f);
+            deadline += Duration::from_millis(rule.delay_ms);
+        }
+        Some((f, deadline))
+    }
+
+    /// Forward frames received from `recv` for up to `timeout`, calling
+    /// `send` for each frame to transmit in the direction it travels.
+    ///
+    /// Returns false once `recv` is disconnected and no delayed frames are
+    /// left to send.
+    pub fn poll(
+        &mut self,
+        recv: &Receiver<(Direction, Frame)>,
+        timeout: Duration,
+        mut send: impl FnMut(Direction, Frame) -> Result<(), Error>,
+    ) -> bool {
+        let end = Instant::now() + timeout;
+        let mut connected = true;
+        loop {
+            let now = Instant::now();
+            self.send_pending(now, &mut send);
+            if now >= end || (!connected && self.pending.is_empty()) {
+                return connected || !self.pending.is_empty();
+            }
+
+            // wake up for the next delayed frame, if it is due before the end
+            let wake = match self.pending.peek() {
+                Some(Reverse(p)) => p.deadline.min(end),
+                None => end,
+            };
+            if !connected {
+                thread::sleep(wake.saturating_duration_since(now));
+                continue;
+            }
+            match recv.recv_timeout(wake.saturating_duration_since(now)) {
+                Ok((dir, f)) => {
+                    if let Some((f, deadline)) = self.process(dir, f, Instant::now()) {
+                        self.seq += 1;
+                        self.pending.push(Reverse(Pending {
+                            deadline,
+                            seq: self.seq,
+                            dir,
+                            frame: f,
+                        }));
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => connected = false,
+            }
+        }
+    }
+
+    // send every delayed frame that is due
+    fn send_pending(
+        &mut self,
+        now: Instant,
+        send: &mut impl FnMut(Direction, Frame) -> Result<(), Error>,
+    ) {
+        while self
+            .pending
+            .peek()
+            .is_some_and(|Reverse(p)| p.deadline <= now)
+        {
+            let Reverse(p) = self.pending.pop().unwrap();
+            let stats = self.stats.direction(p.dir);
+            match send(p.dir, p.frame) {
+                Ok(()) => stats.forwarded += 1,
+                Err(_) => stats.failed += 1,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crossbeam_channel::unbounded;
+
+    fn frame(can_id: u32, data: &[u8]) -> Frame {
+        let mut f = Frame {
+            can_id,
+            can_dlc: data.len() as u8,
+            ..Frame::default()
+        };
+        f.data[..data.len()].copy_from_slice(data);
+        f
+    }
+
+    #[test]
+    fn test_rules() {
+        let rules = vec![
+            Rule {
+                direction: Some(Direction::AToB),
+                id: Some(0x7DF),
+                drop: true,
+                ..Rule::default()
+            },
+            Rule {
+                id: Some(0x100),
+                id_mask: Some(0x7F0),
+                set_id: Some(0x200),
+                data_mask: vec![0x00, 0xF0],
+                data_value: vec![0xFF, 0xA0],
+                ..Rule::default()
+            },
+        ];
+        let mut gw = Gateway::new(rules).unwrap();
+        let now = Instant::now();
+
+        assert!(gw
+            .process(Direction::AToB, frame(0x7DF, &[]), now)
+            .is_none());
+        // the drop rule only applies from A to B
+        assert!(gw
+            .process(Direction::BToA, frame(0x7DF, &[]), now)
+            .is_some());
+
+        let (f, t) = gw
+            .process(Direction::AToB, frame(0x105, &[0x12, 0x34, 0x56]), now)
+            .unwrap();
+        assert_eq!(t, now);
+        assert_eq!(f.can_id, 0x200);
+        assert_eq!(&f.data[..3], &[0x12, 0xA4, 0x56]);
+
+        // echoes of sent frames are ignored
+        let mut echo = frame(0x7DF, &[]);
+        echo.loopback = true;
+        assert!(gw.process(Direction::AToB, echo, now).is_none());
+
+        assert_eq!(gw.stats().hits, vec![1, 1]);
+        assert_eq!(gw.stats().a_to_b.received, 2);
+        assert_eq!(gw.stats().a_to_b.dropped, 1);
+        assert_eq!(gw.stats().b_to_a.received, 1);
+    }
+
+    #[test]
+    fn test_invalid_rules() {
+        let bad = [
+            Rule {
+                data_mask: vec![0xFF],
+                ..Rule::default()
+            },
+            Rule {
+                set_id: Some(0x800),
+                ..Rule::default()
+            },
+            Rule {
+                drop: true,
+                delay_ms: 5,
+                ..Rule::default()
+            },
+        ];
+        for rule in bad.iter() {
+            let rules = vec![Rule::default(), rule.clone()];
+            match Gateway::new(rules) {
+                Err(Error::InvalidRule(1, _)) => {}
+                r => panic!("expected invalid rule, got {:?}", r.map(|_| ())),
+            }
+        }
+    }
+
+    #[test]
+    fn test_delay() {
+        let rules = vec![Rule {
+            id: Some(0x10),
+            delay_ms: 50,
+            ..Rule::default()
+        }];
+        let mut gw = Gateway::new(rules).unwrap();
+        let (send, recv) = unbounded();
+        send.send((Direction::AToB, frame(0x10, &[]))).unwrap();
+        send.send((Direction::BToA, frame(0x20, &[]))).unwrap();
+        drop(send);
+
+        let start = Instant::now();
+        let mut sent = Vec::new();
+        while gw.poll(&recv, Duration::from_millis(10), |dir, f| {
+            sent.push((dir, f.can_id, start.elapsed()));
+            Ok(())
+        }) {}
+
+        // the delayed frame does not hold up the one after it
+        assert_eq!(sent[0].0, Direction::BToA);
+        assert!(sent[0].2 < Duration::from_millis(25));
+        assert_eq!(sent[1].1, 0x10);
+        assert!(sent[1].2 >= Duration::from_millis(50));
+        assert_eq!(gw.stats().a_to_b.forwarded, 1);
+        assert_eq!(gw.pending(), 0);
+    }
+}
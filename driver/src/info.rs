@@ -0,0 +1,163 @@
+//! Information about attached devices and their capabilities.
+
+use crate::device::gsusb::*;
+use crate::device::{self, UsbContext};
+use crate::Error;
+use serde::Serialize;
+
+// names of the GS_CAN_FEATURE_* bits
+const FEATURE_NAMES: [(u32, &str); 9] = [
+    (GS_CAN_FEATURE_LISTEN_ONLY, "listen-only"),
+    (GS_CAN_FEATURE_LOOP_BACK, "loop-back"),
+    (GS_CAN_FEATURE_TRIPLE_SAMPLE, "triple-sample"),
+    (GS_CAN_FEATURE_ONE_SHOT, "one-shot"),
+    (GS_CAN_FEATURE_HW_TIMESTAMP, "hw-timestamp"),
+    (GS_CAN_FEATURE_IDENTIFY, "identify"),
+    (GS_CAN_FEATURE_USER_ID, "user-id"),
+    (
+        GS_CAN_FEATURE_PAD_PKTS_TO_MAX_PKT_SIZE,
+        "pad-pkts-to-max-pkt-size",
+    ),
+    (GS_CAN_FEATURE_FD, "fd"),
+];
+
+/// Returns the names of the feature bits set in `features`, as reported by
+/// `Interface::features`.
+pub fn feature_names(features: u32) -> Vec<&'static str> {
+    FEATURE_NAMES
+        .iter()
+        .filter(|(bit, _)| features & bit != 0)
+        .map(|(_, name)| *name)
+        .collect()
+}
+
+/// Limits on bit timing parameters supported by a device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize)]
+pub struct BitTimingLimits {
+    /// Minimum time segment 1, in time quanta.
+    pub tseg1_min: u32,
+    /// Maximum time segment 1, in time quanta.
+    pub tseg1_max: u32,
+    /// Minimum time segment 2, in time quanta.
+    pub tseg2_min: u32,
+    /// Maximum time segment 2, in time quanta.
+    pub tseg2_max: u32,
+    /// Maximum synchronization jump width, in time quanta.
+    pub sjw_max: u32,
+    /// Minimum bit rate prescaler.
+    pub brp_min: u32,
+    /// Maximum bit rate prescaler.
+    pub brp_max: u32,
+    /// Bit rate prescaler increment.
+    pub brp_inc: u32,
+}
+impl BitTimingLimits {
+    pub(crate) fn from_consts(c: &BitTimingConsts) -> BitTimingLimits {
+        BitTimingLimits {
+            tseg1_min: c.tseg1_min,
+            tseg1_max: c.tseg1_max,
+            tseg2_min: c.tseg2_min,
+            tseg2_max: c.tseg2_max,
+            sjw_max: c.sjw_max,
+            brp_min: c.brp_min,
+            brp_max: c.brp_max,
+            brp_inc: c.brp_inc,
+        }
+    }
+
+    // checks that a bit timing can be set on a device with these limits
+    pub(crate) fn check(&self, bt: &BitTiming) -> Result<(), Error> {
+        let tseg1 = bt.prop_seg + bt.phase_seg1;
+        if !(self.tseg1_min..=self.tseg1_max).contains(&tseg1) {
+            return Err(Error::InvalidBitTiming(format!(
+                "time segment 1 of {} time quanta is outside the device limits ({} to {})",
+                tseg1, self.tseg1_min, self.tseg1_max
+            )));
+        }
+        if !(self.tseg2_min..=self.tseg2_max).contains(&bt.phase_seg2) {
+            return Err(Error::InvalidBitTiming(format!(
+                "time segment 2 of {} time quanta is outside the device limits ({} to {})",
+                bt.phase_seg2, self.tseg2_min, self.tseg2_max
+            )));
+        }
+        if bt.sjw > self.sjw_max {
+            return Err(Error::InvalidBitTiming(format!(
+                "SJW {} is longer than the device maximum of {}",
+                bt.sjw, self.sjw_max
+            )));
+        }
+        if !(self.brp_min..=self.brp_max).contains(&bt.brp)
+            || (self.brp_inc > 1 && !(bt.brp - self.brp_min).is_multiple_of(self.brp_inc))
+        {
+            return Err(Error::InvalidBitTiming(format!(
+                "prescaler {} is not supported by the device ({} to {} in steps of {})",
+                bt.brp,
+                self.brp_min,
+                self.brp_max,
+                self.brp_inc.max(1)
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// An attached CANtact device.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DeviceInfo {
+    /// Position of the device, for use with `DeviceSelector::Index`.
+    pub index: usize,
+    /// USB serial number, or None if the device could not be opened to read it.
+    pub serial: Option<String>,
+    /// USB bus number.
+    pub bus: u8,
+    /// USB device address on the bus.
+    pub address: u8,
+    /// Hub port numbers from the root hub to the device.
+    pub ports: Vec<u8>,
+}
+impl DeviceInfo {
+    /// Returns the bus path of the device, in the form used by Linux sysfs,
+    /// e.g. `1-2.3` for port 3 of a hub on port 2 of bus 1.
+    pub fn path(&self) -> String {
+        let ports: Vec<String> = self.ports.iter().map(|p| p.to_string()).collect();
+        format!("{}-{}", self.bus, ports.join("."))
+    }
+}
+
+/// Returns every attached CANtact device.
+pub fn list_devices() -> Result<Vec<DeviceInfo>, Error> {
+    let ctx = UsbContext::new()?;
+    let devices = device::list_usb_devices(&ctx)?
+        .into_iter()
+        .enumerate()
+        .map(|(index, d)| DeviceInfo {
+            index,
+            serial: d.serial,
+            bus: d.bus,
+            address: d.address,
+            ports: d.ports,
+        })
+        .collect();
+    Ok(devices)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_info() {
+        assert_eq!(
+            feature_names(GS_CAN_FEATURE_LISTEN_ONLY | GS_CAN_FEATURE_FD),
+            vec!["listen-only", "fd"]
+        );
+        let d = DeviceInfo {
+            index: 0,
+            serial: None,
+            bus: 1,
+            address: 5,
+            ports: vec![2, 3],
+        };
+        assert_eq!(d.path(), "1-2.3");
+    }
+}
@@ -8,17 +8,29 @@
 #![warn(missing_docs)]
 
 use std::fmt;
-use std::sync::{Arc, RwLock};
+use std::sync::{Arc, Mutex, RwLock};
 use std::thread;
 use std::time;
 
-use crossbeam_channel::RecvError;
+use crossbeam_channel::{bounded, select, Sender};
 
 use serde::{Deserialize, Serialize};
 
+mod builder;
 mod device;
+mod dispatch;
+mod gateway;
+mod info;
+mod scheduler;
+mod timebase;
+pub use builder::*;
 use device::gsusb::*;
 use device::*;
+pub use dispatch::*;
+pub use gateway::*;
+pub use info::*;
+pub use scheduler::*;
+pub use timebase::*;
 
 pub mod c;
 /// Implementation of Python bindings
@@ -42,17 +54,93 @@ pub enum Error {
     InvalidChannel,
     /// The requested bitrate cannot be set within an acceptable tolerance
     InvalidBitrate(u32),
+    /// The requested sample point or synchronization jump width cannot be
+    /// used. Contains a description of the problem.
+    InvalidBitTiming(String),
+    /// The configuration of a channel is invalid. Contains the channel index
+    /// and the problem with its configuration.
+    ChannelConfig(usize, Box<Error>),
     /// The requested set of features is not supported by the device
     UnsupportedFeature(&'static str),
+    /// The requested configuration is invalid for the device. Contains an
+    /// error for each problem found.
+    InvalidConfiguration(Vec<Error>),
+    /// A gateway rule is invalid. Contains the index of the rule and a
+    /// description of the problem.
+    InvalidRule(usize, String),
+    /// The period of a periodic transmission must be greater than zero.
+    InvalidPeriod,
 }
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::DeviceError(e) => write!(f, "device error: {}", e),
+            Error::DeviceNotFound => {
+                write!(f, "no CANtact device found, check that it is connected")?;
+                if cfg!(target_os = "linux") {
+                    write!(
+                        f,
+                        "\nif it is, you may not have permission to access it: \
+                         install the udev rules described in the README, or run as root"
+                    )?;
+                }
+                Ok(())
+            }
+            Error::Timeout => write!(f, "timed out communicating with the device"),
+            Error::Running => write!(f, "the device must be stopped first"),
+            Error::NotRunning => write!(f, "the device must be started first"),
+            Error::InvalidChannel => write!(f, "channel does not exist on this device"),
+            Error::InvalidBitrate(bitrate) => write!(
+                f,
+                "bitrate {} cannot be set on this device, try a standard bitrate such as 500000",
+                bitrate
+            ),
+            Error::InvalidBitTiming(msg) => write!(f, "invalid bit timing: {}", msg),
+            Error::ChannelConfig(n, e) => write!(f, "channel {}: {}", n, e),
+            Error::UnsupportedFeature(feature) => {
+                write!(f, "{} mode is not supported by this device", feature)
+            }
+            Error::InvalidConfiguration(errors) => {
+                write!(f, "invalid configuration")?;
+                for e in errors {
+                    write!(f, "\n  {}", e)?;
+                }
+                Ok(())
+            }
+            Error::InvalidRule(n, msg) => write!(f, "invalid rule {}: {}", n + 1, msg),
+            Error::InvalidPeriod => write!(f, "the period must be greater than zero"),
+        }
+    }
+}
+impl std::error::Error for Error {}
 impl From<device::Error> for Error {
     fn from(e: device::Error) -> Error {
-        // TODO
-        // this could do a much better job of converting
-        Error::DeviceError(e)
+        match e {
+            device::Error::DeviceNotFound => Error::DeviceNotFound,
+            device::Error::TransferFailed(libusb1_sys::constants::LIBUSB_TRANSFER_TIMED_OUT) => {
+                Error::Timeout
+            }
+            e => Error::DeviceError(e),
+        }
     }
 }
 
+/// Source of the timestamp applied to received frames.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TimestampMode {
+    /// Received frames are not timestamped.
+    None,
+    /// Host time elapsed since the start of the interface's time base.
+    Host,
+    /// Wall-clock time since the Unix epoch (UTC), taken from the host.
+    Wall,
+    /// The device's hardware timestamps, converted to time elapsed since the
+    /// start of the interface's time base. The device clock is continuously
+    /// correlated with the host clock to compensate for drift. Frames without
+    /// a hardware timestamp fall back to host time.
+    Hardware,
+}
+
 /// Controller Area Network Frame
 #[derive(Debug, Clone)]
 pub struct Frame {
@@ -120,32 +208,24 @@ impl Frame {
         } else {
             can_id
         };
+        // apply FD flags, BRS is only valid for FD frames
+        let mut flags = 0;
+        if self.fd {
+            flags |= GS_CAN_FLAG_FD;
+            if self.brs {
+                flags |= GS_CAN_FLAG_BRS;
+            }
+        }
 
         HostFrame {
             echo_id: 1,
-            flags: if self.fd { GS_CAN_FLAG_FD } else { 0 },
+            flags,
             reserved: 0,
             can_id,
             can_dlc: self.can_dlc,
             channel: self.channel,
             data: self.data_as_array(),
-        }
-    }
-    /// Returns a default CAN frame with all values set to zero/false.
-    pub fn default() -> Frame {
-        Frame {
-            can_id: 0,
-            can_dlc: 0,
-            data: vec![0; 64],
-            channel: 0,
-            ext: false,
-            fd: false,
-            loopback: false,
-            rtr: false,
-            brs: false,
-            esi: false,
-            err: false,
-            timestamp: None,
+            timestamp_us: None,
         }
     }
     fn from_host_frame(hf: HostFrame) -> Frame {
@@ -196,6 +276,26 @@ impl Frame {
     }
 }
 
+impl Default for Frame {
+    /// Returns a default CAN frame with all values set to zero/false.
+    fn default() -> Frame {
+        Frame {
+            can_id: 0,
+            can_dlc: 0,
+            data: vec![0; 64],
+            channel: 0,
+            ext: false,
+            fd: false,
+            loopback: false,
+            rtr: false,
+            brs: false,
+            esi: false,
+            err: false,
+            timestamp: None,
+        }
+    }
+}
+
 /// Configuration for a device's CAN channel.
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Channel {
@@ -211,12 +311,60 @@ pub struct Channel {
     pub fd: bool,
     /// CAN FD data bitrate of the channel in bits/second
     pub data_bitrate: u32,
+    /// Sample point of the nominal bit rate, as a fraction of the bit time
+    /// (e.g. 0.875). When None, the earliest valid sample point is used.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sample_point: Option<f32>,
+    /// Synchronization jump width in time quanta. When None, 1 is used.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sjw: Option<u32>,
+    /// When true, frames are not retransmitted if they lose arbitration or
+    /// are not acknowledged.
+    #[serde(default)]
+    pub one_shot: bool,
+    /// When true, the bus is sampled three times per bit.
+    #[serde(default)]
+    pub triple_sample: bool,
+    /// Name of the channel, for display.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+}
+
+// a thread waiting in `Interface::transact` for a matching frame
+struct Waiter {
+    id: u64,
+    matcher: Box<dyn Fn(&Frame) -> bool + Send>,
+    send: Sender<Frame>,
+}
+
+#[derive(Default)]
+struct Waiters {
+    next_id: u64,
+    list: Vec<Waiter>,
+}
+impl Waiters {
+    // hands the frame to the first waiter it matches, which stops waiting
+    fn offer(&mut self, f: &Frame) {
+        if let Some(n) = self.list.iter().position(|w| (w.matcher)(f)) {
+            let w = self.list.remove(n);
+            let _ = w.send.send(f.clone());
+        }
+    }
 }
 
 /// Interface for interacting with CANtact devices
 pub struct Interface {
-    dev: Device,
+    dev: Arc<Mutex<Box<dyn Backend>>>,
     running: Arc<RwLock<bool>>,
+    rx_thread: Option<thread::JoinHandle<()>>,
+    // dropped to tell the rx thread to exit
+    rx_shutdown: Option<Sender<()>>,
+    timestamp_mode: TimestampMode,
+    time_base: TimeBase,
+    // false if a new time base is taken each time the interface starts
+    fixed_time_base: bool,
+    waiters: Arc<Mutex<Waiters>>,
+    scheduler: Scheduler,
 
     can_clock: u32,
     // zero indexed (0 = 1 channel, 1 = 2 channels, etc...)
@@ -224,19 +372,25 @@ pub struct Interface {
     sw_version: u32,
     hw_version: u32,
     features: u32,
+    bit_timing_limits: BitTimingLimits,
 
     channels: Vec<Channel>,
+    // true for each channel that is currently on bus
+    channels_started: Vec<bool>,
 }
 
 impl fmt::Debug for Interface {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("Interface")
             .field("running", &(*self.running.read().unwrap()))
+            .field("timestamp_mode", &self.timestamp_mode)
+            .field("time_base", &self.time_base)
             .field("can_clock", &self.can_clock)
             .field("channel_count", &self.channel_count)
             .field("sw_version", &self.sw_version)
             .field("hw_version", &self.hw_version)
             .field("channels", &self.channels)
+            .field("channels_started", &self.channels_started)
             .finish()
     }
 }
@@ -244,12 +398,22 @@ impl fmt::Debug for Interface {
 impl Interface {
     /// Creates a new interface. This always selects the first device found by
     /// libusb. If no device is found, Error::DeviceNotFound is returned.
+    ///
+    /// Use `Interface::builder` to select a device and configure channels.
     pub fn new() -> Result<Interface, Error> {
-        let mut dev = match Device::new(UsbContext::new()) {
+        let dev = match Device::new(UsbContext::new()?) {
             Ok(d) => d,
             Err(_) => return Err(Error::DeviceNotFound),
         };
+        Interface::from_backend(Box::new(dev))
+    }
 
+    /// Returns a builder for configuring an interface before opening it.
+    pub fn builder() -> InterfaceBuilder {
+        InterfaceBuilder::new()
+    }
+
+    fn from_backend(mut dev: Box<dyn Backend>) -> Result<Interface, Error> {
         let dev_config = dev.get_device_config()?;
         let bt_consts = dev.get_bit_timing_consts()?;
 
@@ -265,20 +429,36 @@ impl Interface {
                 monitor: false,
                 fd: false,
                 data_bitrate: 0,
+                sample_point: None,
+                sjw: None,
+                one_shot: false,
+                triple_sample: false,
+                name: None,
             });
         }
 
+        let channels_started = vec![false; channels.len()];
+
         let i = Interface {
-            dev,
+            dev: Arc::new(Mutex::new(dev)),
             running: Arc::new(RwLock::from(false)),
+            rx_thread: None,
+            rx_shutdown: None,
+            timestamp_mode: TimestampMode::Host,
+            time_base: TimeBase::now(),
+            fixed_time_base: false,
+            waiters: Arc::new(Mutex::new(Waiters::default())),
+            scheduler: Scheduler::default(),
 
             channel_count,
             can_clock: bt_consts.fclk_can,
             sw_version: dev_config.sw_version,
             hw_version: dev_config.hw_version,
             features: bt_consts.feature,
+            bit_timing_limits: BitTimingLimits::from_consts(&bt_consts),
 
             channels,
+            channels_started,
         };
 
         Ok(i)
@@ -292,82 +472,302 @@ impl Interface {
         &mut self,
         mut rx_callback: impl FnMut(Frame) + Sync + Send + 'static,
     ) -> Result<(), Error> {
-        // tell the device to go on bus
+        if *self.running.read().unwrap() {
+            return Err(Error::Running);
+        }
+
+        // check that every enabled channel's mode is supported before going on bus
+        let mut modes = Vec::new();
         for (i, ch) in self.channels.iter().enumerate() {
-            let mut flags = 0;
-            // for each mode flag, check that the feature is supported before applying feature
-            // this is necessary since the feature flags are pub
-            if ch.monitor {
-                if (self.features & GS_CAN_FEATURE_LISTEN_ONLY) == 0 {
-                    return Err(Error::UnsupportedFeature("Monitor"));
-                }
-                flags |= GS_CAN_MODE_LISTEN_ONLY;
-            }
-            if ch.loopback {
-                if (self.features & GS_CAN_FEATURE_LOOP_BACK) == 0 {
-                    return Err(Error::UnsupportedFeature("Loopback"));
-                }
-                flags |= GS_CAN_MODE_LOOP_BACK;
-            }
-            if ch.fd {
-                if !self.supports_fd() {
-                    return Err(Error::UnsupportedFeature("FD"));
-                }
-                flags |= GS_CAN_MODE_FD;
+            if ch.enabled {
+                modes.push((i, self.mode_flags(ch)?));
             }
+        }
 
-            let mode = Mode {
-                mode: CanMode::Start as u32,
-                flags,
-            };
-            if ch.enabled {
-                self.dev.set_mode(i as u16, mode).unwrap();
+        // tell the device to go on bus, taking the channels that were
+        // started off bus again if one fails
+        for (i, flags) in modes {
+            if let Err(e) = self.set_channel_mode(i, CanMode::Start, flags) {
+                self.reset_started_channels();
+                return Err(e);
             }
         }
 
+        // discard any frames left over from a previous run
+        let can_rx = self.dev.lock().unwrap().can_rx_recv();
+        while can_rx.try_recv().is_ok() {}
+
+        let result = self.dev.lock().unwrap().start_transfers();
+        if let Err(e) = result {
+            self.reset_started_channels();
+            return Err(e.into());
+        }
+
         {
             *self.running.write().unwrap() = true;
         }
 
         // rx callback thread
-        let can_rx = self.dev.can_rx_recv.clone();
-        let running = Arc::clone(&self.running);
-        let start_time = time::Instant::now();
-        thread::spawn(move || {
-            while *running.read().unwrap() {
-                match can_rx.recv() {
-                    Ok(hf) => {
-                        let mut f = Frame::from_host_frame(hf);
-                        f.timestamp = Some(time::Instant::now().duration_since(start_time));
-                        rx_callback(f)
-                    }
-                    Err(RecvError) => {
+        let (shutdown_send, shutdown_recv) = bounded::<()>(0);
+        let timestamp_mode = self.timestamp_mode;
+        let waiters = Arc::clone(&self.waiters);
+        if !self.fixed_time_base {
+            self.time_base = TimeBase::now();
+        }
+        let time_base = self.time_base;
+        let mut clock_sync = ClockSync::new();
+        self.rx_thread = Some(thread::spawn(move || {
+            let mut handle = |hf: HostFrame| {
+                let received = time_base.elapsed(time::Instant::now());
+                let device_time = hf.timestamp_us;
+                let mut f = Frame::from_host_frame(hf);
+                f.timestamp = match (timestamp_mode, device_time) {
+                    (TimestampMode::None, _) => None,
+                    (TimestampMode::Wall, _) => Some(time_base.since_epoch(received)),
+                    (TimestampMode::Hardware, Some(t)) => Some(clock_sync.update(t, received)),
+                    _ => Some(received),
+                };
+                if !f.loopback {
+                    waiters.lock().unwrap().offer(&f);
+                }
+                rx_callback(f)
+            };
+            loop {
+                select! {
+                    recv(can_rx) -> msg => match msg {
+                        Ok(hf) => handle(hf),
                         // channel disconnected
+                        Err(_) => break,
+                    },
+                    // interface stopped, frames the device delivered before
+                    // it stopped are still passed to the callback
+                    recv(shutdown_recv) -> _ => {
+                        for hf in can_rx.try_iter() {
+                            handle(hf);
+                        }
                         break;
                     }
                 }
             }
+        }));
+        self.rx_shutdown = Some(shutdown_send);
+
+        let dev = Arc::clone(&self.dev);
+        self.scheduler.start(move |f: Frame| {
+            dev.lock().unwrap().send(f.to_host_frame())?;
+            Ok(())
         });
 
-        self.dev.start_transfers().unwrap();
         Ok(())
     }
 
     /// Stop CAN communication on all channels.
+    ///
+    /// When this returns, the receive thread has exited and the rx callback
+    /// passed to `start` has been dropped.
     pub fn stop(&mut self) -> Result<(), Error> {
-        // TODO multi-channel
-        for (i, ch) in self.channels.iter().enumerate() {
-            let mode = Mode {
-                mode: CanMode::Reset as u32,
-                flags: 0,
-            };
-            if ch.enabled {
-                self.dev.set_mode(i as u16, mode).unwrap();
+        if !*self.running.read().unwrap() {
+            return Err(Error::NotRunning);
+        }
+
+        self.scheduler.stop();
+
+        // keep shutting down on errors, report the first one
+        let mut result = Ok(());
+        for i in 0..self.channels.len() {
+            if self.channels_started[i] {
+                if let Err(e) = self.set_channel_mode(i, CanMode::Reset, 0) {
+                    result = result.and(Err(e));
+                    self.channels_started[i] = false;
+                }
             }
         }
 
-        self.dev.stop_transfers().unwrap();
+        if let Err(e) = self.dev.lock().unwrap().stop_transfers() {
+            result = result.and(Err(e.into()));
+        }
         *self.running.write().unwrap() = false;
+
+        // dropping the shutdown sender wakes the rx thread
+        self.rx_shutdown = None;
+        if let Some(t) = self.rx_thread.take() {
+            let _ = t.join();
+        }
+        result
+    }
+
+    /// Start CAN communication on a single channel of a running interface.
+    ///
+    /// The channel is started using its current configuration. Other channels
+    /// are not affected.
+    pub fn start_channel(&mut self, channel: usize) -> Result<(), Error> {
+        if channel > self.channel_count {
+            return Err(Error::InvalidChannel);
+        }
+        if !*self.running.read().unwrap() {
+            return Err(Error::NotRunning);
+        }
+        if self.channels_started[channel] {
+            return Err(Error::Running);
+        }
+
+        let flags = self.mode_flags(&self.channels[channel])?;
+        self.set_channel_mode(channel, CanMode::Start, flags)?;
+        self.channels[channel].enabled = true;
+        Ok(())
+    }
+
+    /// Stop CAN communication on a single channel of a running interface.
+    ///
+    /// Other channels and the receive thread are not affected.
+    pub fn stop_channel(&mut self, channel: usize) -> Result<(), Error> {
+        if channel > self.channel_count {
+            return Err(Error::InvalidChannel);
+        }
+        if !self.channels_started[channel] {
+            return Err(Error::NotRunning);
+        }
+
+        self.set_channel_mode(channel, CanMode::Reset, 0)?;
+        self.channels[channel].enabled = false;
+        Ok(())
+    }
+
+    /// Apply a new configuration to a single channel.
+    ///
+    /// If the channel is on bus, it is reset, the new bit timing and mode flags
+    /// are applied, and it is started again if the new configuration is enabled.
+    /// Other channels and the receive thread are not affected, so this can be
+    /// used while the interface is running.
+    ///
+    /// If the device rejects the new configuration, the channel is left off
+    /// bus and disabled.
+    pub fn reconfigure(&mut self, channel: usize, config: Channel) -> Result<(), Error> {
+        if channel > self.channel_count {
+            return Err(Error::InvalidChannel);
+        }
+
+        // validate the whole configuration before touching the device
+        let flags = self.mode_flags(&config)?;
+        let bt = calculate_bit_timing(
+            self.can_clock,
+            config.bitrate,
+            config.sample_point,
+            config.sjw,
+        )?;
+        let data_bt = if config.fd {
+            Some(calculate_bit_timing(
+                self.can_clock,
+                config.data_bitrate,
+                None,
+                None,
+            )?)
+        } else {
+            None
+        };
+
+        if self.channels_started[channel] {
+            self.set_channel_mode(channel, CanMode::Reset, 0)?;
+        }
+
+        // the device may have part of the new bit timing, so the channel
+        // can't be started with either configuration
+        let result = self.set_channel_bit_timing(channel, bt, data_bt);
+        if let Err(e) = result {
+            self.channels[channel].enabled = false;
+            return Err(e);
+        }
+
+        let restart = config.enabled && *self.running.read().unwrap();
+        self.channels[channel] = config;
+        if restart {
+            if let Err(e) = self.set_channel_mode(channel, CanMode::Start, flags) {
+                self.channels[channel].enabled = false;
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    fn set_channel_bit_timing(
+        &mut self,
+        channel: usize,
+        bt: BitTiming,
+        data_bt: Option<BitTiming>,
+    ) -> Result<(), Error> {
+        let mut dev = self.dev.lock().unwrap();
+        dev.set_bit_timing(channel as u16, bt)?;
+        if let Some(bt) = data_bt {
+            dev.set_data_bit_timing(channel as u16, bt)?;
+        }
+        Ok(())
+    }
+
+    // takes every started channel off bus, ignoring errors
+    fn reset_started_channels(&mut self) {
+        for i in 0..self.channels.len() {
+            if self.channels_started[i] {
+                let _ = self.set_channel_mode(i, CanMode::Reset, 0);
+            }
+        }
+    }
+
+    // returns the device mode flags for a channel configuration, checking
+    // that each requested mode is supported by the device
+    fn mode_flags(&self, ch: &Channel) -> Result<u32, Error> {
+        let mut flags = 0;
+        // for each mode flag, check that the feature is supported before applying feature
+        // this is necessary since the feature flags are pub
+        if ch.monitor {
+            if (self.features & GS_CAN_FEATURE_LISTEN_ONLY) == 0 {
+                return Err(Error::UnsupportedFeature("Monitor"));
+            }
+            flags |= GS_CAN_MODE_LISTEN_ONLY;
+        }
+        if ch.loopback {
+            if (self.features & GS_CAN_FEATURE_LOOP_BACK) == 0 {
+                return Err(Error::UnsupportedFeature("Loopback"));
+            }
+            flags |= GS_CAN_MODE_LOOP_BACK;
+        }
+        if ch.fd {
+            if !self.supports_fd() {
+                return Err(Error::UnsupportedFeature("FD"));
+            }
+            flags |= GS_CAN_MODE_FD;
+        }
+        if ch.one_shot {
+            if (self.features & GS_CAN_FEATURE_ONE_SHOT) == 0 {
+                return Err(Error::UnsupportedFeature("One-shot"));
+            }
+            flags |= GS_CAN_MODE_ONE_SHOT;
+        }
+        if ch.triple_sample {
+            if (self.features & GS_CAN_FEATURE_TRIPLE_SAMPLE) == 0 {
+                return Err(Error::UnsupportedFeature("Triple sample"));
+            }
+            flags |= GS_CAN_MODE_TRIPLE_SAMPLE;
+        }
+        if self.timestamp_mode == TimestampMode::Hardware {
+            if (self.features & GS_CAN_FEATURE_HW_TIMESTAMP) == 0 {
+                return Err(Error::UnsupportedFeature("Hardware timestamp"));
+            }
+            flags |= GS_CAN_MODE_HW_TIMESTAMP;
+        }
+        Ok(flags)
+    }
+
+    fn set_channel_mode(&mut self, channel: usize, mode: CanMode, flags: u32) -> Result<(), Error> {
+        let started = match mode {
+            CanMode::Start => true,
+            CanMode::Reset => false,
+        };
+        let mode = Mode {
+            mode: mode as u32,
+            flags,
+        };
+        self.dev.lock().unwrap().set_mode(channel as u16, mode)?;
+        self.channels_started[channel] = started;
         Ok(())
     }
 
@@ -377,10 +777,12 @@ impl Interface {
             return Err(Error::InvalidChannel);
         }
 
-        let bt = calculate_bit_timing(self.can_clock, bitrate)?;
+        let ch = &self.channels[channel];
+        let bt = calculate_bit_timing(self.can_clock, bitrate, ch.sample_point, ch.sjw)?;
         self.dev
-            .set_bit_timing(channel as u16, bt)
-            .expect("failed to set bit timing");
+            .lock()
+            .unwrap()
+            .set_bit_timing(channel as u16, bt)?;
 
         self.channels[channel].bitrate = bitrate;
         Ok(())
@@ -396,10 +798,11 @@ impl Interface {
             return Err(Error::InvalidChannel);
         }
 
-        let bt = calculate_bit_timing(self.can_clock, bitrate)?;
+        let bt = calculate_bit_timing(self.can_clock, bitrate, None, None)?;
         self.dev
-            .set_data_bit_timing(channel as u16, bt)
-            .expect("failed to set bit timing");
+            .lock()
+            .unwrap()
+            .set_data_bit_timing(channel as u16, bt)?;
 
         self.channels[channel].data_bitrate = bitrate;
         Ok(())
@@ -422,8 +825,9 @@ impl Interface {
             sjw,
         };
         self.dev
-            .set_bit_timing(channel as u16, bt)
-            .expect("failed to set bit timing");
+            .lock()
+            .unwrap()
+            .set_bit_timing(channel as u16, bt)?;
         Ok(())
     }
 
@@ -494,28 +898,137 @@ impl Interface {
         Ok(())
     }
 
+    /// Set the source of timestamps for received frames. Takes effect the next
+    /// time the interface is started.
+    pub fn set_timestamp_mode(&mut self, mode: TimestampMode) {
+        self.timestamp_mode = mode;
+    }
+
+    /// Measure timestamps from `base` instead of from the time the interface
+    /// is started. Interfaces sharing a time base have timestamps that can be
+    /// compared directly. Takes effect the next time the interface is started.
+    pub fn set_time_base(&mut self, base: TimeBase) {
+        self.time_base = base;
+        self.fixed_time_base = true;
+    }
+
+    /// Returns the time base that timestamps are measured from. Unless one
+    /// was set with `set_time_base`, a new time base is taken each time the
+    /// interface is started.
+    pub fn time_base(&self) -> TimeBase {
+        self.time_base
+    }
+
     /// Returns true if device suports CAN-FD operation, false otherwise.
     pub fn supports_fd(&self) -> bool {
         (self.features & GS_CAN_FEATURE_FD) > 0
     }
 
     /// Send a CAN frame using the device
-    pub fn send(&mut self, f: Frame) -> Result<(), Error> {
+    pub fn send(&self, f: Frame) -> Result<(), Error> {
         if !*self.running.read().unwrap() {
             return Err(Error::NotRunning);
         }
 
-        self.dev.send(f.to_host_frame()).unwrap();
+        self.dev.lock().unwrap().send(f.to_host_frame())?;
         Ok(())
     }
 
+    /// Send a CAN frame and wait for a response.
+    ///
+    /// Returns the first frame received after sending for which `matcher`
+    /// returns true, or `Error::Timeout` if no such frame is received within
+    /// `timeout`. Frames sent by this device are never matched.
+    ///
+    /// This can be called concurrently from multiple threads. Each received
+    /// frame is returned to at most one caller, the one that started waiting
+    /// first. Received frames are still passed to the rx callback.
+    pub fn transact(
+        &self,
+        f: Frame,
+        matcher: impl Fn(&Frame) -> bool + Send + 'static,
+        timeout: time::Duration,
+    ) -> Result<Frame, Error> {
+        let (send, recv) = bounded(1);
+
+        // start waiting before sending, so a fast response can't be missed
+        let id = {
+            let mut waiters = self.waiters.lock().unwrap();
+            waiters.next_id += 1;
+            let id = waiters.next_id;
+            waiters.list.push(Waiter {
+                id,
+                matcher: Box::new(matcher),
+                send,
+            });
+            id
+        };
+
+        let result = match self.send(f) {
+            Ok(()) => recv.recv_timeout(timeout).map_err(|_| Error::Timeout),
+            Err(e) => Err(e),
+        };
+
+        // the waiter is already gone if it received a frame
+        self.waiters.lock().unwrap().list.retain(|w| w.id != id);
+        result
+    }
+
+    /// Returns the scheduler used to send frames periodically. Jobs are sent
+    /// while the interface is running.
+    pub fn scheduler(&self) -> &Scheduler {
+        &self.scheduler
+    }
+
     /// Returns the number of channels this Interface has
     pub fn channels(&self) -> usize {
         self.channel_count + 1
     }
+
+    /// Returns the device's firmware version.
+    pub fn sw_version(&self) -> u32 {
+        self.sw_version
+    }
+
+    /// Returns the device's hardware version.
+    pub fn hw_version(&self) -> u32 {
+        self.hw_version
+    }
+
+    /// Returns the frequency of the device's CAN clock, in Hz.
+    pub fn can_clock(&self) -> u32 {
+        self.can_clock
+    }
+
+    /// Returns the device's feature bits (`GS_CAN_FEATURE_*`). Use
+    /// `feature_names` to describe them.
+    pub fn features(&self) -> u32 {
+        self.features
+    }
+
+    /// Returns the device's limits on bit timing parameters.
+    pub fn bit_timing_limits(&self) -> BitTimingLimits {
+        self.bit_timing_limits
+    }
+}
+
+impl Drop for Interface {
+    fn drop(&mut self) {
+        if *self.running.read().unwrap() {
+            let _ = self.stop();
+        }
+    }
 }
 
-fn calculate_bit_timing(clk: u32, bitrate: u32) -> Result<BitTiming, Error> {
+// find a bit timing for a bitrate, with the sample point closest to
+// `sample_point` (a fraction of the bit time) if given, or the earliest
+// sample point otherwise
+fn calculate_bit_timing(
+    clk: u32,
+    bitrate: u32,
+    sample_point: Option<f32>,
+    sjw: Option<u32>,
+) -> Result<BitTiming, Error> {
     let max_brp = 32;
     let min_seg1 = 3;
     let max_seg1 = 18;
@@ -523,7 +1036,24 @@ fn calculate_bit_timing(clk: u32, bitrate: u32) -> Result<BitTiming, Error> {
     let max_seg2 = 8;
     let tolerances = vec![0.0, 0.1 / 100.0, 0.5 / 100.0];
 
+    if let Some(sp) = sample_point {
+        if !(sp > 0.0 && sp < 1.0) {
+            return Err(Error::InvalidBitTiming(format!(
+                "sample point {} is not between 0 and 1",
+                sp
+            )));
+        }
+    }
+    let sjw = sjw.unwrap_or(1);
+    if sjw == 0 {
+        return Err(Error::InvalidBitTiming(String::from(
+            "SJW must be at least 1",
+        )));
+    }
+
     for tolerance in tolerances {
+        // (distance from the requested sample point, brp, seg1, seg2)
+        let mut best: Option<(f32, u32, u32, u32)> = None;
         let tmp = clk as f32 / bitrate as f32;
         for brp in 1..(max_brp + 1) {
             let btq = tmp / brp as f32;
@@ -539,20 +1069,39 @@ fn calculate_bit_timing(clk: u32, bitrate: u32) -> Result<BitTiming, Error> {
 
             for seg1 in min_seg1..max_seg1 {
                 // subtract 1 from seg2 to account for propagation phase
-                let seg2 = btq_rounded - seg1 - 1;
-                if seg2 < min_seg2 || seg2 > max_seg2 {
+                let seg2 = match btq_rounded.checked_sub(seg1 + 1) {
+                    Some(s) if (min_seg2..=max_seg2).contains(&s) => s,
                     // invalid seg2 value
-                    continue;
-                }
+                    _ => continue,
+                };
                 // brp, seg1, and seg2 are all valid
-                return Ok(BitTiming {
-                    brp,
-                    prop_seg: 0,
-                    phase_seg1: seg1,
-                    phase_seg2: seg2,
-                    sjw: 1,
-                });
+                let distance = match sample_point {
+                    Some(sp) => ((1 + seg1) as f32 / btq_rounded as f32 - sp).abs(),
+                    None => 0.0,
+                };
+                if best.is_none_or(|(d, ..)| distance < d) {
+                    best = Some((distance, brp, seg1, seg2));
+                }
+            }
+            if sample_point.is_none() && best.is_some() {
+                break;
+            }
+        }
+
+        if let Some((_, brp, seg1, seg2)) = best {
+            if sjw > seg2 {
+                return Err(Error::InvalidBitTiming(format!(
+                    "SJW {} is longer than phase segment 2 ({} time quanta)",
+                    sjw, seg2
+                )));
             }
+            return Ok(BitTiming {
+                brp,
+                prop_seg: 0,
+                phase_seg1: seg1,
+                phase_seg2: seg2,
+                sjw,
+            });
         }
     }
     Err(Error::InvalidBitrate(bitrate))
@@ -566,12 +1115,172 @@ fn effective_bitrate(clk: u32, bt: BitTiming) -> u32 {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use device::virt::{Fault, VirtualDevice};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn virtual_interface() -> Interface {
+        let mut i = Interface::from_backend(Box::new(VirtualDevice::new(2))).unwrap();
+        for n in 0..i.channels() {
+            i.set_bitrate(n, 500_000).unwrap();
+        }
+        i
+    }
+
+    #[test]
+    fn test_start_stop_cycles() {
+        let mut i = virtual_interface();
+        let received = Arc::new(AtomicUsize::new(0));
+
+        for n in 0..5000 {
+            let r = Arc::clone(&received);
+            i.start(move |_| {
+                r.fetch_add(1, Ordering::SeqCst);
+            })
+            .unwrap();
+            assert!(matches!(i.start(|_| {}), Err(Error::Running)));
+
+            let f = Frame {
+                can_id: n % 0x800,
+                can_dlc: 8,
+                channel: (n % 2) as u8,
+                ..Frame::default()
+            };
+            i.send(f).unwrap();
+            i.stop().unwrap();
+
+            // the rx thread has exited and dropped its callback
+            assert_eq!(Arc::strong_count(&received), 1);
+            assert!(i.channels_started.iter().all(|s| !s));
+        }
+        assert!(matches!(i.stop(), Err(Error::NotRunning)));
+        // every echoed frame is delivered before stop returns
+        assert_eq!(received.load(Ordering::SeqCst), 5000);
+    }
+
+    // frame as received from another node on the bus
+    fn bus_frame(can_id: u32, data: &[u8]) -> HostFrame {
+        let mut f = Frame {
+            can_id,
+            can_dlc: data.len() as u8,
+            ..Frame::default()
+        };
+        f.data[..data.len()].copy_from_slice(data);
+        let mut hf = f.to_host_frame();
+        hf.echo_id = GSUSB_RX_ECHO_ID;
+        hf
+    }
+
+    #[test]
+    fn test_transact() {
+        let dev = VirtualDevice::new(2);
+        let bus = dev.bus();
+        let mut i = Interface::from_backend(Box::new(dev)).unwrap();
+        i.set_bitrate(0, 500_000).unwrap();
+        i.start(|_| {}).unwrap();
+        let i = Arc::new(i);
+
+        // nothing responds to this request
+        let timeout = time::Duration::from_millis(10);
+        let request = Frame {
+            can_id: 0x7DF,
+            can_dlc: 8,
+            ..Frame::default()
+        };
+        let result = i.transact(request, |f| f.can_id == 0x7E8, timeout);
+        assert!(matches!(result, Err(Error::Timeout)));
+
+        // concurrent requests with different matchers
+        let threads: Vec<_> = (0..4)
+            .map(|n| {
+                let i = Arc::clone(&i);
+                thread::spawn(move || {
+                    let request = Frame {
+                        can_id: 0x7E0 + n,
+                        can_dlc: 8,
+                        ..Frame::default()
+                    };
+                    let matcher = move |f: &Frame| f.can_id == 0x7E8 + n && f.data[0] == 0x50;
+                    i.transact(request, matcher, time::Duration::from_secs(5))
+                })
+            })
+            .collect();
+        while i.waiters.lock().unwrap().list.len() < 4 {
+            thread::yield_now();
+        }
+        bus.send(bus_frame(0x7E8, &[0x7F])).unwrap();
+        for n in (0..4).rev() {
+            bus.send(bus_frame(0x7E8 + n, &[0x50, n as u8])).unwrap();
+        }
+
+        for (n, t) in threads.into_iter().enumerate() {
+            let f = t.join().unwrap().unwrap();
+            assert_eq!(f.can_id, 0x7E8 + n as u32);
+            assert_eq!(f.data[..2], [0x50, n as u8]);
+        }
+        assert!(i.waiters.lock().unwrap().list.is_empty());
+    }
+
+    #[test]
+    fn test_drop_running_interface() {
+        let received = Arc::new(AtomicUsize::new(0));
+        let r = Arc::clone(&received);
+
+        let mut i = virtual_interface();
+        i.start(move |_| {
+            r.fetch_add(1, Ordering::SeqCst);
+        })
+        .unwrap();
+        drop(i);
+
+        assert_eq!(Arc::strong_count(&received), 1);
+    }
+    #[test]
+    fn test_timestamp_modes() {
+        let base = TimeBase::now();
+        let mut host = virtual_interface();
+        host.set_time_base(base);
+        let mut hw = virtual_interface();
+        hw.set_time_base(base);
+        hw.set_timestamp_mode(TimestampMode::Hardware);
+        let mut wall = virtual_interface();
+        wall.set_timestamp_mode(TimestampMode::Wall);
+
+        let mut received = Vec::new();
+        for i in [&mut host, &mut hw, &mut wall].iter_mut() {
+            let (send, recv) = crossbeam_channel::unbounded();
+            i.start(move |f| send.send(f).unwrap()).unwrap();
+            received.push(recv);
+        }
+        thread::sleep(time::Duration::from_millis(20));
+        let sent = base.elapsed(time::Instant::now());
+        for i in [&host, &hw, &wall].iter() {
+            i.send(Frame::default()).unwrap();
+        }
+        let timestamps: Vec<time::Duration> = received
+            .iter()
+            .map(|r| r.recv().unwrap().timestamp.unwrap())
+            .collect();
+        for i in [&mut host, &mut hw, &mut wall].iter_mut() {
+            i.stop().unwrap();
+        }
+
+        // timestamps from interfaces sharing a time base can be compared
+        assert_eq!(host.time_base(), hw.time_base());
+        for t in &timestamps[..2] {
+            assert!(*t >= sent && *t - sent < time::Duration::from_millis(100));
+        }
+        let now = time::SystemTime::now()
+            .duration_since(time::UNIX_EPOCH)
+            .unwrap();
+        assert!(timestamps[2] <= now && now - timestamps[2] < time::Duration::from_secs(1));
+    }
+
     #[test]
     fn test_bit_timing() {
         let clk = 24000000;
         let bitrates = vec![1000000, 500000, 250000, 125000, 33333];
         for b in bitrates {
-            let bt = calculate_bit_timing(clk, b).unwrap();
+            let bt = calculate_bit_timing(clk, b, None, None).unwrap();
 
             // ensure error < 0.5%
             println!("{:?}", &bt);
@@ -580,4 +1289,82 @@ mod tests {
             assert!(err < 0.5);
         }
     }
+
+    #[test]
+    fn test_sample_point_and_sjw() {
+        let clk = 48_000_000;
+        let bt = calculate_bit_timing(clk, 500_000, Some(0.875), Some(2)).unwrap();
+        let tq = 1 + bt.phase_seg1 + bt.phase_seg2;
+        assert_eq!((1 + bt.phase_seg1) as f32 / tq as f32, 0.875);
+        assert_eq!(bt.sjw, 2);
+
+        assert!(matches!(
+            calculate_bit_timing(clk, 500_000, Some(1.5), None),
+            Err(Error::InvalidBitTiming(_))
+        ));
+        assert!(matches!(
+            calculate_bit_timing(clk, 500_000, Some(0.875), Some(8)),
+            Err(Error::InvalidBitTiming(_))
+        ));
+    }
+
+    #[test]
+    fn test_device_info() {
+        let i = virtual_interface();
+        assert_eq!(i.can_clock(), 48_000_000);
+        assert_eq!(i.channels(), 2);
+        assert!(feature_names(i.features()).contains(&"fd"));
+        let limits = i.bit_timing_limits();
+        assert_eq!((limits.tseg1_max, limits.brp_max), (16, 1024));
+    }
+
+    #[test]
+    fn test_start_failure() {
+        let dev = VirtualDevice::new(2);
+        let faults = dev.faults();
+        let mut i = Interface::from_backend(Box::new(dev)).unwrap();
+        for n in 0..i.channels() {
+            i.set_bitrate(n, 500_000).unwrap();
+        }
+
+        // channel 0 is taken off bus again when channel 1 fails to start
+        faults.lock().unwrap().insert(Fault::Start(1));
+        assert!(i.start(|_| {}).is_err());
+        assert_eq!(i.channels_started, vec![false, false]);
+        assert!(i.send(Frame::default()).is_err());
+
+        faults.lock().unwrap().clear();
+        i.start(|_| {}).unwrap();
+        assert_eq!(i.channels_started, vec![true, true]);
+        i.stop().unwrap();
+    }
+
+    #[test]
+    fn test_reconfigure_failure() {
+        let dev = VirtualDevice::new(2);
+        let faults = dev.faults();
+        let mut i = Interface::from_backend(Box::new(dev)).unwrap();
+        for n in 0..i.channels() {
+            i.set_bitrate(n, 500_000).unwrap();
+        }
+        i.start(|_| {}).unwrap();
+
+        // a channel whose bit timing can't be set is left off bus and
+        // disabled, and the other channel is not affected
+        faults.lock().unwrap().insert(Fault::BitTiming(0));
+        let config = Channel {
+            bitrate: 250_000,
+            ..i.channels[0].clone()
+        };
+        assert!(i.reconfigure(0, config.clone()).is_err());
+        assert!(!i.channels[0].enabled);
+        assert_eq!(i.channels_started, vec![false, true]);
+
+        faults.lock().unwrap().clear();
+        i.reconfigure(0, config).unwrap();
+        assert!(i.channels[0].enabled);
+        assert_eq!(i.channels[0].bitrate, 250_000);
+        assert_eq!(i.channels_started, vec![true, true]);
+        i.stop().unwrap();
+    }
 }
@@ -125,34 +125,75 @@ impl PyInterface {
         dlc: u8,
         data: Vec<u8>,
     ) -> PyResult<()> {
-        let mut data_array: Vec<u8> = vec![0; 64];
-        for i in 0..dlc as usize {
-            if i < dlc.into() {
-                data_array[i] = data[i];
-            }
-        }
-        self.i.send(Frame {
-            can_id: id,
-            can_dlc: dlc,
-            ext,
-            rtr,
-            data: data_array,
-            channel,
-            loopback: false,
-            fd: false,
-            brs: false,
-            err: false,
-            esi: false,
-            timestamp: None,
-        })?;
+        self.i
+            .send(frame_from_args(channel, id, ext, rtr, dlc, data))?;
         Ok(())
     }
 
+    /// Send a frame and wait for a response with the given ID, received on the
+    /// same channel and with the same `ext` flag as the frame sent. If
+    /// `response_data` is given, the response data must start with these bytes.
+    /// Returns None if no response is received within the timeout.
+    #[allow(clippy::too_many_arguments)]
+    #[args(response_data = "None")]
+    fn transact(
+        &self,
+        py: Python,
+        channel: u8,
+        id: u32,
+        ext: bool,
+        rtr: bool,
+        dlc: u8,
+        data: Vec<u8>,
+        response_id: u32,
+        timeout_ms: u64,
+        response_data: Option<Vec<u8>>,
+    ) -> PyResult<Option<Frame>> {
+        let f = frame_from_args(channel, id, ext, rtr, dlc, data);
+        let prefix = response_data.unwrap_or_default();
+        let matcher = move |r: &Frame| {
+            let len = r.data_len().min(r.data.len());
+            r.channel == channel
+                && r.ext == ext
+                && r.can_id == response_id
+                && r.data[..len].starts_with(&prefix)
+        };
+        let timeout = std::time::Duration::from_millis(timeout_ms);
+
+        // release the GIL so other Python threads can run while waiting
+        let i = &self.i;
+        match py.allow_threads(|| i.transact(f, matcher, timeout)) {
+            Ok(f) => Ok(Some(f)),
+            Err(Error::Timeout) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
     fn channel_count(&self) -> PyResult<usize> {
         Ok(self.i.channels())
     }
 }
 
+fn frame_from_args(channel: u8, id: u32, ext: bool, rtr: bool, dlc: u8, data: Vec<u8>) -> Frame {
+    let mut data_array: Vec<u8> = vec![0; 64];
+    let len = std::cmp::min(dlc as usize, data.len());
+    data_array[..len].copy_from_slice(&data[..len]);
+    Frame {
+        can_id: id,
+        can_dlc: dlc,
+        ext,
+        rtr,
+        data: data_array,
+        channel,
+        loopback: false,
+        fd: false,
+        brs: false,
+        err: false,
+        esi: false,
+        timestamp: None,
+    }
+}
+
 #[pymodule]
 fn cantact(_py: Python, m: &PyModule) -> PyResult<()> {
     m.add_class::<PyInterface>()?;
@@ -0,0 +1,472 @@
+//! Periodic transmission of frames.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::{Error, Frame};
+
+// modifies a job's frame before each transmission
+type UpdateFn = Box<dyn FnMut(&mut Frame) + Send>;
+
+/// A frame to be sent periodically by a `Scheduler`.
+pub struct Job {
+    frame: Frame,
+    period: Duration,
+    offset: Duration,
+    update: Option<UpdateFn>,
+}
+
+impl Job {
+    /// Create a job sending `frame` every `period`.
+    pub fn new(frame: Frame, period: Duration) -> Job {
+        Job {
+            frame,
+            period,
+            offset: Duration::from_secs(0),
+            update: None,
+        }
+    }
+
+    /// Delay the first transmission by `offset` after the scheduler starts,
+    /// or after the job is added to a running scheduler. Used to spread the
+    /// load of jobs with the same period.
+    pub fn offset(mut self, offset: Duration) -> Job {
+        self.offset = offset;
+        self
+    }
+
+    /// Call `update` before each transmission to modify the frame, for
+    /// example to increment a counter or compute a checksum.
+    pub fn update(mut self, update: impl FnMut(&mut Frame) + Send + 'static) -> Job {
+        self.update = Some(Box::new(update));
+        self
+    }
+}
+
+/// Handle identifying a job in a `Scheduler`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct JobId(u64);
+
+/// Timing statistics for a job.
+///
+/// Delay is the time between a transmission's deadline and the frame being
+/// handed to the device.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct JobStats {
+    /// Number of frames sent.
+    pub sent: u64,
+    /// Number of frames the device failed to send.
+    pub failed: u64,
+    /// Number of transmissions skipped because the scheduler fell more than
+    /// a period behind.
+    pub skipped: u64,
+    /// Smallest delay of a transmission.
+    pub min_delay: Duration,
+    /// Largest delay of a transmission.
+    pub max_delay: Duration,
+    /// Mean delay of all transmissions.
+    pub mean_delay: Duration,
+}
+
+struct Entry {
+    job: Job,
+    // None until the scheduler is running
+    deadline: Option<Instant>,
+    // incremented each time the frame is replaced with `set_frame`
+    version: u64,
+    stats: JobStats,
+    total_delay: Duration,
+}
+impl Entry {
+    fn record(&mut self, delay: Duration, result: Result<(), Error>) {
+        match result {
+            Ok(()) => self.stats.sent += 1,
+            Err(_) => self.stats.failed += 1,
+        }
+        let count = self.stats.sent + self.stats.failed;
+        if count == 1 || delay < self.stats.min_delay {
+            self.stats.min_delay = delay;
+        }
+        if delay > self.stats.max_delay {
+            self.stats.max_delay = delay;
+        }
+        self.total_delay += delay;
+        self.stats.mean_delay = self.total_delay / count as u32;
+    }
+}
+
+#[derive(Default)]
+struct State {
+    running: bool,
+    next_id: u64,
+    jobs: HashMap<JobId, Entry>,
+}
+
+type Shared = Arc<(Mutex<State>, Condvar)>;
+
+/// Sends frames periodically using absolute deadlines, so timing does not
+/// drift as jobs run.
+///
+/// Each `Interface` owns a scheduler, which runs while the interface is
+/// started. Jobs can be added, modified, and removed at any time.
+#[derive(Default)]
+pub struct Scheduler {
+    shared: Shared,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl Scheduler {
+    /// Add a job, returning its handle. Returns `Error::InvalidPeriod` if the
+    /// job's period is zero.
+    pub fn add(&self, job: Job) -> Result<JobId, Error> {
+        if job.period == Duration::from_secs(0) {
+            return Err(Error::InvalidPeriod);
+        }
+        let (lock, cvar) = &*self.shared;
+        let mut state = lock.lock().unwrap();
+        state.next_id += 1;
+        let id = JobId(state.next_id);
+        let deadline = if state.running {
+            Some(Instant::now() + job.offset)
+        } else {
+            None
+        };
+        state.jobs.insert(
+            id,
+            Entry {
+                job,
+                deadline,
+                version: 0,
+                stats: JobStats::default(),
+                total_delay: Duration::from_secs(0),
+            },
+        );
+        cvar.notify_one();
+        Ok(id)
+    }
+
+    /// Remove a job. Returns false if it did not exist.
+    pub fn remove(&self, id: JobId) -> bool {
+        let (lock, cvar) = &*self.shared;
+        let removed = lock.lock().unwrap().jobs.remove(&id).is_some();
+        cvar.notify_one();
+        removed
+    }
+
+    /// Change the frame sent by a job. Returns false if the job does not exist.
+    pub fn set_frame(&self, id: JobId, frame: Frame) -> bool {
+        self.modify(id, |e| {
+            e.job.frame = frame;
+            e.version += 1;
+        })
+    }
+
+    /// Change the period of a job. The next transmission is one new period
+    /// after the previous one. Returns false if the job does not exist, and
+    /// `Error::InvalidPeriod` if the period is zero.
+    pub fn set_period(&self, id: JobId, period: Duration) -> Result<bool, Error> {
+        if period == Duration::from_secs(0) {
+            return Err(Error::InvalidPeriod);
+        }
+        Ok(self.modify(id, |e| {
+            if let Some(deadline) = e.deadline {
+                // the previous transmission can't be represented if it was
+                // before the origin of the monotonic clock
+                let previous = deadline
+                    .checked_sub(e.job.period)
+                    .unwrap_or_else(Instant::now);
+                e.deadline = Some(previous + period);
+            }
+            e.job.period = period;
+        }))
+    }
+
+    /// Returns timing statistics for a job, or None if it does not exist.
+    pub fn stats(&self, id: JobId) -> Option<JobStats> {
+        let (lock, _) = &*self.shared;
+        lock.lock().unwrap().jobs.get(&id).map(|e| e.stats.clone())
+    }
+
+    /// Returns the handles of all jobs.
+    pub fn jobs(&self) -> Vec<JobId> {
+        let (lock, _) = &*self.shared;
+        lock.lock().unwrap().jobs.keys().copied().collect()
+    }
+
+    fn modify(&self, id: JobId, f: impl FnOnce(&mut Entry)) -> bool {
+        let (lock, cvar) = &*self.shared;
+        let found = match lock.lock().unwrap().jobs.get_mut(&id) {
+            Some(e) => {
+                f(e);
+                true
+            }
+            None => false,
+        };
+        cvar.notify_one();
+        found
+    }
+
+    // start sending jobs using `send`
+    pub(crate) fn start(&mut self, send: impl FnMut(Frame) -> Result<(), Error> + Send + 'static) {
+        let (lock, _) = &*self.shared;
+        {
+            let mut state = lock.lock().unwrap();
+            state.running = true;
+            let now = Instant::now();
+            for e in state.jobs.values_mut() {
+                e.deadline = Some(now + e.job.offset);
+            }
+        }
+
+        let shared = Arc::clone(&self.shared);
+        self.thread = Some(thread::spawn(move || run(shared, send)));
+    }
+
+    // stop sending jobs, returning when the scheduler thread has exited
+    pub(crate) fn stop(&mut self) {
+        {
+            let (lock, cvar) = &*self.shared;
+            lock.lock().unwrap().running = false;
+            cvar.notify_one();
+        }
+        if let Some(t) = self.thread.take() {
+            let _ = t.join();
+        }
+    }
+}
+
+impl Drop for Scheduler {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+// a transmission taken from a job to be sent without holding the lock
+struct Due {
+    id: JobId,
+    frame: Frame,
+    version: u64,
+    update: Option<UpdateFn>,
+    delay: Duration,
+}
+
+fn run(shared: Shared, mut send: impl FnMut(Frame) -> Result<(), Error>) {
+    let (lock, cvar) = &*shared;
+    let mut state = lock.lock().unwrap();
+    while state.running {
+        let now = Instant::now();
+        let mut due = Vec::new();
+        for (id, e) in state.jobs.iter_mut() {
+            let deadline = match e.deadline {
+                Some(d) if d <= now => d,
+                _ => continue,
+            };
+
+            // deadlines are absolute, so delays don't accumulate
+            // if more than a period late, skip the missed transmissions
+            let mut next = deadline + e.job.period;
+            while next <= now {
+                next += e.job.period;
+                e.stats.skipped += 1;
+            }
+            e.deadline = Some(next);
+
+            due.push(Due {
+                id: *id,
+                frame: e.job.frame.clone(),
+                version: e.version,
+                update: e.job.update.take(),
+                delay: now - deadline,
+            });
+        }
+
+        if !due.is_empty() {
+            // frames are updated and sent without the lock, so jobs can be
+            // changed while the device is busy, and update closures can use
+            // the scheduler
+            drop(state);
+            let mut results = Vec::new();
+            for mut d in due {
+                if let Some(update) = d.update.as_mut() {
+                    update(&mut d.frame);
+                }
+                let result = send(d.frame.clone());
+                results.push((d, result));
+            }
+
+            state = lock.lock().unwrap();
+            for (d, result) in results {
+                // the job may have been removed while it was sent
+                if let Some(e) = state.jobs.get_mut(&d.id) {
+                    // keep changes made by the update closure, unless the
+                    // frame was replaced meanwhile
+                    if e.version == d.version {
+                        e.job.frame = d.frame;
+                    }
+                    e.job.update = d.update;
+                    e.record(d.delay, result);
+                }
+            }
+            continue;
+        }
+
+        // sleep until the next deadline, or a job is changed
+        let next = state.jobs.values().filter_map(|e| e.deadline).min();
+        state = match next {
+            Some(next) => {
+                let timeout = next.saturating_duration_since(Instant::now());
+                cvar.wait_timeout(state, timeout).unwrap().0
+            }
+            None => cvar.wait(state).unwrap(),
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(can_id: u32) -> Frame {
+        Frame {
+            can_id,
+            can_dlc: 1,
+            ..Frame::default()
+        }
+    }
+
+    #[test]
+    fn test_periodic_jobs() {
+        let sent = Arc::new(Mutex::new(Vec::new()));
+        let s = Arc::clone(&sent);
+
+        let mut scheduler = Scheduler::default();
+        let fast = scheduler.add(Job::new(frame(0x100), Duration::from_millis(10)).update(
+            |f: &mut Frame| {
+                f.data[0] = f.data[0].wrapping_add(1);
+            },
+        ));
+        let slow = scheduler.add(
+            Job::new(frame(0x200), Duration::from_millis(50)).offset(Duration::from_millis(5)),
+        );
+        let (fast, slow) = (fast.unwrap(), slow.unwrap());
+
+        let start = Instant::now();
+        scheduler.start(move |f| {
+            s.lock()
+                .unwrap()
+                .push((f.can_id, f.data[0], Instant::now()));
+            Ok(())
+        });
+        thread::sleep(Duration::from_millis(200));
+        assert!(scheduler.remove(slow));
+        assert!(!scheduler.remove(slow));
+        let removed = Instant::now();
+        thread::sleep(Duration::from_millis(100));
+        scheduler.stop();
+        let elapsed = start.elapsed();
+
+        let sent = sent.lock().unwrap();
+        let fast_frames: Vec<_> = sent.iter().filter(|(id, _, _)| *id == 0x100).collect();
+        let slow_frames: Vec<_> = sent.iter().filter(|(id, _, _)| *id == 0x200).collect();
+
+        // drift-free scheduling never sends more than one frame per elapsed
+        // period, however late the scheduler thread runs. How many are sent
+        // depends on the load of the machine, so only lower bounds are checked.
+        let expected = (elapsed.as_millis() / 10) as usize + 1;
+        assert!(fast_frames.len() >= 4 && fast_frames.len() <= expected);
+        assert!(!slow_frames.is_empty());
+        // at most a transmission already in progress ends after removal
+        assert!(slow_frames.iter().filter(|(_, _, t)| *t > removed).count() <= 1);
+        // frames of each job are sent in order
+        assert!(fast_frames.windows(2).all(|w| w[0].2 <= w[1].2));
+
+        // the update closure ran before each transmission
+        for (n, (_, counter, _)) in fast_frames.iter().enumerate() {
+            assert_eq!(*counter as usize, n + 1);
+        }
+
+        let stats = scheduler.stats(fast).unwrap();
+        assert_eq!(stats.sent as usize, fast_frames.len());
+        assert!(stats.min_delay <= stats.mean_delay && stats.mean_delay <= stats.max_delay);
+    }
+
+    #[test]
+    fn test_send_without_lock() {
+        let (gate_send, gate_recv) = crossbeam_channel::bounded::<()>(0);
+        let mut scheduler = Scheduler::default();
+        let id = scheduler
+            .add(Job::new(frame(0x100), Duration::from_millis(10)))
+            .unwrap();
+        // the first transmission blocks until the gate is closed
+        scheduler.start(move |_| {
+            let _ = gate_recv.recv_timeout(Duration::from_secs(5));
+            Ok(())
+        });
+        thread::sleep(Duration::from_millis(20));
+
+        let start = Instant::now();
+        assert!(scheduler.stats(id).is_some());
+        scheduler
+            .add(Job::new(frame(0x200), Duration::from_millis(10)))
+            .unwrap();
+        assert!(scheduler.set_frame(id, frame(0x101)));
+        assert!(start.elapsed() < Duration::from_secs(1));
+
+        drop(gate_send);
+        scheduler.stop();
+        // the frame set while sending was not overwritten
+        let (lock, _) = &*scheduler.shared;
+        assert_eq!(lock.lock().unwrap().jobs[&id].job.frame.can_id, 0x101);
+    }
+
+    #[test]
+    fn test_modify_job() {
+        let sent = Arc::new(Mutex::new(Vec::new()));
+        let s = Arc::clone(&sent);
+
+        let mut scheduler = Scheduler::default();
+        let id = scheduler
+            .add(Job::new(frame(0x100), Duration::from_millis(10)))
+            .unwrap();
+        assert!(matches!(
+            scheduler.set_period(id, Duration::from_secs(0)),
+            Err(Error::InvalidPeriod)
+        ));
+        assert!(matches!(
+            scheduler.add(Job::new(frame(0x100), Duration::from_secs(0))),
+            Err(Error::InvalidPeriod)
+        ));
+        scheduler.start(move |f| {
+            s.lock().unwrap().push(f.can_id);
+            Ok(())
+        });
+        thread::sleep(Duration::from_millis(55));
+        assert!(scheduler.set_period(id, Duration::from_secs(10)).unwrap());
+        let before = sent.lock().unwrap().len();
+        assert!(scheduler.set_frame(id, frame(0x101)));
+        thread::sleep(Duration::from_millis(55));
+        scheduler.stop();
+
+        let sent = sent.lock().unwrap();
+        // no transmissions after the period was increased, other than one
+        // that was already in progress
+        assert!(!sent.contains(&0x101));
+        assert!(!sent.is_empty() && sent.len() <= before + 1);
+    }
+
+    #[test]
+    fn test_shorten_long_period() {
+        // the previous transmission of a job with a very long period is
+        // before the origin of the monotonic clock
+        let mut scheduler = Scheduler::default();
+        let long = Duration::MAX;
+        let id = scheduler
+            .add(Job::new(frame(0x100), long).offset(Duration::from_secs(3600)))
+            .unwrap();
+        scheduler.start(|_| Ok(()));
+        assert!(scheduler.set_period(id, Duration::from_secs(10)).unwrap());
+        scheduler.stop();
+    }
+}
@@ -0,0 +1,255 @@
+//! Time references for frame timestamps.
+
+use std::collections::VecDeque;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// device timestamps are a 32 bit microsecond counter
+const COUNTER_PERIOD_US: f64 = 4_294_967_296.0;
+// one sample is kept for each interval of device time
+const SAMPLE_INTERVAL_US: i64 = 250_000;
+// number of sample intervals used to fit the clock
+const SAMPLE_WINDOW: usize = 64;
+// device time the samples must span before drift is estimated
+const MIN_DRIFT_SPAN_US: f64 = 1_000_000.0;
+// largest drift accepted between the device and host clocks
+const MAX_DRIFT: f64 = 0.001;
+
+/// A reference point for timestamps, pairing a monotonic host instant with
+/// the wall-clock time it was taken at.
+///
+/// Interfaces given the same time base produce timestamps that can be
+/// compared directly, so frames from several devices can be merged in order.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TimeBase {
+    instant: Instant,
+    system: SystemTime,
+}
+
+impl Default for TimeBase {
+    fn default() -> TimeBase {
+        TimeBase::now()
+    }
+}
+
+impl TimeBase {
+    /// Returns a time base starting now.
+    pub fn now() -> TimeBase {
+        TimeBase {
+            instant: Instant::now(),
+            system: SystemTime::now(),
+        }
+    }
+
+    /// Returns the host instant the time base starts at.
+    pub fn instant(&self) -> Instant {
+        self.instant
+    }
+
+    /// Returns the wall-clock time the time base starts at.
+    pub fn system_time(&self) -> SystemTime {
+        self.system
+    }
+
+    /// Returns the time from the start of the time base to `t`, or zero if
+    /// `t` is earlier.
+    pub fn elapsed(&self, t: Instant) -> Duration {
+        t.saturating_duration_since(self.instant)
+    }
+
+    /// Converts a time relative to the time base to a time since the Unix
+    /// epoch (UTC).
+    pub fn since_epoch(&self, elapsed: Duration) -> Duration {
+        self.system.duration_since(UNIX_EPOCH).unwrap_or_default() + elapsed
+    }
+}
+
+/// Maps a device's hardware timestamps to host time.
+///
+/// The device counts microseconds in a 32 bit counter that wraps about every
+/// 71 minutes, from an arbitrary start and with a crystal that drifts
+/// relative to the host clock. `ClockSync` is fed pairs of device timestamps
+/// and the host time they were received at, unwraps the counter, and fits a
+/// line through recent pairs to compensate for the offset and drift. Since
+/// USB latency only ever delays the host time, the line is placed through the
+/// earliest arrivals rather than the average.
+#[derive(Debug, Clone)]
+pub struct ClockSync {
+    // the last device timestamp unwrapped, with its host time
+    last: Option<(i64, f64)>,
+    // (unwrapped device µs, host µs), the earliest arrival of each interval
+    samples: VecDeque<(i64, f64)>,
+    // host µs = offset + rate * (device µs - reference)
+    reference: i64,
+    offset: f64,
+    rate: f64,
+}
+
+impl Default for ClockSync {
+    fn default() -> ClockSync {
+        ClockSync::new()
+    }
+}
+
+impl ClockSync {
+    /// Returns a `ClockSync` with no samples.
+    pub fn new() -> ClockSync {
+        ClockSync {
+            last: None,
+            samples: VecDeque::new(),
+            reference: 0,
+            offset: 0.0,
+            rate: 1.0,
+        }
+    }
+
+    /// Add a device timestamp received at host time `host`, returning the
+    /// host time the device timestamp corresponds to.
+    pub fn update(&mut self, device_us: u32, host: Duration) -> Duration {
+        let host_us = host.as_secs_f64() * 1e6;
+        // unwrap the counter to the value closest to the time expected from
+        // the host clock, so long gaps between frames are handled
+        let device = match self.last {
+            Some((last, last_host)) => {
+                unwrap_near(device_us, last as f64 + (host_us - last_host) / self.rate)
+            }
+            None => device_us as i64,
+        };
+        self.last = Some((device, host_us));
+
+        let bucket = device.div_euclid(SAMPLE_INTERVAL_US);
+        match self.samples.back_mut() {
+            Some(s) if s.0.div_euclid(SAMPLE_INTERVAL_US) == bucket => {
+                // keep the sample that arrived with the least latency
+                if host_us - device as f64 * self.rate < s.1 - s.0 as f64 * self.rate {
+                    *s = (device, host_us);
+                }
+            }
+            _ => {
+                self.samples.push_back((device, host_us));
+                if self.samples.len() > SAMPLE_WINDOW {
+                    self.samples.pop_front();
+                }
+            }
+        }
+        self.fit();
+        self.to_host_unwrapped(device)
+    }
+
+    /// Returns the host time of a device timestamp, or None if no samples
+    /// have been added. The timestamp must be within about 35 minutes of the
+    /// last one added.
+    pub fn to_host(&self, device_us: u32) -> Option<Duration> {
+        let (last, _) = self.last?;
+        Some(self.to_host_unwrapped(unwrap_near(device_us, last as f64)))
+    }
+
+    /// Returns the estimated drift of the device clock in parts per million.
+    /// Positive when the device clock runs fast.
+    pub fn drift_ppm(&self) -> f64 {
+        (1.0 / self.rate - 1.0) * 1e6
+    }
+
+    fn to_host_unwrapped(&self, device: i64) -> Duration {
+        let us = self.offset + self.rate * (device - self.reference) as f64;
+        Duration::from_secs_f64(us.max(0.0) / 1e6)
+    }
+
+    fn fit(&mut self) {
+        let (first, _) = self.samples[0];
+        let (last, _) = self.samples[self.samples.len() - 1];
+        let n = self.samples.len() as f64;
+        // work relative to the first sample to keep precision
+        self.reference = first;
+        if (last - first) as f64 >= MIN_DRIFT_SPAN_US {
+            let (mut sx, mut sy) = (0.0, 0.0);
+            for (d, h) in self.samples.iter() {
+                sx += (d - first) as f64;
+                sy += h;
+            }
+            let (mx, my) = (sx / n, sy / n);
+            let (mut sxy, mut sxx) = (0.0, 0.0);
+            for (d, h) in self.samples.iter() {
+                let x = (d - first) as f64 - mx;
+                sxy += x * (h - my);
+                sxx += x * x;
+            }
+            self.rate = (sxy / sxx).clamp(1.0 - MAX_DRIFT, 1.0 + MAX_DRIFT);
+        }
+        self.offset = self
+            .samples
+            .iter()
+            .map(|(d, h)| h - self.rate * (d - first) as f64)
+            .fold(f64::INFINITY, f64::min);
+    }
+}
+
+// returns the unwrapped value of a counter reading closest to `expected`
+fn unwrap_near(raw: u32, expected: f64) -> i64 {
+    let wraps = ((expected - raw as f64) / COUNTER_PERIOD_US).round();
+    raw as i64 + (wraps * COUNTER_PERIOD_US) as i64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_time_base() {
+        let base = TimeBase::now();
+        let later = base.instant() + Duration::from_millis(1500);
+        assert_eq!(base.elapsed(later), Duration::from_millis(1500));
+        assert_eq!(base.elapsed(base.instant()), Duration::from_secs(0));
+
+        let epoch = base.system_time().duration_since(UNIX_EPOCH).unwrap();
+        assert_eq!(
+            base.since_epoch(Duration::from_secs(2)),
+            epoch + Duration::from_secs(2)
+        );
+    }
+
+    #[test]
+    fn test_clock_sync() {
+        let mut sync = ClockSync::new();
+        assert_eq!(sync.to_host(0), None);
+
+        // the device clock runs 100 ppm fast, starting near the end of the
+        // counter, and frames arrive with 100 to 900 µs of latency
+        let start = u32::MAX as u64 - 5_000_000;
+        let mut max_error = 0.0f64;
+        for n in 0..20_000u64 {
+            let true_us = n * 1000;
+            let device = (start + true_us + true_us / 10_000) as u32;
+            let latency = 100 + (n * 7919) % 800;
+            let host = Duration::from_micros(true_us + latency);
+            let t = sync.update(device, host);
+            if n > 5000 {
+                let error = t.as_secs_f64() * 1e6 - true_us as f64;
+                max_error = max_error.max(error.abs());
+            }
+        }
+        assert!(
+            (sync.drift_ppm() - 100.0).abs() < 5.0,
+            "{}",
+            sync.drift_ppm()
+        );
+        // within the smallest latency seen
+        assert!(max_error < 200.0, "{}", max_error);
+
+        // the last timestamp sent, and one from before the counter wrapped
+        let last = (start + 19_999_000 + 1999) as u32;
+        let t = sync.to_host(last).unwrap().as_secs_f64();
+        assert!((t - 19.999).abs() < 0.001);
+        let t = sync.to_host(u32::MAX).unwrap().as_secs_f64();
+        assert!((t - 5.0).abs() < 0.001);
+    }
+
+    #[test]
+    fn test_clock_sync_gap() {
+        let mut sync = ClockSync::new();
+        sync.update(1000, Duration::from_secs(1));
+        // no frames for longer than the counter period
+        let gap = 2 * COUNTER_PERIOD_US as u64 + 3_000_000;
+        let t = sync.update((1000 + gap) as u32, Duration::from_micros(1_000_000 + gap));
+        assert!((t.as_secs_f64() - (1e6 + gap as f64) / 1e6).abs() < 0.001);
+    }
+}
@@ -0,0 +1,450 @@
+//! Timing analysis of cyclic messages.
+//!
+//! An `Analyzer` is fed received frames and tracks the time between frames of
+//! each message. The nominal period of a message is either given, or learned
+//! from its first periods. Periods outside the tolerance of the nominal
+//! period, and messages that stop arriving, are counted as violations.
+
+use cantact::Frame;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::time::Duration;
+
+/// A message is identified by its channel and CAN ID.
+pub type Key = (u8, u32);
+
+// number of periods used to learn the nominal period of a message
+const LEARN_PERIODS: usize = 8;
+
+/// Upper edges of the jitter histogram bins, as the deviation of a period from
+/// the nominal period in percent. The last bin holds everything above.
+pub const HISTOGRAM_EDGES: [f64; 10] =
+    [-50.0, -20.0, -10.0, -5.0, -1.0, 1.0, 5.0, 10.0, 20.0, 50.0];
+
+/// Returns the label of each histogram bin.
+pub fn histogram_labels() -> Vec<String> {
+    let mut labels = Vec::new();
+    let mut low: Option<f64> = None;
+    for high in HISTOGRAM_EDGES.iter() {
+        labels.push(match low {
+            None => format!("< {}%", high),
+            Some(low) => format!("{}..{}%", low, high),
+        });
+        low = Some(*high);
+    }
+    labels.push(format!("> {}%", HISTOGRAM_EDGES[HISTOGRAM_EDGES.len() - 1]));
+    labels
+}
+
+fn histogram_bin(deviation: f64) -> usize {
+    HISTOGRAM_EDGES
+        .iter()
+        .position(|edge| deviation < *edge)
+        .unwrap_or(HISTOGRAM_EDGES.len())
+}
+
+/// The nominal period of a message.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Nominal {
+    /// Periods seen so far, until enough are seen to learn the period.
+    Learning(Vec<Duration>),
+    /// The period was given.
+    Given(Duration),
+    /// The period was learned from the first periods.
+    Learned(Duration),
+    /// The first periods were too irregular for the message to be cyclic.
+    Aperiodic,
+}
+
+/// Timing statistics of one message.
+#[derive(Debug, Clone)]
+pub struct Message {
+    pub nominal: Nominal,
+    pub count: u64,
+    // timestamp of the last frame
+    pub last: Duration,
+    pub min: Option<Duration>,
+    pub max: Option<Duration>,
+    // periods longer and shorter than the tolerance allows
+    pub late: u64,
+    pub early: u64,
+    // number of times the message went missing
+    pub timeouts: u64,
+    // true while the message is missing
+    pub missing: bool,
+    pub histogram: [u64; HISTOGRAM_EDGES.len() + 1],
+    // mean and variance of the period in seconds, by Welford's method
+    periods: u64,
+    mean: f64,
+    m2: f64,
+}
+
+impl Message {
+    fn new(nominal: Nominal, t: Duration) -> Message {
+        Message {
+            nominal,
+            count: 1,
+            last: t,
+            min: None,
+            max: None,
+            late: 0,
+            early: 0,
+            timeouts: 0,
+            missing: false,
+            histogram: [0; HISTOGRAM_EDGES.len() + 1],
+            periods: 0,
+            mean: 0.0,
+            m2: 0.0,
+        }
+    }
+
+    /// Returns the period the message is checked against, if it is known.
+    pub fn expected(&self) -> Option<Duration> {
+        match self.nominal {
+            Nominal::Given(p) | Nominal::Learned(p) => Some(p),
+            _ => None,
+        }
+    }
+
+    /// Returns the mean period.
+    pub fn mean(&self) -> Option<Duration> {
+        if self.periods > 0 {
+            Some(Duration::from_secs_f64(self.mean))
+        } else {
+            None
+        }
+    }
+
+    /// Returns the standard deviation of the period.
+    pub fn jitter(&self) -> Option<Duration> {
+        if self.periods > 0 {
+            Some(Duration::from_secs_f64(
+                (self.m2 / self.periods as f64).sqrt(),
+            ))
+        } else {
+            None
+        }
+    }
+
+    /// Returns the number of timing violations of the message.
+    pub fn violations(&self) -> u64 {
+        self.late + self.early + self.timeouts
+    }
+
+    // record a period in the histogram and check it against the tolerance
+    fn check(&mut self, period: Duration, nominal: Duration, tolerance: f64, timed_out: bool) {
+        let deviation = period.as_secs_f64() / nominal.as_secs_f64() - 1.0;
+        self.histogram[histogram_bin(deviation * 100.0)] += 1;
+        // a gap that was already reported as a timeout is not also late
+        if deviation > tolerance && !timed_out {
+            self.late += 1;
+        } else if deviation < -tolerance {
+            self.early += 1;
+        }
+    }
+}
+
+/// Something that happened to a message, reported as it happens.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    /// No frame was received for `gap`, longer than the timeout.
+    Timeout {
+        key: Key,
+        gap: Duration,
+        period: Duration,
+    },
+    /// A missing message was received again after `gap`.
+    Resumed { key: Key, gap: Duration },
+    /// The nominal period of a message was learned.
+    Learned { key: Key, period: Duration },
+    /// The message was found not to be cyclic.
+    Aperiodic { key: Key },
+}
+
+fn format_key(key: &Key) -> String {
+    format!("ch:{} {:03X}", key.0, key.1)
+}
+
+/// Formats a duration in milliseconds.
+pub fn format_ms(d: Duration) -> String {
+    format!("{:.1} ms", d.as_secs_f64() * 1000.0)
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Event::Timeout { key, gap, period } => write!(
+                f,
+                "{} missing: no frame for {} (period {})",
+                format_key(key),
+                format_ms(*gap),
+                format_ms(*period)
+            ),
+            Event::Resumed { key, gap } => {
+                write!(f, "{} resumed after {}", format_key(key), format_ms(*gap))
+            }
+            Event::Learned { key, period } => {
+                write!(
+                    f,
+                    "{} period learned: {}",
+                    format_key(key),
+                    format_ms(*period)
+                )
+            }
+            Event::Aperiodic { key } => write!(f, "{} is not cyclic", format_key(key)),
+        }
+    }
+}
+
+/// Tracks the timing of every message received.
+#[derive(Debug)]
+pub struct Analyzer {
+    // nominal periods given for IDs on any channel
+    expected: HashMap<u32, Duration>,
+    // allowed deviation from the nominal period, as a fraction
+    tolerance: f64,
+    // number of nominal periods without a frame before a message is missing
+    timeout: f64,
+    messages: BTreeMap<Key, Message>,
+}
+
+impl Analyzer {
+    /// Create an analyzer that allows periods to deviate from the nominal
+    /// period by `tolerance` (a fraction, e.g. 0.1), and reports messages as
+    /// missing after `timeout` nominal periods without a frame.
+    pub fn new(tolerance: f64, timeout: f64) -> Analyzer {
+        Analyzer {
+            expected: HashMap::new(),
+            tolerance,
+            timeout,
+            messages: BTreeMap::new(),
+        }
+    }
+
+    /// Set the nominal period of an ID instead of learning it.
+    pub fn expect(&mut self, id: u32, period: Duration) {
+        self.expected.insert(id, period);
+    }
+
+    /// Returns the statistics of each message, in order of channel and ID.
+    pub fn messages(&self) -> impl Iterator<Item = (&Key, &Message)> {
+        self.messages.iter()
+    }
+
+    /// Returns the IDs that were given a nominal period but never received,
+    /// in order.
+    pub fn unseen(&self) -> Vec<u32> {
+        let mut ids: Vec<u32> = self
+            .expected
+            .keys()
+            .filter(|id| !self.messages.keys().any(|(_, seen)| seen == *id))
+            .copied()
+            .collect();
+        ids.sort();
+        ids
+    }
+
+    /// Returns the total number of timing violations, counting each ID that
+    /// was expected but never received as one.
+    pub fn violations(&self) -> u64 {
+        self.messages.values().map(|m| m.violations()).sum::<u64>() + self.unseen().len() as u64
+    }
+
+    /// Add a received frame. Error frames, frames sent by this device and
+    /// frames without a timestamp are ignored.
+    pub fn add(&mut self, f: &Frame) -> Option<Event> {
+        let t = match f.timestamp {
+            Some(t) if !f.err && !f.loopback => t,
+            _ => return None,
+        };
+        let key = (f.channel, f.can_id);
+        let m = match self.messages.get_mut(&key) {
+            Some(m) => m,
+            None => {
+                let nominal = match self.expected.get(&f.can_id) {
+                    Some(p) => Nominal::Given(*p),
+                    None => Nominal::Learning(Vec::new()),
+                };
+                self.messages.insert(key, Message::new(nominal, t));
+                return None;
+            }
+        };
+
+        let period = t.saturating_sub(m.last);
+        m.count += 1;
+        m.last = t;
+        m.min = Some(m.min.map_or(period, |min| min.min(period)));
+        m.max = Some(m.max.map_or(period, |max| max.max(period)));
+        m.periods += 1;
+        let secs = period.as_secs_f64();
+        let delta = secs - m.mean;
+        m.mean += delta / m.periods as f64;
+        m.m2 += delta * (secs - m.mean);
+
+        let timed_out = m.missing;
+        m.missing = false;
+        let mut event = if timed_out {
+            Some(Event::Resumed { key, gap: period })
+        } else {
+            None
+        };
+
+        match &mut m.nominal {
+            Nominal::Given(p) | Nominal::Learned(p) => {
+                let p = *p;
+                m.check(period, p, self.tolerance, timed_out);
+            }
+            Nominal::Learning(periods) => {
+                periods.push(period);
+                if periods.len() >= LEARN_PERIODS {
+                    let mut sorted = periods.clone();
+                    sorted.sort();
+                    let median = sorted[sorted.len() / 2];
+                    let tolerance = self.tolerance;
+                    let cyclic = median > Duration::from_secs(0)
+                        && sorted.iter().all(|p| {
+                            (p.as_secs_f64() / median.as_secs_f64() - 1.0).abs() <= tolerance
+                        });
+                    if cyclic {
+                        // the learning periods are within the tolerance, but
+                        // still belong in the histogram
+                        let learned = std::mem::take(periods);
+                        m.nominal = Nominal::Learned(median);
+                        for p in learned {
+                            m.check(p, median, tolerance, false);
+                        }
+                        event = Some(Event::Learned {
+                            key,
+                            period: median,
+                        });
+                    } else {
+                        m.nominal = Nominal::Aperiodic;
+                        event = Some(Event::Aperiodic { key });
+                    }
+                }
+            }
+            Nominal::Aperiodic => {}
+        }
+        event
+    }
+
+    /// Check for messages that have gone missing by time `now`, on the same
+    /// clock as the frame timestamps.
+    pub fn check(&mut self, now: Duration) -> Vec<Event> {
+        let mut events = Vec::new();
+        for (key, m) in self.messages.iter_mut() {
+            let period = match m.expected() {
+                Some(p) if !m.missing => p,
+                _ => continue,
+            };
+            let gap = now.saturating_sub(m.last);
+            if gap > period.mul_f64(self.timeout) {
+                m.missing = true;
+                m.timeouts += 1;
+                events.push(Event::Timeout {
+                    key: *key,
+                    gap,
+                    period,
+                });
+            }
+        }
+        events
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(can_id: u32, ms: u64) -> Frame {
+        Frame {
+            can_id,
+            timestamp: Some(Duration::from_millis(ms)),
+            ..Frame::default()
+        }
+    }
+
+    #[test]
+    fn test_learn_and_check() {
+        let mut a = Analyzer::new(0.1, 3.0);
+        let mut events = Vec::new();
+        // cyclic at 100 ms, with one late period after learning
+        for t in [0, 100, 200, 300, 400, 500, 600, 700, 800, 900, 1100, 1200] {
+            events.extend(a.add(&frame(0x100, t)));
+        }
+        // irregular
+        for t in [0, 10, 200, 210, 600, 610, 1000, 1010, 1500] {
+            events.extend(a.add(&frame(0x200, t)));
+        }
+        assert_eq!(
+            events,
+            vec![
+                Event::Learned {
+                    key: (0, 0x100),
+                    period: Duration::from_millis(100)
+                },
+                Event::Aperiodic { key: (0, 0x200) },
+            ]
+        );
+
+        let (_, m) = a.messages().next().unwrap();
+        assert_eq!(m.count, 12);
+        assert_eq!(m.late, 1);
+        assert_eq!(m.early, 0);
+        assert_eq!(m.max, Some(Duration::from_millis(200)));
+        assert_eq!(m.min, Some(Duration::from_millis(100)));
+        // 10 periods on time, one 100% late
+        assert_eq!(m.histogram[5], 10);
+        assert_eq!(m.histogram[10], 1);
+        assert_eq!(a.violations(), 1);
+    }
+
+    #[test]
+    fn test_timeout() {
+        let mut a = Analyzer::new(0.1, 3.0);
+        a.expect(0x100, Duration::from_millis(10));
+        assert_eq!(a.add(&frame(0x100, 0)), None);
+        assert_eq!(a.add(&frame(0x100, 10)), None);
+        assert!(a.check(Duration::from_millis(30)).is_empty());
+
+        let events = a.check(Duration::from_millis(45));
+        assert_eq!(
+            events,
+            vec![Event::Timeout {
+                key: (0, 0x100),
+                gap: Duration::from_millis(35),
+                period: Duration::from_millis(10)
+            }]
+        );
+        // reported once
+        assert!(a.check(Duration::from_millis(100)).is_empty());
+        assert_eq!(
+            a.add(&frame(0x100, 110)),
+            Some(Event::Resumed {
+                key: (0, 0x100),
+                gap: Duration::from_millis(100)
+            })
+        );
+        let (_, m) = a.messages().next().unwrap();
+        assert_eq!((m.timeouts, m.late), (1, 0));
+        assert!(a.unseen().is_empty());
+        a.expect(0x7FF, Duration::from_millis(10));
+        assert_eq!(a.unseen(), vec![0x7FF]);
+        assert_eq!(a.violations(), 2);
+        assert_eq!(
+            events[0].to_string(),
+            "ch:0 100 missing: no frame for 35.0 ms (period 10.0 ms)"
+        );
+    }
+
+    #[test]
+    fn test_histogram_labels() {
+        let labels = histogram_labels();
+        assert_eq!(labels.len(), HISTOGRAM_EDGES.len() + 1);
+        assert_eq!(labels[0], "< -50%");
+        assert_eq!(labels[5], "-1..1%");
+        assert_eq!(labels[10], "> 50%");
+        assert_eq!(histogram_bin(0.0), 5);
+        assert_eq!(histogram_bin(-60.0), 0);
+    }
+}
@@ -0,0 +1,211 @@
+use crate::Error;
+use cantact::{DeviceSelector, Direction, Frame, Gateway, GatewayStats, Interface, Rule};
+use clap::ArgMatches;
+use crossbeam_channel::{unbounded, Sender};
+use log::info;
+use serde::Deserialize;
+use std::fs;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::config::{self, Config};
+use crate::helpers;
+
+// rules used when no rule file is given, if it exists
+const RULES_FILE: &str = "bridge.toml";
+// how often the main loop checks for ctrl-c
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RuleFile {
+    #[serde(rename = "rule", default)]
+    rules: Vec<Rule>,
+}
+
+fn parse_rules(s: &str) -> Result<Vec<Rule>, String> {
+    toml::from_str::<RuleFile>(s)
+        .map(|f| f.rules)
+        .map_err(|e| e.to_string())
+}
+
+// reads the rule file given on the command line, or the default rule file
+// next to cantact.toml if there is one
+fn read_rules(path: Option<&str>) -> Result<Vec<Rule>, Error> {
+    let path = match path {
+        Some(p) => PathBuf::from(p),
+        None => match config::config_file(RULES_FILE) {
+            Some(p) if p.exists() => p,
+            _ => return Ok(Vec::new()),
+        },
+    };
+    info!("reading bridge rules from {:?}", path);
+    let s = fs::read_to_string(&path)
+        .map_err(|e| Error::IoError(format!("could not read {}: {}", path.display(), e)))?;
+    parse_rules(&s)
+        .map_err(|e| Error::InvalidArgument(format!("invalid rules in {}: {}", path.display(), e)))
+}
+
+fn rule_name(rules: &[Rule], n: usize) -> String {
+    match &rules[n].name {
+        Some(name) => name.clone(),
+        None => format!("rule {}", n + 1),
+    }
+}
+
+// a device index, or a serial number
+fn parse_device(s: &str) -> DeviceSelector {
+    match s.parse::<usize>() {
+        Ok(n) => DeviceSelector::Index(n),
+        Err(_) => DeviceSelector::Serial(String::from(s)),
+    }
+}
+
+fn open(matches: &ArgMatches, selector: DeviceSelector) -> Result<Interface, Error> {
+    let mut config = Config::read_for_device(matches, &selector)?;
+    let mut i = Interface::builder().device(selector).build()?;
+    config.fit_to_interface(&i)?;
+    info!("config: {:?}", config);
+    config.apply_to_interface(&mut i)?;
+    Ok(i)
+}
+
+// passes frames received on the bridged channels of an interface to the gateway
+fn receiver(
+    send: Sender<(Direction, Frame)>,
+    a: Option<u8>,
+    b: Option<u8>,
+) -> impl FnMut(Frame) + Sync + Send + 'static {
+    move |f: Frame| {
+        let dir = if Some(f.channel) == a {
+            Direction::AToB
+        } else if Some(f.channel) == b {
+            Direction::BToA
+        } else {
+            return;
+        };
+        // the gateway has stopped if the receiver is gone
+        let _ = send.send((dir, f));
+    }
+}
+
+fn print_stats(stats: &GatewayStats, rules: &[Rule]) {
+    for (name, d) in [("a -> b", &stats.a_to_b), ("b -> a", &stats.b_to_a)].iter() {
+        println!(
+            "{}: received {}, forwarded {}, dropped {}, failed {}",
+            name, d.received, d.forwarded, d.dropped, d.failed
+        );
+    }
+    if !rules.is_empty() {
+        println!("rule hits:");
+        for (n, hits) in stats.hits.iter().enumerate() {
+            println!("{:10}  {}", hits, rule_name(rules, n));
+        }
+    }
+}
+
+pub fn cmd(matches: &ArgMatches) -> Result<(), Error> {
+    let flag = helpers::initialize_ctrlc()?;
+
+    let mut gw = Gateway::new(read_rules(matches.value_of("rules"))?).map_err(|e| match e {
+        cantact::Error::InvalidRule(n, msg) => {
+            Error::InvalidArgument(format!("invalid rule {}: {}", n + 1, msg))
+        }
+        e => Error::DeviceError(e),
+    })?;
+
+    let device_a = parse_device(matches.value_of("device-a").unwrap_or("0"));
+    let device_b = matches.value_of("device-b").map(parse_device);
+    let channel_a = helpers::parse_arg::<u8>(matches, "channel-a")?.unwrap_or(0);
+    // the second channel of one device, or the first channel of another
+    let channel_b = helpers::parse_arg::<u8>(matches, "channel-b")?
+        .unwrap_or(if device_b.is_some() { 0 } else { 1 });
+
+    // both sides are on interfaces[0] unless a second device is given
+    let mut interfaces = vec![open(matches, device_a)?];
+    if let Some(d) = device_b {
+        interfaces.push(open(matches, d)?);
+    }
+    let side_a = (0, channel_a);
+    let side_b = (interfaces.len() - 1, channel_b);
+    if side_a == side_b {
+        return Err(Error::InvalidArgument(String::from(
+            "cannot bridge a channel to itself",
+        )));
+    }
+    for &(n, ch) in [side_a, side_b].iter() {
+        if ch as usize >= interfaces[n].channels() {
+            return Err(Error::InvalidArgument(format!(
+                "channel {} does not exist",
+                ch
+            )));
+        }
+        // the bridged channels must be on bus, whatever the configuration
+        interfaces[n].set_enabled(ch as usize, true)?;
+    }
+
+    let (send, recv) = unbounded();
+    if interfaces.len() == 1 {
+        interfaces[0].start(receiver(send, Some(channel_a), Some(channel_b)))?;
+    } else {
+        interfaces[0].start(receiver(send.clone(), Some(channel_a), None))?;
+        interfaces[1].start(receiver(send, None, Some(channel_b)))?;
+    }
+    println!(
+        "bridging channel {} and channel {} with {} rules, press ctrl-c to stop",
+        channel_a,
+        channel_b,
+        gw.rules().len()
+    );
+
+    while !helpers::check_ctrlc(&flag) {
+        gw.poll(&recv, POLL_INTERVAL, |dir, mut f| {
+            let (n, ch) = match dir {
+                Direction::AToB => side_b,
+                Direction::BToA => side_a,
+            };
+            f.channel = ch;
+            interfaces[n].send(f)
+        });
+    }
+
+    for i in interfaces.iter_mut() {
+        i.stop()?;
+    }
+    print_stats(gw.stats(), gw.rules());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_rules() {
+        let rules = parse_rules(
+            r#"
+            [[rule]]
+            name = "block diagnostics"
+            direction = "a-to-b"
+            id = 0x7DF
+            drop = true
+
+            [[rule]]
+            id = 0x100
+            data_mask = [0x00, 0xFF]
+            data_value = [0x00, 0x12]
+            delay_ms = 10
+            "#,
+        )
+        .unwrap();
+        assert_eq!(rules.len(), 2);
+        assert_eq!(rules[0].direction, Some(Direction::AToB));
+        assert!(rules[0].drop);
+        assert_eq!(rules[1].data_value, vec![0x00, 0x12]);
+        assert_eq!(rule_name(&rules, 0), "block diagnostics");
+        assert_eq!(rule_name(&rules, 1), "rule 2");
+
+        assert!(parse_rules("[[rule]]\nid = 0x100\nrewrite = true\n").is_err());
+        assert!(parse_rules("").unwrap().is_empty());
+    }
+}
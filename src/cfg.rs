@@ -1,71 +1,237 @@
 use crate::Error;
+use cantact::{Channel, DeviceSelector, Error as DevError, Interface};
 use clap::ArgMatches;
+use log::{info, warn};
 
-use crate::config::Config;
+use crate::config::{self, Config, Source, DEFAULT_CONFIG};
 use crate::helpers;
 
-pub fn cmd(matches: &ArgMatches) -> Result<(), Error> {
-    let mut config = Config::read();
+// options that change a channel's configuration
+const OPTIONS: [&str; 18] = [
+    "bitrate",
+    "data_bitrate",
+    "disable",
+    "enable",
+    "monitor",
+    "no-monitor",
+    "loopback",
+    "no-loopback",
+    "fd",
+    "no-fd",
+    "one-shot",
+    "no-one-shot",
+    "triple-sample",
+    "no-triple-sample",
+    "sample-point",
+    "sjw",
+    "name",
+    "reset",
+];
 
-    let ch = match helpers::parse_channel(matches)? {
-        None => {
-            // if no channel is provided, print the current configuration
-            print!("{}", config);
-            return Ok(());
-        }
-        Some(ch) => ch,
+// sets `value` if the flag or its negation (--no-flag) is given
+fn flag(matches: &ArgMatches, name: &str, value: &mut bool) {
+    if matches.is_present(name) {
+        *value = true;
+    } else if matches.is_present(format!("no-{}", name)) {
+        *value = false;
+    }
+}
+
+// parses a sample point given as a fraction (0.875) or a percentage (87.5 or
+// 87.5%). Values without a % are fractions if below 1, and percentages if
+// above 1, so 1 itself is ambiguous and rejected.
+pub fn parse_sample_point(s: &str) -> Option<f32> {
+    let (s, percent) = match s.strip_suffix('%') {
+        Some(s) => (s, true),
+        None => (s, false),
     };
+    let v = s.trim().parse::<f32>().ok()?;
+    let v = if percent || v > 1.0 { v / 100.0 } else { v };
+    if v > 0.0 && v < 1.0 {
+        Some(v)
+    } else {
+        None
+    }
+}
+
+// applies the options given on the command line to a channel, leaving
+// other settings unchanged
+fn update(matches: &ArgMatches, ch: &mut Channel) -> Result<(), Error> {
+    if matches.is_present("reset") {
+        *ch = DEFAULT_CONFIG;
+    }
 
     if matches.is_present("disable") {
-        config.channels[ch].enabled = false;
-    } else {
-        config.channels[ch].enabled = true;
+        ch.enabled = false;
+    } else if matches.is_present("enable") {
+        ch.enabled = true;
     }
+    flag(matches, "monitor", &mut ch.monitor);
+    flag(matches, "loopback", &mut ch.loopback);
+    flag(matches, "fd", &mut ch.fd);
+    flag(matches, "one-shot", &mut ch.one_shot);
+    flag(matches, "triple-sample", &mut ch.triple_sample);
 
-    if matches.is_present("loopback") {
-        config.channels[ch].loopback = true;
-    } else {
-        config.channels[ch].loopback = false;
+    if let Some(bitrate) = helpers::parse_arg::<u32>(matches, "bitrate")? {
+        ch.bitrate = bitrate;
+    }
+    if let Some(data_bitrate) = helpers::parse_arg::<u32>(matches, "data_bitrate")? {
+        ch.data_bitrate = data_bitrate;
     }
 
-    if matches.is_present("monitor") {
-        config.channels[ch].monitor = true;
-    } else {
-        config.channels[ch].monitor = false;
+    match matches.value_of("sample-point") {
+        None => {}
+        Some("auto") => ch.sample_point = None,
+        Some(s) => {
+            ch.sample_point = Some(parse_sample_point(s).ok_or_else(|| {
+                Error::InvalidArgument(String::from("invalid sample-point value"))
+            })?)
+        }
     }
+    match matches.value_of("sjw") {
+        None => {}
+        Some("auto") => ch.sjw = None,
+        Some(_) => match helpers::parse_arg::<u32>(matches, "sjw")? {
+            Some(sjw) if sjw > 0 => ch.sjw = Some(sjw),
+            _ => return Err(Error::InvalidArgument(String::from("invalid sjw value"))),
+        },
+    }
+    match matches.value_of("name") {
+        None => {}
+        Some("") => ch.name = None,
+        Some(name) => ch.name = Some(String::from(name)),
+    }
+    Ok(())
+}
 
-    if matches.is_present("fd") {
-        config.channels[ch].fd = true;
-    } else {
-        config.channels[ch].fd = false;
+fn selector(config: &Config) -> DeviceSelector {
+    match &config.source {
+        Source::Device(serial) => DeviceSelector::Serial(serial.clone()),
+        _ => DeviceSelector::First,
     }
+}
 
-    if matches.is_present("bitrate") {
-        let bitrate = match matches.value_of("bitrate").unwrap().parse::<u32>() {
-            Err(_) => {
-                return Err(Error::InvalidArgument(String::from(
-                    "invalid bitrate value",
-                )))
-            }
-            Ok(b) => b,
-        };
-        config.channels[ch].bitrate = bitrate;
+// opens the device the configuration is for, if it is attached
+fn open_device(config: &Config) -> Option<Interface> {
+    match Interface::builder().device(selector(config)).build() {
+        Ok(i) => Some(i),
+        Err(DevError::DeviceNotFound) => {
+            info!("no device attached, configuration not checked");
+            None
+        }
+        Err(e) => {
+            warn!("could not open device to check configuration: {}", e);
+            None
+        }
+    }
+}
+
+// checks the configuration against the device it is for
+fn validate(config: &Config, i: &Interface) -> Result<(), Error> {
+    let count = i.channels();
+    let mut builder = Interface::builder();
+    for (n, ch) in config.channels.iter().enumerate() {
+        if n < count {
+            builder = builder.channel(n, ch.clone());
+        } else if ch.enabled {
+            return Err(Error::InvalidArgument(format!(
+                "channel {} is enabled, but the device only has {} channels",
+                n, count
+            )));
+        }
+    }
+    builder.check(i)?;
+    info!("configuration is valid for the attached device");
+    Ok(())
+}
+
+pub fn cmd(matches: &ArgMatches) -> Result<(), Error> {
+    let mut config = Config::open(matches)?;
+
+    // select the section to show or change, new sections start with the
+    // current settings
+    if let Some(serial) = matches.value_of("serial") {
+        if !config.select_device(serial) {
+            config.add_section(Source::Device(String::from(serial)));
+        }
+    } else if let Some(profile) = config::profile_name(matches) {
+        if config.select_profile(&profile).is_err() {
+            config.add_section(Source::Profile(profile));
+        }
     }
 
-    if matches.is_present("data_bitrate") {
-        let data_bitrate = match matches.value_of("data_bitrate").unwrap().parse::<u32>() {
-            Err(_) => {
-                return Err(Error::InvalidArgument(String::from(
-                    "invalid data_bitrate value",
-                )))
+    let ch = helpers::parse_channel(matches)?;
+    let changed = OPTIONS.iter().any(|o| matches.is_present(o));
+
+    // show and configure every channel of the device
+    let device = open_device(&config);
+    let count = device.as_ref().map(|i| i.channels());
+    if let Some(count) = count {
+        if config.channels.len() < count {
+            config.channels.resize(count, DEFAULT_CONFIG);
+        }
+    }
+    match ch {
+        // if no channel or option is provided, print the current configuration
+        None if !changed => {
+            print!("{}", config);
+            return Ok(());
+        }
+        // --reset alone resets every channel
+        None if OPTIONS
+            .iter()
+            .all(|o| *o == "reset" || !matches.is_present(o)) =>
+        {
+            for c in config.channels.iter_mut() {
+                *c = DEFAULT_CONFIG;
+            }
+        }
+        None => {
+            return Err(Error::InvalidArgument(String::from(
+                "a channel must be given with -c",
+            )))
+        }
+        Some(ch) if count.is_some_and(|count| ch >= count && ch >= config.channels.len()) => {
+            return Err(Error::InvalidArgument(format!(
+                "channel {} does not exist, the device has {} channels",
+                ch,
+                count.unwrap()
+            )))
+        }
+        Some(ch) => {
+            // channels that are not configured yet start with the defaults
+            if ch >= config.channels.len() {
+                config.channels.resize(ch + 1, DEFAULT_CONFIG);
             }
-            Ok(b) => b,
-        };
-        config.channels[ch].data_bitrate = data_bitrate;
+            update(matches, &mut config.channels[ch])?
+        }
     }
 
-    config.write().unwrap();
+    if let Some(i) = device.as_ref() {
+        validate(&config, i)?;
+    }
+    config
+        .write()
+        .map_err(|e| Error::ConfigError(format!("could not write configuration: {}", e)))?;
 
     print!("{}", config);
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_sample_point() {
+        assert_eq!(parse_sample_point("0.875"), Some(0.875));
+        assert_eq!(parse_sample_point("87.5"), Some(0.875));
+        assert_eq!(parse_sample_point("75%"), Some(0.75));
+        assert_eq!(parse_sample_point("1%"), Some(0.01));
+        assert_eq!(parse_sample_point("1"), None);
+        assert_eq!(parse_sample_point("100"), None);
+        assert_eq!(parse_sample_point("150"), None);
+        assert_eq!(parse_sample_point("0"), None);
+        assert_eq!(parse_sample_point("fast"), None);
+    }
+}
@@ -1,37 +1,93 @@
 use crate::Error;
 use app_dirs::*;
-use cantact::{Channel, Interface};
+use cantact::{Channel, DeviceSelector, Interface};
+use clap::ArgMatches;
 use log::info;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::env;
 use std::fmt;
 use std::fs;
 use std::fs::File;
 use std::io;
 use std::io::prelude::*;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 const APP_INFO: AppInfo = AppInfo {
     name: "cantact",
     author: "Linklayer",
 };
 const CFG_FILE: &str = "cantact.toml";
-const DEFAULT_CONFIG: Channel = Channel {
+// environment variables used when --config and --profile are not given
+const CONFIG_ENV: &str = "CANTACT_CONFIG";
+const PROFILE_ENV: &str = "CANTACT_PROFILE";
+pub const DEFAULT_CONFIG: Channel = Channel {
     bitrate: 500_000,
     data_bitrate: 500_000,
     loopback: false,
     monitor: false,
     fd: false,
     enabled: true,
+    sample_point: None,
+    sjw: None,
+    one_shot: false,
+    triple_sample: false,
+    name: None,
 };
 
-#[derive(Debug, Serialize, Deserialize)]
+// channel settings in a [profile.NAME] or [device.SERIAL] section. Channels
+// that are not listed use the default settings.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Section {
+    #[serde(rename = "channel", default, skip_serializing_if = "Vec::is_empty")]
+    channels: Vec<Channel>,
+}
+
+// contents of the configuration file: the default channel settings, named
+// profiles, and settings for devices with a given serial number
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    #[serde(rename = "channel", default, skip_serializing_if = "Vec::is_empty")]
+    channels: Vec<Channel>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    profile: BTreeMap<String, Section>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    device: BTreeMap<String, Section>,
+}
+// section of the configuration file the channel settings come from
+#[derive(Debug, Clone, PartialEq)]
+pub enum Source {
+    Default,
+    Profile(String),
+    Device(String),
+}
+
+#[derive(Debug)]
 pub struct Config {
-    #[serde(rename = "channel")]
+    // settings used for the device, from `source`. Until the configuration
+    // is fitted to a device, this only contains the configured channels.
     pub channels: Vec<Channel>,
+    pub source: Source,
+    path: PathBuf,
+    file: ConfigFile,
 }
 impl fmt::Display for Config {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.source {
+            Source::Default => {}
+            Source::Profile(name) => writeln!(f, "Profile: {}", name)?,
+            Source::Device(serial) => writeln!(f, "Device: {}", serial)?,
+        }
         writeln!(f, "Channels:")?;
+        if self.channels.is_empty() {
+            writeln!(
+                f,
+                "\tnot configured, every channel uses {:?}",
+                DEFAULT_CONFIG
+            )?;
+        }
         for (n, ch) in self.channels.iter().enumerate() {
             writeln!(f, "\t{} -> {:?}", n, ch)?;
         }
@@ -39,61 +95,393 @@ impl fmt::Display for Config {
     }
 }
 
-impl Config {
-    pub fn default() -> Config {
+impl Default for Config {
+    fn default() -> Config {
         Config {
-            channels: vec![DEFAULT_CONFIG, DEFAULT_CONFIG],
+            channels: Vec::new(),
+            source: Source::Default,
+            path: PathBuf::from(CFG_FILE),
+            file: ConfigFile::default(),
         }
     }
+}
+
+// returns the path of a file in the configuration directory, next to cantact.toml
+pub fn config_file(name: &str) -> Option<PathBuf> {
+    let dir = get_app_root(AppDataType::UserConfig, &APP_INFO).ok()?;
+    Some(Path::new("").join(dir).join(name))
+}
+
+// the value of an argument, or of an environment variable if it is not given
+fn arg_or_env(matches: &ArgMatches, name: &str, var: &str) -> Option<String> {
+    matches
+        .value_of(name)
+        .map(String::from)
+        .or_else(|| env::var(var).ok().filter(|v| !v.is_empty()))
+}
+
+// the profile given with --profile or CANTACT_PROFILE
+pub fn profile_name(matches: &ArgMatches) -> Option<String> {
+    arg_or_env(matches, "profile", PROFILE_ENV)
+}
+
+// returns the line number of the nth (zero indexed) occurrence of a table
+// header in a TOML document, ignoring whitespace and quotes
+fn header_line(s: &str, header: &str, n: usize) -> Option<usize> {
+    let normalize = |l: &str| -> String {
+        l.chars()
+            .filter(|c| !c.is_whitespace() && *c != '"' && *c != '\'')
+            .collect()
+    };
+    s.lines()
+        .enumerate()
+        .filter(|(_, l)| normalize(l.split('#').next().unwrap_or("")) == header)
+        .nth(n)
+        .map(|(i, _)| i + 1)
+}
+
+// checks the channel settings of one section, `prefix` is the section's table name
+fn validate(s: &str, prefix: &str, channels: &[Channel]) -> Result<(), String> {
+    let header = format!("[[{}channel]]", prefix);
+    for (n, ch) in channels.iter().enumerate() {
+        let problem = if ch.bitrate == 0 {
+            "bitrate must be greater than 0"
+        } else if ch.fd && ch.data_bitrate == 0 {
+            "data_bitrate must be greater than 0 when fd is enabled"
+        } else {
+            continue;
+        };
+        let location = match header_line(s, &header, n) {
+            Some(line) => format!("line {}", line),
+            None => format!("{}channel {}", prefix, n),
+        };
+        return Err(format!("{} at {}", problem, location));
+    }
+    Ok(())
+}
+
+fn parse(s: &str) -> Result<ConfigFile, String> {
+    // toml errors include the line and column of the problem
+    let file: ConfigFile = toml::from_str(s).map_err(|e| e.to_string())?;
+    validate(s, "", &file.channels)?;
+    for (name, p) in file.profile.iter() {
+        validate(s, &format!("profile.{}.", name), &p.channels)?;
+    }
+    for (serial, d) in file.device.iter() {
+        validate(s, &format!("device.{}.", serial), &d.channels)?;
+    }
+    Ok(file)
+}
 
-    // since config files are not mandatory, this should never fail
-    pub fn read() -> Config {
-        let dir = match get_app_root(AppDataType::UserConfig, &APP_INFO) {
-            Ok(d) => d,
-            Err(_) => return Config::default(),
+impl Config {
+    // read the configuration file given with --config or CANTACT_CONFIG, or
+    // the default file. Config files are not mandatory, but errors in an
+    // existing file are reported.
+    pub fn open(matches: &ArgMatches) -> Result<Config, Error> {
+        let path = match arg_or_env(matches, "config", CONFIG_ENV) {
+            Some(p) => PathBuf::from(p),
+            None => match config_file(CFG_FILE) {
+                Some(p) => p,
+                None => return Ok(Config::default()),
+            },
         };
-        let filename = Path::new("").join(dir).join(CFG_FILE);
-        let s = match fs::read_to_string(&filename) {
-            Ok(s) => s,
-            Err(_) => return Config::default(),
+        let file = match fs::read_to_string(&path) {
+            Ok(s) => {
+                info!("read configuration from {:?}", path);
+                parse(&s).map_err(|e| Error::ConfigError(format!("{}: {}", path.display(), e)))?
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => ConfigFile::default(),
+            Err(e) => {
+                return Err(Error::ConfigError(format!(
+                    "could not read {}: {}",
+                    path.display(),
+                    e
+                )))
+            }
         };
-        let result = toml::from_str(&s).unwrap_or_else(|_| Config::default());
-        info!("read configuration from {:?}", filename);
-        result
+        Ok(Config {
+            channels: file.channels.clone(),
+            source: Source::Default,
+            path,
+            file,
+        })
+    }
+
+    // read the configuration for the first device found
+    pub fn read(matches: &ArgMatches) -> Result<Config, Error> {
+        Config::read_for_device(matches, &DeviceSelector::First)
+    }
+
+    // read the configuration for a device. The profile given with --profile
+    // or CANTACT_PROFILE is used if there is one, otherwise the device's
+    // section if there is one, otherwise the default channel settings.
+    pub fn read_for_device(matches: &ArgMatches, device: &DeviceSelector) -> Result<Config, Error> {
+        let mut config = Config::open(matches)?;
+        if let Some(profile) = profile_name(matches) {
+            config.select_profile(&profile)?;
+        } else if !config.file.device.is_empty() {
+            if let Some(serial) = serial_number(device) {
+                config.select_device(&serial);
+            }
+        }
+        Ok(config)
     }
 
-    pub fn write(&self) -> io::Result<()> {
-        let dir = match get_app_root(AppDataType::UserConfig, &APP_INFO) {
-            Ok(d) => d,
-            Err(AppDirsError::NotSupported) => panic!("platform does not support configuation"),
-            Err(AppDirsError::Io(e)) => {
-                panic!("IO error determining configuration location: {:?}", e)
+    // use the settings of a profile
+    pub fn select_profile(&mut self, name: &str) -> Result<(), Error> {
+        match self.file.profile.get(name) {
+            Some(p) => {
+                self.channels = p.channels.clone();
+                self.source = Source::Profile(String::from(name));
+                Ok(())
             }
-            Err(AppDirsError::InvalidAppInfo) => panic!("app info struct is invalid"),
+            None => {
+                let names: Vec<&str> = self.file.profile.keys().map(|k| k.as_str()).collect();
+                Err(Error::ConfigError(format!(
+                    "profile {} not found in {}, available profiles: {}",
+                    name,
+                    self.path.display(),
+                    if names.is_empty() {
+                        String::from("none")
+                    } else {
+                        names.join(", ")
+                    }
+                )))
+            }
+        }
+    }
+
+    // use the settings for a device, returning false if there are none
+    pub fn select_device(&mut self, serial: &str) -> bool {
+        match self.file.device.get(serial) {
+            Some(d) => {
+                info!("using configuration for device {}", serial);
+                self.channels = d.channels.clone();
+                self.source = Source::Device(String::from(serial));
+                true
+            }
+            None => false,
+        }
+    }
+
+    // sets `source` to a new section with the current settings, so they
+    // are written there
+    pub fn add_section(&mut self, source: Source) {
+        let section = Section {
+            channels: self.channels.clone(),
         };
-        fs::create_dir_all(&dir)?;
-        let filename = Path::new("").join(dir).join(CFG_FILE);
-        info!("writing configuration to {:?}", filename);
+        match &source {
+            Source::Default => {}
+            Source::Profile(name) => {
+                self.file.profile.insert(name.clone(), section);
+            }
+            Source::Device(serial) => {
+                self.file.device.insert(serial.clone(), section);
+            }
+        }
+        self.source = source;
+    }
+
+    // write the configuration, storing the channel settings in their section
+    pub fn write(&mut self) -> io::Result<()> {
+        let channels = self.channels.clone();
+        match &self.source {
+            Source::Default => self.file.channels = channels,
+            Source::Profile(name) => {
+                self.file
+                    .profile
+                    .entry(name.clone())
+                    .or_insert(Section {
+                        channels: Vec::new(),
+                    })
+                    .channels = channels
+            }
+            Source::Device(serial) => {
+                self.file
+                    .device
+                    .entry(serial.clone())
+                    .or_insert(Section {
+                        channels: Vec::new(),
+                    })
+                    .channels = channels
+            }
+        }
+
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        info!("writing configuration to {:?}", self.path);
+
+        let s = toml::to_string(&self.file)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let mut file = File::create(&self.path)?;
+        file.write_all(s.as_bytes())
+    }
 
-        let mut file = File::create(filename)?;
-        file.write_all(toml::to_string(&self).unwrap().as_bytes())
+    // size the channel settings to the device's channels, using the default
+    // settings for channels that are not configured
+    pub fn fit_to_interface(&mut self, i: &Interface) -> Result<(), Error> {
+        self.resize(i.channels())
+    }
+
+    fn resize(&mut self, count: usize) -> Result<(), Error> {
+        if let Some(n) = (count..self.channels.len()).find(|n| self.channels[*n].enabled) {
+            return Err(Error::ConfigError(format!(
+                "channel {} is enabled in {}, but the device only has {} channel{}, disable it with `can cfg -c {} --disable`",
+                n,
+                self.path.display(),
+                count,
+                if count == 1 { "" } else { "s" },
+                n
+            )));
+        }
+        self.channels.resize(count, DEFAULT_CONFIG);
+        Ok(())
+    }
+
+    // enable only the given channels, which must exist
+    pub fn select_channels(&mut self, channels: &[usize]) -> Result<(), Error> {
+        if let Some(ch) = channels.iter().find(|ch| **ch >= self.channels.len()) {
+            return Err(Error::InvalidArgument(format!(
+                "channel {} does not exist, the device has {} channel{}",
+                ch,
+                self.channels.len(),
+                if self.channels.len() == 1 { "" } else { "s" }
+            )));
+        }
+        for (n, c) in self.channels.iter_mut().enumerate() {
+            c.enabled = channels.contains(&n);
+        }
+        Ok(())
     }
 
     pub fn apply_to_interface(&self, i: &mut Interface) -> Result<(), Error> {
+        if self.channels.len() > i.channels() {
+            return Err(Error::ConfigError(format!(
+                "configuration has {} channels, but the device only has {}",
+                self.channels.len(),
+                i.channels()
+            )));
+        }
         for (n, ch) in self.channels.iter().enumerate() {
-            if n > (i.channels() - 1) {
-                // device doesn't have as many channels as config, ignore the rest
-                break;
-            }
-            i.set_bitrate(n, ch.bitrate)?;
-            i.set_enabled(n, ch.enabled)?;
-            i.set_loopback(n, ch.loopback)?;
-            i.set_monitor(n, ch.monitor)?;
-            if i.supports_fd() {
-                i.set_fd(n, ch.fd)?;
-                i.set_data_bitrate(n, ch.data_bitrate)?;
+            let mut ch = ch.clone();
+            if !i.supports_fd() {
+                ch.fd = false;
             }
+            i.reconfigure(n, ch)?;
         }
         Ok(())
     }
 }
+
+// the serial number of the device a selector opens, if it can be found
+fn serial_number(device: &DeviceSelector) -> Option<String> {
+    let index = match device {
+        DeviceSelector::Serial(s) => return Some(s.clone()),
+        DeviceSelector::First => 0,
+        DeviceSelector::Index(n) => *n,
+    };
+    cantact::list_devices().ok()?.get(index)?.serial.clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FILE: &str = r#"
+[[channel]]
+bitrate = 500000
+enabled = true
+loopback = false
+monitor = false
+fd = false
+data_bitrate = 500000
+
+[profile.vehicle_a]
+[[profile.vehicle_a.channel]]
+bitrate = 250000
+enabled = true
+loopback = false
+monitor = true
+fd = false
+data_bitrate = 250000
+
+[device.ABC123]
+[[device.ABC123.channel]]
+bitrate = 1000000
+enabled = true
+loopback = false
+monitor = false
+fd = false
+data_bitrate = 1000000
+"#;
+
+    fn config(s: &str) -> Config {
+        let file = parse(s).unwrap();
+        Config {
+            channels: file.channels.clone(),
+            source: Source::Default,
+            path: PathBuf::from(CFG_FILE),
+            file,
+        }
+    }
+
+    #[test]
+    fn test_sections() {
+        let mut c = config(FILE);
+        assert_eq!(c.channels[0].bitrate, 500_000);
+
+        c.select_profile("vehicle_a").unwrap();
+        assert_eq!(c.source, Source::Profile(String::from("vehicle_a")));
+        assert_eq!(c.channels[0].bitrate, 250_000);
+        assert!(c.channels[0].monitor);
+
+        assert!(c.select_device("ABC123"));
+        assert_eq!(c.channels[0].bitrate, 1_000_000);
+        assert!(!c.select_device("XYZ"));
+
+        match c.select_profile("vehicle_b") {
+            Err(Error::ConfigError(e)) => assert!(e.ends_with("available profiles: vehicle_a")),
+            r => panic!("unexpected result {:?}", r),
+        }
+
+        // a file with only profiles uses the default channel settings
+        let mut c = config("[profile.empty]\n");
+        c.select_profile("empty").unwrap();
+        assert!(c.channels.is_empty());
+        c.resize(3).unwrap();
+        assert_eq!(c.channels.len(), 3);
+        assert_eq!(c.channels[2].bitrate, DEFAULT_CONFIG.bitrate);
+    }
+
+    #[test]
+    fn test_channel_count() {
+        let mut c = config(FILE);
+        c.resize(4).unwrap();
+        assert_eq!(c.channels.len(), 4);
+        c.select_channels(&[0, 2]).unwrap();
+        let enabled: Vec<bool> = c.channels.iter().map(|ch| ch.enabled).collect();
+        assert_eq!(enabled, vec![true, false, true, false]);
+        assert!(c.select_channels(&[4]).is_err());
+
+        // disabled channels the device does not have are ignored
+        c.resize(3).unwrap();
+        assert_eq!(c.channels.len(), 3);
+        c.channels[2].enabled = true;
+        match c.resize(2) {
+            Err(Error::ConfigError(e)) => assert!(e.starts_with("channel 2 is enabled"), "{}", e),
+            r => panic!("unexpected result {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_errors() {
+        let e = parse("[[channel]]\nbitrate = \"fast\"\n").unwrap_err();
+        assert!(e.contains("line 2"), "{}", e);
+
+        let e = parse(&FILE.replace("bitrate = 250000", "bitrate = 0")).unwrap_err();
+        assert_eq!(e, "bitrate must be greater than 0 at line 11");
+
+        assert!(parse("[profiles.x]\n").is_err());
+    }
+}
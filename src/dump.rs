@@ -1,66 +1,75 @@
 use crate::Error;
-use cantact::{Frame, Interface};
+use cantact::{Frame, Interface, TimeBase};
 use clap::ArgMatches;
 use log::info;
 
 use crate::config::Config;
+use crate::filter::Filter;
+use crate::format::{Format, Printer, StateTracker, Timestamps};
 use crate::helpers;
 
-fn print_frame(f: Frame) {
-    let ts = match f.timestamp {
-        Some(t) => format!("{:.6}\t", t.as_secs_f32()),
-        None => String::new(),
-    };
-
-    if f.err {
-        println!("{}  ch:{} error frame", ts, f.channel);
-    }
+pub fn cmd(matches: &ArgMatches) -> Result<(), Error> {
+    let flag = helpers::initialize_ctrlc()?;
+    let mut config = Config::read(matches)?;
 
-    let mut s = format!("{}  ch:{} {:03X}", ts, f.channel, f.can_id,);
+    let channels = helpers::parse_channels(matches)?;
 
-    s = if f.fd {
-        format!("{}   [{:02}]  ", s, f.data_len())
-    } else {
-        format!("{}   [{:01}]  ", s, f.data_len())
+    let filter = match matches.value_of("filter") {
+        Some(s) => Some(
+            Filter::parse(s)
+                .map_err(|e| Error::InvalidArgument(format!("invalid filter: {}", e)))?,
+        ),
+        None => None,
+    };
+    let format = match matches.value_of("format") {
+        Some(s) => Format::parse(s)
+            .ok_or_else(|| Error::InvalidArgument(String::from("invalid format value")))?,
+        None => Format::Human,
+    };
+    // candump compatible formats default to absolute timestamps
+    let timestamps = match matches.value_of("timestamp") {
+        Some(s) => Timestamps::parse(s)
+            .ok_or_else(|| Error::InvalidArgument(String::from("invalid timestamp value")))?,
+        None if format == Format::Human => Timestamps::Relative,
+        None => Timestamps::Absolute,
     };
-
-    for b in f.data.iter().take(f.data_len()) {
-        s = format!("{}{:02X} ", s, b);
-    }
-    println!("{}", s)
-}
-
-pub fn cmd(matches: &ArgMatches) -> Result<(), Error> {
-    let flag = helpers::initialize_ctrlc();
-    let mut config = Config::read();
-
-    let ch = helpers::parse_channel(matches)?;
-    match ch {
-        None => { /* no channel specified, follow config */ }
-        Some(ch) => {
-            // channel specified, disable all others
-            for n in 0..config.channels.len() {
-                if n != ch {
-                    config.channels[n].enabled = false;
-                }
-            }
-        }
-    }
-    info!("config: {:?}", config);
 
     // initialize the interface
     let mut i = Interface::new()?;
+    config.fit_to_interface(&i)?;
+    if let Some(channels) = channels {
+        // channels specified, disable all others
+        config.select_channels(&channels)?;
+    }
+    info!("config: {:?}", config);
     config.apply_to_interface(&mut i)?;
 
     // start the device
     info!("starting dump");
+    // frame timestamps are relative to the interface's time base
+    let base = TimeBase::now();
+    i.set_time_base(base);
+    let mut printer = Printer::new(format, timestamps, matches.is_present("ascii"));
+    printer.set_time_base(&base);
+    if let Some(header) = printer.header() {
+        println!("{}", header);
+    }
+    // changes of a controller's error state are shown as separate events in
+    // the human format, other formats are left for other tools to read
+    let mut states = StateTracker::default();
     i.start(move |f: Frame| {
-        print_frame(f);
-    })
-    .expect("failed to start device");
+        if filter.as_ref().is_none_or(|filter| filter.matches(&f)) {
+            println!("{}", printer.format(&f));
+        }
+        if let Some((old, new)) = states.update(&f) {
+            if format == Format::Human {
+                println!("  ch:{} state changed: {} -> {}", f.channel, old, new);
+            }
+        }
+    })?;
 
     helpers::wait_for_ctrlc(&flag);
 
-    i.stop().expect("failed to stop device");
+    i.stop()?;
     Ok(())
 }
@@ -0,0 +1,183 @@
+use crate::Error;
+use cantact::{Frame, Interface};
+use clap::ArgMatches;
+use crossbeam_channel::{unbounded, RecvTimeoutError};
+use log::info;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::time::{Duration, Instant};
+
+use crate::config::Config;
+use crate::format::{self, BusState, StateTracker, ERROR_KINDS};
+use crate::helpers;
+
+// how often the main loop checks for ctrl-c
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+// error frames of one channel, counted by kind
+#[derive(Debug, Default, Clone)]
+struct Tally {
+    errors: u64,
+    kinds: [u64; ERROR_KINDS.len()],
+}
+
+impl Tally {
+    fn add(&mut self, f: &Frame) {
+        self.errors += 1;
+        for kind in format::error_kinds(f) {
+            if let Some(n) = ERROR_KINDS.iter().position(|k| *k == kind) {
+                self.kinds[n] += 1;
+            }
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct ChannelErrors {
+    // since the last row was printed
+    interval: Tally,
+    total: Tally,
+    // the last error counters reported
+    counters: Option<(u8, u8)>,
+}
+
+fn width(name: &str) -> usize {
+    std::cmp::max(name.len(), 6)
+}
+
+fn header() -> String {
+    let mut s = String::from("    time  ch  errors");
+    for kind in ERROR_KINDS.iter() {
+        let _ = write!(s, "  {:>w$}", kind, w = width(kind));
+    }
+    s.push_str("  state           tx   rx");
+    s
+}
+
+fn row(time: &str, ch: u8, tally: &Tally, state: BusState, counters: Option<(u8, u8)>) -> String {
+    let mut s = format!("{:>8}  {:>2}  {:>6}", time, ch, tally.errors);
+    for (kind, n) in ERROR_KINDS.iter().zip(tally.kinds.iter()) {
+        let _ = write!(s, "  {:>w$}", n, w = width(kind));
+    }
+    let _ = write!(s, "  {:13}", state.to_string());
+    match counters {
+        Some((tx, rx)) => {
+            let _ = write!(s, "  {:>3}  {:>3}", tx, rx);
+        }
+        None => s.push_str("    -    -"),
+    }
+    s
+}
+
+pub fn cmd(matches: &ArgMatches) -> Result<(), Error> {
+    let flag = helpers::initialize_ctrlc()?;
+    let mut config = Config::read(matches)?;
+    let channels = helpers::parse_channels(matches)?;
+    let interval = match matches.value_of("interval") {
+        Some(s) => helpers::parse_duration(s)?,
+        None => Duration::from_secs(1),
+    };
+    if interval == Duration::from_secs(0) {
+        return Err(Error::InvalidArgument(String::from(
+            "interval must be greater than 0",
+        )));
+    }
+
+    // initialize the interface
+    let mut i = Interface::new()?;
+    config.fit_to_interface(&i)?;
+    if let Some(channels) = channels {
+        config.select_channels(&channels)?;
+    }
+    info!("config: {:?}", config);
+    config.apply_to_interface(&mut i)?;
+
+    // a row is printed for every channel listened to, even without errors
+    let mut tallies: BTreeMap<u8, ChannelErrors> = config
+        .channels
+        .iter()
+        .enumerate()
+        .filter(|(_, c)| c.enabled)
+        .map(|(n, _)| (n as u8, ChannelErrors::default()))
+        .collect();
+    let mut states = StateTracker::default();
+
+    let (send, recv) = unbounded();
+    i.start(move |f: Frame| {
+        if f.err {
+            let _ = send.send(f);
+        }
+    })?;
+
+    println!("{}", header());
+    let start = Instant::now();
+    let mut rows = 1;
+    while !helpers::check_ctrlc(&flag) {
+        let deadline = start + interval * rows;
+        let timeout = std::cmp::min(
+            deadline.saturating_duration_since(Instant::now()),
+            POLL_INTERVAL,
+        );
+        match recv.recv_timeout(timeout) {
+            Ok(f) => {
+                if let Some((old, new)) = states.update(&f) {
+                    println!("  ch:{} state changed: {} -> {}", f.channel, old, new);
+                }
+                let c = tallies.entry(f.channel).or_default();
+                c.interval.add(&f);
+                c.total.add(&f);
+                if let Some(counters) = format::error_counters(&f) {
+                    c.counters = Some(counters);
+                }
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+
+        if Instant::now() >= deadline {
+            let time = format!("{:.1}", (interval * rows).as_secs_f64());
+            for (ch, c) in tallies.iter_mut() {
+                println!(
+                    "{}",
+                    row(&time, *ch, &c.interval, states.state(*ch), c.counters)
+                );
+                c.interval = Tally::default();
+            }
+            rows += 1;
+        }
+    }
+
+    i.stop()?;
+    println!("total:");
+    for (ch, c) in tallies.iter() {
+        println!("{}", row("", *ch, &c.total, states.state(*ch), c.counters));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tally() {
+        let mut f = Frame {
+            can_id: 0x0008 | 0x0020 | 0x0200,
+            err: true,
+            can_dlc: 8,
+            ..Frame::default()
+        };
+        f.data[3] = 0x08;
+        f.data[6] = 100;
+        let mut t = Tally::default();
+        t.add(&f);
+        t.add(&f);
+        assert_eq!(t.errors, 2);
+        // crc and ack
+        assert_eq!(t.kinds, [0, 0, 0, 2, 2, 0, 0, 0]);
+
+        let line = row("1.0", 0, &t, BusState::Warning, Some((100, 0)));
+        assert_eq!(line.len(), header().len());
+        assert!(line.ends_with("error warning  100    0"));
+    }
+}
@@ -0,0 +1,518 @@
+//! Frame filter expressions, shared by commands that select frames.
+//!
+//! An expression compares frame fields with numbers, for example
+//! `id in 0x100..0x1ff && ext == false && data[0] == 0x10 && dlc > 4`.
+//!
+//! - fields: `id`, `ext`, `rtr`, `fd`, `brs`, `esi`, `err`, `loopback`,
+//!   `dlc`, `len`, `channel` and `data[n]`
+//! - numbers: decimal, hex (`0x`) or binary (`0b`), and `true`/`false`
+//! - comparisons: `==`, `!=`, `<`, `<=`, `>`, `>=`, and `in a..b`, where the
+//!   range includes both ends
+//! - masks: `id & 0x700 == 0x100`
+//! - logic: `&&`, `||`, `!` and parentheses
+//!
+//! A field or number on its own is true if it is not zero, so `ext` and
+//! `!rtr` are valid expressions. `data[n]` past the end of the frame's data
+//! makes any comparison using it false.
+//!
+//! The older `id:mask` form, e.g. `0x123:0x7FF`, is also accepted.
+
+use cantact::Frame;
+use std::fmt;
+
+/// An error found while parsing a filter expression.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseError {
+    /// Position in the expression, in characters.
+    pub pos: usize,
+    /// Description of the problem.
+    pub msg: String,
+}
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at column {}", self.msg, self.pos + 1)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Field {
+    Id,
+    Ext,
+    Rtr,
+    Fd,
+    Brs,
+    Esi,
+    Err,
+    Loopback,
+    Dlc,
+    Len,
+    Channel,
+    Data(usize),
+}
+impl Field {
+    fn value(self, f: &Frame) -> Option<u64> {
+        let v = match self {
+            Field::Id => f.can_id as u64,
+            Field::Ext => f.ext as u64,
+            Field::Rtr => f.rtr as u64,
+            Field::Fd => f.fd as u64,
+            Field::Brs => f.brs as u64,
+            Field::Esi => f.esi as u64,
+            Field::Err => f.err as u64,
+            Field::Loopback => f.loopback as u64,
+            Field::Dlc => f.can_dlc as u64,
+            Field::Len => f.data_len() as u64,
+            Field::Channel => f.channel as u64,
+            Field::Data(n) if n < f.data_len() => f.data[n] as u64,
+            Field::Data(_) => return None,
+        };
+        Some(v)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum CmpOp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    Field(Field),
+    Number(u64),
+    Mask(Box<Value>, Box<Value>),
+}
+impl Value {
+    fn eval(&self, f: &Frame) -> Option<u64> {
+        match self {
+            Value::Field(field) => field.value(f),
+            Value::Number(n) => Some(*n),
+            Value::Mask(a, b) => Some(a.eval(f)? & b.eval(f)?),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Expr {
+    Value(Value),
+    Cmp(Value, CmpOp, Value),
+    In(Value, Value, Value),
+    Not(Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+}
+impl Expr {
+    fn eval(&self, f: &Frame) -> bool {
+        match self {
+            Expr::Value(v) => v.eval(f).is_some_and(|v| v != 0),
+            Expr::Cmp(a, op, b) => match (a.eval(f), b.eval(f)) {
+                (Some(a), Some(b)) => match op {
+                    CmpOp::Eq => a == b,
+                    CmpOp::Ne => a != b,
+                    CmpOp::Lt => a < b,
+                    CmpOp::Le => a <= b,
+                    CmpOp::Gt => a > b,
+                    CmpOp::Ge => a >= b,
+                },
+                _ => false,
+            },
+            Expr::In(v, start, end) => match (v.eval(f), start.eval(f), end.eval(f)) {
+                (Some(v), Some(start), Some(end)) => (start..=end).contains(&v),
+                _ => false,
+            },
+            Expr::Not(e) => !e.eval(f),
+            Expr::And(a, b) => a.eval(f) && b.eval(f),
+            Expr::Or(a, b) => a.eval(f) || b.eval(f),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(u64),
+    Ident(String),
+    Cmp(CmpOp),
+    And,
+    Or,
+    Not,
+    Mask,
+    Range,
+    LParen,
+    RParen,
+    LBracket,
+    RBracket,
+    End,
+}
+
+fn tokenize(s: &str) -> Result<Vec<(usize, Token)>, ParseError> {
+    let chars: Vec<char> = s.chars().collect();
+    let mut tokens = Vec::new();
+    let mut pos = 0;
+    while pos < chars.len() {
+        let c = chars[pos];
+        if c.is_whitespace() {
+            pos += 1;
+            continue;
+        }
+        let start = pos;
+        let next = chars.get(pos + 1).copied();
+
+        if c.is_ascii_digit() {
+            let (radix, digits_start) = match (c, next) {
+                ('0', Some('x')) | ('0', Some('X')) => (16, pos + 2),
+                ('0', Some('b')) | ('0', Some('B')) => (2, pos + 2),
+                _ => (10, pos),
+            };
+            pos = digits_start;
+            while pos < chars.len() && (chars[pos].is_ascii_alphanumeric() || chars[pos] == '_') {
+                pos += 1;
+            }
+            let digits: String = chars[digits_start..pos]
+                .iter()
+                .filter(|c| **c != '_')
+                .collect();
+            let n = u64::from_str_radix(&digits, radix).map_err(|_| ParseError {
+                pos: start,
+                msg: format!(
+                    "invalid number '{}'",
+                    chars[start..pos].iter().collect::<String>()
+                ),
+            })?;
+            tokens.push((start, Token::Number(n)));
+            continue;
+        }
+        if c.is_ascii_alphabetic() || c == '_' {
+            while pos < chars.len() && (chars[pos].is_ascii_alphanumeric() || chars[pos] == '_') {
+                pos += 1;
+            }
+            let ident: String = chars[start..pos].iter().collect();
+            tokens.push((start, Token::Ident(ident)));
+            continue;
+        }
+
+        let (token, len) = match (c, next) {
+            ('=', Some('=')) => (Token::Cmp(CmpOp::Eq), 2),
+            ('!', Some('=')) => (Token::Cmp(CmpOp::Ne), 2),
+            ('<', Some('=')) => (Token::Cmp(CmpOp::Le), 2),
+            ('>', Some('=')) => (Token::Cmp(CmpOp::Ge), 2),
+            ('&', Some('&')) => (Token::And, 2),
+            ('|', Some('|')) => (Token::Or, 2),
+            ('.', Some('.')) => (Token::Range, 2),
+            ('<', _) => (Token::Cmp(CmpOp::Lt), 1),
+            ('>', _) => (Token::Cmp(CmpOp::Gt), 1),
+            ('!', _) => (Token::Not, 1),
+            ('&', _) => (Token::Mask, 1),
+            ('(', _) => (Token::LParen, 1),
+            (')', _) => (Token::RParen, 1),
+            ('[', _) => (Token::LBracket, 1),
+            (']', _) => (Token::RBracket, 1),
+            _ => {
+                return Err(ParseError {
+                    pos,
+                    msg: format!("unexpected character '{}'", c),
+                })
+            }
+        };
+        tokens.push((start, token));
+        pos += len;
+    }
+    tokens.push((chars.len(), Token::End));
+    Ok(tokens)
+}
+
+// recursive descent parser, lowest precedence first:
+// or: and ('||' and)*
+// and: unary ('&&' unary)*
+// unary: '!' unary | '(' or ')' | comparison
+// comparison: value [cmp value | 'in' value '..' value]
+// value: term ('&' term)*
+// term: number | field | 'true' | 'false'
+struct Parser {
+    tokens: Vec<(usize, Token)>,
+    next: usize,
+}
+impl Parser {
+    fn peek(&self) -> &Token {
+        &self.tokens[self.next].1
+    }
+
+    fn pos(&self) -> usize {
+        self.tokens[self.next].0
+    }
+
+    fn advance(&mut self) -> Token {
+        let t = self.tokens[self.next].1.clone();
+        if t != Token::End {
+            self.next += 1;
+        }
+        t
+    }
+
+    fn error<T>(&self, msg: &str) -> Result<T, ParseError> {
+        Err(ParseError {
+            pos: self.pos(),
+            msg: String::from(msg),
+        })
+    }
+
+    fn expect(&mut self, t: Token, msg: &str) -> Result<(), ParseError> {
+        if *self.peek() == t {
+            self.advance();
+            Ok(())
+        } else {
+            self.error(msg)
+        }
+    }
+
+    fn or(&mut self) -> Result<Expr, ParseError> {
+        let mut e = self.and()?;
+        while *self.peek() == Token::Or {
+            self.advance();
+            e = Expr::Or(Box::new(e), Box::new(self.and()?));
+        }
+        Ok(e)
+    }
+
+    fn and(&mut self) -> Result<Expr, ParseError> {
+        let mut e = self.unary()?;
+        while *self.peek() == Token::And {
+            self.advance();
+            e = Expr::And(Box::new(e), Box::new(self.unary()?));
+        }
+        Ok(e)
+    }
+
+    fn unary(&mut self) -> Result<Expr, ParseError> {
+        match self.peek() {
+            Token::Not => {
+                self.advance();
+                Ok(Expr::Not(Box::new(self.unary()?)))
+            }
+            Token::LParen => {
+                self.advance();
+                let e = self.or()?;
+                self.expect(Token::RParen, "expected ')'")?;
+                Ok(e)
+            }
+            _ => self.comparison(),
+        }
+    }
+
+    fn comparison(&mut self) -> Result<Expr, ParseError> {
+        let a = self.value()?;
+        match self.peek().clone() {
+            Token::Cmp(op) => {
+                self.advance();
+                Ok(Expr::Cmp(a, op, self.value()?))
+            }
+            Token::Ident(s) if s == "in" => {
+                self.advance();
+                let start = self.value()?;
+                self.expect(Token::Range, "expected '..' in range")?;
+                let end = self.value()?;
+                Ok(Expr::In(a, start, end))
+            }
+            _ => Ok(Expr::Value(a)),
+        }
+    }
+
+    fn value(&mut self) -> Result<Value, ParseError> {
+        let mut v = self.term()?;
+        while *self.peek() == Token::Mask {
+            self.advance();
+            v = Value::Mask(Box::new(v), Box::new(self.term()?));
+        }
+        Ok(v)
+    }
+
+    fn term(&mut self) -> Result<Value, ParseError> {
+        let pos = self.pos();
+        match self.advance() {
+            Token::Number(n) => Ok(Value::Number(n)),
+            Token::Ident(s) => {
+                let field = match s.as_str() {
+                    "true" => return Ok(Value::Number(1)),
+                    "false" => return Ok(Value::Number(0)),
+                    "id" => Field::Id,
+                    "ext" => Field::Ext,
+                    "rtr" => Field::Rtr,
+                    "fd" => Field::Fd,
+                    "brs" => Field::Brs,
+                    "esi" => Field::Esi,
+                    "err" => Field::Err,
+                    "loopback" => Field::Loopback,
+                    "dlc" => Field::Dlc,
+                    "len" => Field::Len,
+                    "channel" => Field::Channel,
+                    "data" => {
+                        self.expect(Token::LBracket, "expected '[' after data")?;
+                        let n = match self.advance() {
+                            Token::Number(n) if n < 64 => n as usize,
+                            _ => {
+                                return Err(ParseError {
+                                    pos: self.tokens[self.next - 1].0,
+                                    msg: String::from("expected data index from 0 to 63"),
+                                })
+                            }
+                        };
+                        self.expect(Token::RBracket, "expected ']'")?;
+                        Field::Data(n)
+                    }
+                    _ => {
+                        return Err(ParseError {
+                            pos,
+                            msg: format!("unknown field '{}'", s),
+                        })
+                    }
+                };
+                Ok(Value::Field(field))
+            }
+            Token::End => Err(ParseError {
+                pos,
+                msg: String::from("unexpected end of expression"),
+            }),
+            _ => Err(ParseError {
+                pos,
+                msg: String::from("expected a field or number"),
+            }),
+        }
+    }
+}
+
+// parse the legacy id:mask form, e.g. 0x123:0x7FF
+fn parse_id_mask(s: &str) -> Option<Expr> {
+    let mut parts = s.trim().split(':');
+    let (id, mask) = (parts.next()?, parts.next()?);
+    if parts.next().is_some() {
+        return None;
+    }
+    let parse = |s: &str| {
+        let s = s.trim();
+        let digits = s.trim_start_matches("0x").trim_start_matches("0X");
+        u32::from_str_radix(digits, 16).ok()
+    };
+    let (id, mask) = (parse(id)?, parse(mask)?);
+    Some(Expr::Cmp(
+        Value::Mask(
+            Box::new(Value::Field(Field::Id)),
+            Box::new(Value::Number(mask as u64)),
+        ),
+        CmpOp::Eq,
+        Value::Number((id & mask) as u64),
+    ))
+}
+
+/// A parsed filter expression.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Filter {
+    expr: Expr,
+}
+
+impl Filter {
+    /// Parse a filter expression.
+    pub fn parse(s: &str) -> Result<Filter, ParseError> {
+        if let Some(expr) = parse_id_mask(s) {
+            return Ok(Filter { expr });
+        }
+
+        let mut p = Parser {
+            tokens: tokenize(s)?,
+            next: 0,
+        };
+        let expr = p.or()?;
+        if *p.peek() != Token::End {
+            return p.error("unexpected token");
+        }
+        Ok(Filter { expr })
+    }
+
+    /// Returns true if the frame matches the expression.
+    pub fn matches(&self, f: &Frame) -> bool {
+        self.expr.eval(f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(can_id: u32, data: &[u8]) -> Frame {
+        let mut f = Frame {
+            can_id,
+            can_dlc: data.len() as u8,
+            ..Frame::default()
+        };
+        f.data[..data.len()].copy_from_slice(data);
+        f
+    }
+
+    fn matches(expr: &str, f: &Frame) -> bool {
+        Filter::parse(expr).unwrap().matches(f)
+    }
+
+    #[test]
+    fn test_comparisons() {
+        let f = frame(0x123, &[0x10, 0x20, 0x30, 0x40, 0x50]);
+        assert!(matches("id == 0x123", &f));
+        assert!(!matches("id != 291 || false", &f));
+        assert!(matches(
+            "id in 0x100..0x1ff && ext == false && data[0] == 0x10 && dlc > 4",
+            &f
+        ));
+        assert!(matches("id in 0x100..0x123", &f));
+        assert!(!matches("id in 0x124..0x1ff", &f));
+        assert!(matches("len >= 5 && len <= 5 && dlc < 6", &f));
+        assert!(matches("id & 0x700 == 0x100", &f));
+        assert!(matches("data[1] & 0b11110000 == 0x20", &f));
+        assert!(matches("channel == 0 && !ext && !(rtr || fd)", &f));
+    }
+
+    #[test]
+    fn test_precedence() {
+        let f = frame(0x100, &[]);
+        // && binds tighter than ||
+        assert!(matches("id == 0x100 || id == 0x200 && ext", &f));
+        assert!(!matches("(id == 0x100 || id == 0x200) && ext", &f));
+        assert!(matches("!ext && !rtr", &f));
+    }
+
+    #[test]
+    fn test_missing_data() {
+        let f = frame(0x100, &[0x01]);
+        assert!(!matches("data[1] == 0", &f));
+        assert!(!matches("data[1] != 0", &f));
+        assert!(matches("!(data[1] == 0)", &f));
+    }
+
+    #[test]
+    fn test_id_mask() {
+        let filter = Filter::parse("0x123:0x7FF").unwrap();
+        assert!(filter.matches(&frame(0x123, &[])));
+        assert!(!filter.matches(&frame(0x124, &[])));
+
+        let filter = Filter::parse("100:700").unwrap();
+        assert!(filter.matches(&frame(0x1AB, &[])));
+        assert!(!filter.matches(&frame(0x2AB, &[])));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let err = |s: &str| Filter::parse(s).unwrap_err();
+        assert_eq!(err("id == ").msg, "unexpected end of expression");
+        assert_eq!(err("idd == 1").msg, "unknown field 'idd'");
+        assert_eq!(err("idd == 1").pos, 0);
+        assert_eq!(err("id == 0x12g").pos, 6);
+        assert_eq!(err("(id == 1").msg, "expected ')'");
+        assert_eq!(err("id in 1 2").msg, "expected '..' in range");
+        assert_eq!(err("data[64] == 1").pos, 5);
+        assert_eq!(err("id == 1 id").pos, 8);
+        assert_eq!(
+            err("id # 1").to_string(),
+            "unexpected character '#' at column 4"
+        );
+    }
+}
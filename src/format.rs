@@ -0,0 +1,982 @@
+//! Text formats for frames, used to display frames and to read frame logs.
+
+use cantact::{Frame, TimeBase};
+use std::collections::BTreeMap;
+use std::fmt::{self, Write};
+use std::io::{self, BufRead};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::helpers;
+
+// error frames have this flag set in their identifier in candump logs
+const CAN_ERR_FLAG: u32 = 0x2000_0000;
+
+// error classes, in the identifier of error frames
+const CAN_ERR_TX_TIMEOUT: u32 = 0x0001;
+const CAN_ERR_LOSTARB: u32 = 0x0002;
+const CAN_ERR_CRTL: u32 = 0x0004;
+const CAN_ERR_PROT: u32 = 0x0008;
+const CAN_ERR_TRX: u32 = 0x0010;
+const CAN_ERR_ACK: u32 = 0x0020;
+const CAN_ERR_BUSOFF: u32 = 0x0040;
+const CAN_ERR_BUSERROR: u32 = 0x0080;
+const CAN_ERR_RESTARTED: u32 = 0x0100;
+// error counters in data[6] and data[7] are valid
+const CAN_ERR_CNT: u32 = 0x0200;
+
+// controller problems, in data[1]
+const CAN_ERR_CRTL_OVERFLOW: u8 = 0x03;
+const CAN_ERR_CRTL_WARNING: u8 = 0x0C;
+const CAN_ERR_CRTL_PASSIVE: u8 = 0x30;
+const CAN_ERR_CRTL_ACTIVE: u8 = 0x40;
+const CAN_ERR_CRTL_NAMES: [(u8, &str); 7] = [
+    (0x01, "rx overflow"),
+    (0x02, "tx overflow"),
+    (0x04, "rx error warning"),
+    (0x08, "tx error warning"),
+    (0x10, "rx error passive"),
+    (0x20, "tx error passive"),
+    (0x40, "back to error active"),
+];
+
+// protocol violations, in data[2]
+const CAN_ERR_PROT_FORM: u8 = 0x02;
+const CAN_ERR_PROT_STUFF: u8 = 0x04;
+const CAN_ERR_PROT_BIT: u8 = 0x19;
+const CAN_ERR_PROT_NAMES: [(u8, &str); 8] = [
+    (0x01, "bit error"),
+    (0x02, "form error"),
+    (0x04, "stuff error"),
+    (0x08, "dominant bit error"),
+    (0x10, "recessive bit error"),
+    (0x20, "overload"),
+    (0x40, "active error announcement"),
+    (0x80, "tx error"),
+];
+
+// location of protocol violations, in data[3]
+const CAN_ERR_PROT_LOC_CRC: [u8; 2] = [0x08, 0x18];
+const CAN_ERR_PROT_LOC_ACK: [u8; 2] = [0x19, 0x1B];
+const CAN_ERR_PROT_LOC_NAMES: [(u8, &str); 19] = [
+    (0x03, "start of frame"),
+    (0x02, "identifier"),
+    (0x06, "identifier"),
+    (0x04, "SRR bit"),
+    (0x05, "IDE bit"),
+    (0x07, "identifier"),
+    (0x0F, "identifier"),
+    (0x0E, "identifier"),
+    (0x0C, "RTR bit"),
+    (0x0D, "reserved bit"),
+    (0x09, "reserved bit"),
+    (0x0B, "DLC"),
+    (0x0A, "data field"),
+    (0x08, "CRC sequence"),
+    (0x18, "CRC delimiter"),
+    (0x19, "ACK slot"),
+    (0x1B, "ACK delimiter"),
+    (0x1A, "end of frame"),
+    (0x12, "intermission"),
+];
+
+/// Kinds of error counted by `error_kinds`.
+pub const ERROR_KINDS: [&str; 8] = [
+    "bit",
+    "stuff",
+    "form",
+    "crc",
+    "ack",
+    "arbitration",
+    "overflow",
+    "bus-off",
+];
+
+/// Layout used to print frames.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    /// Aligned columns for reading on a terminal.
+    Human,
+    /// candump log lines, e.g. `(1600000000.000000) can0 123#DEADBEEF`.
+    Log,
+    /// One JSON object per line.
+    Json,
+    /// Comma separated values, with a header line.
+    Csv,
+}
+impl Format {
+    pub fn parse(s: &str) -> Option<Format> {
+        match s {
+            "human" => Some(Format::Human),
+            "log" => Some(Format::Log),
+            "json" => Some(Format::Json),
+            "csv" => Some(Format::Csv),
+            _ => None,
+        }
+    }
+}
+
+/// How frame timestamps are printed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Timestamps {
+    /// Seconds since the Unix epoch.
+    Absolute,
+    /// Seconds since the interface was started.
+    Relative,
+    /// Seconds since the previous frame.
+    Delta,
+    /// Timestamps are not printed.
+    None,
+}
+impl Timestamps {
+    pub fn parse(s: &str) -> Option<Timestamps> {
+        match s {
+            "absolute" => Some(Timestamps::Absolute),
+            "relative" => Some(Timestamps::Relative),
+            "delta" => Some(Timestamps::Delta),
+            "none" => Some(Timestamps::None),
+            _ => None,
+        }
+    }
+}
+
+fn format_time(d: Duration) -> String {
+    format!("{}.{:06}", d.as_secs(), d.subsec_micros())
+}
+
+fn hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02X}", b)).collect()
+}
+
+fn ascii(data: &[u8]) -> String {
+    data.iter()
+        .map(|b| match *b {
+            0x20..=0x7E => *b as char,
+            _ => '.',
+        })
+        .collect()
+}
+
+fn flag_names<T: Copy + Into<u32>>(value: T, flags: &[(T, &'static str)]) -> Vec<&'static str> {
+    flags
+        .iter()
+        .filter(|(bit, _)| value.into() & (*bit).into() != 0)
+        .map(|(_, name)| *name)
+        .collect()
+}
+
+/// Describe the contents of an error frame.
+pub fn describe_error(f: &Frame) -> String {
+    let class = f.can_id;
+    let mut parts = Vec::new();
+    if class & CAN_ERR_TX_TIMEOUT != 0 {
+        parts.push(String::from("tx timeout"));
+    }
+    if class & CAN_ERR_LOSTARB != 0 {
+        match f.data[0] {
+            0 => parts.push(String::from("lost arbitration")),
+            bit => parts.push(format!("lost arbitration at bit {}", bit)),
+        }
+    }
+    if class & CAN_ERR_CRTL != 0 {
+        let names = flag_names(f.data[1], &CAN_ERR_CRTL_NAMES);
+        if names.is_empty() {
+            parts.push(String::from("controller problem"));
+        }
+        parts.extend(names.into_iter().map(String::from));
+    }
+    // some devices only set the bus error class for protocol violations
+    let prot = class & (CAN_ERR_PROT | CAN_ERR_BUSERROR) != 0;
+    if prot && (class & CAN_ERR_PROT != 0 || f.data[2] != 0 || f.data[3] != 0) {
+        let mut names = flag_names(f.data[2], &CAN_ERR_PROT_NAMES);
+        if CAN_ERR_PROT_LOC_CRC.contains(&f.data[3]) {
+            names.insert(0, "CRC error");
+        } else if names.is_empty() {
+            names.push("protocol violation");
+        }
+        let mut s = names.join(", ");
+        if let Some((_, loc)) = CAN_ERR_PROT_LOC_NAMES.iter().find(|(l, _)| *l == f.data[3]) {
+            let _ = write!(s, " in {}", loc);
+        }
+        parts.push(s);
+    } else if class & CAN_ERR_BUSERROR != 0 {
+        parts.push(String::from("bus error"));
+    }
+    if class & CAN_ERR_TRX != 0 {
+        parts.push(String::from("transceiver problem"));
+    }
+    if class & CAN_ERR_ACK != 0 {
+        parts.push(String::from("ACK missing"));
+    }
+    if class & CAN_ERR_BUSOFF != 0 {
+        parts.push(String::from("bus-off"));
+    }
+    if class & CAN_ERR_RESTARTED != 0 {
+        parts.push(String::from("controller restarted"));
+    }
+
+    let mut s = if parts.is_empty() {
+        String::from("unknown error")
+    } else {
+        parts.join(", ")
+    };
+    if let Some((tx, rx)) = error_counters(f) {
+        let _ = write!(s, " (tx errors: {}, rx errors: {})", tx, rx);
+    }
+    s
+}
+
+/// The kinds of error, from `ERROR_KINDS`, reported by an error frame.
+pub fn error_kinds(f: &Frame) -> Vec<&'static str> {
+    let class = f.can_id;
+    let (prot, loc) = if class & (CAN_ERR_PROT | CAN_ERR_BUSERROR) != 0 {
+        (f.data[2], f.data[3])
+    } else {
+        (0, 0)
+    };
+    let crtl = if class & CAN_ERR_CRTL != 0 {
+        f.data[1]
+    } else {
+        0
+    };
+    let kinds = [
+        prot & CAN_ERR_PROT_BIT != 0,
+        prot & CAN_ERR_PROT_STUFF != 0,
+        prot & CAN_ERR_PROT_FORM != 0,
+        CAN_ERR_PROT_LOC_CRC.contains(&loc),
+        class & CAN_ERR_ACK != 0 || CAN_ERR_PROT_LOC_ACK.contains(&loc),
+        class & CAN_ERR_LOSTARB != 0,
+        crtl & CAN_ERR_CRTL_OVERFLOW != 0,
+        class & CAN_ERR_BUSOFF != 0,
+    ];
+    ERROR_KINDS
+        .iter()
+        .zip(kinds.iter())
+        .filter(|(_, k)| **k)
+        .map(|(name, _)| *name)
+        .collect()
+}
+
+/// The transmit and receive error counters reported by an error frame, if
+/// it reports them.
+pub fn error_counters(f: &Frame) -> Option<(u8, u8)> {
+    if f.can_id & CAN_ERR_CNT != 0 {
+        Some((f.data[6], f.data[7]))
+    } else {
+        None
+    }
+}
+
+/// Error state of a CAN controller, from error frames.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum BusState {
+    /// Error counters are below 96, the normal state.
+    Active,
+    /// An error counter has reached 96.
+    Warning,
+    /// An error counter has reached 128, the controller no longer sends
+    /// active error flags.
+    Passive,
+    /// The transmit error counter has passed 255, the controller has left
+    /// the bus.
+    BusOff,
+}
+impl fmt::Display for BusState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            BusState::Active => "error active",
+            BusState::Warning => "error warning",
+            BusState::Passive => "error passive",
+            BusState::BusOff => "bus-off",
+        };
+        f.write_str(s)
+    }
+}
+
+/// The controller state reported by an error frame, if it reports one.
+pub fn error_state(f: &Frame) -> Option<BusState> {
+    let class = f.can_id;
+    let crtl = if class & CAN_ERR_CRTL != 0 {
+        f.data[1]
+    } else {
+        0
+    };
+    if class & CAN_ERR_BUSOFF != 0 {
+        Some(BusState::BusOff)
+    } else if crtl & CAN_ERR_CRTL_PASSIVE != 0 {
+        Some(BusState::Passive)
+    } else if crtl & CAN_ERR_CRTL_WARNING != 0 {
+        Some(BusState::Warning)
+    } else if crtl & CAN_ERR_CRTL_ACTIVE != 0 || class & CAN_ERR_RESTARTED != 0 {
+        Some(BusState::Active)
+    } else {
+        error_counters(f).map(|(tx, rx)| match std::cmp::max(tx, rx) {
+            128..=255 => BusState::Passive,
+            96..=127 => BusState::Warning,
+            _ => BusState::Active,
+        })
+    }
+}
+
+/// Tracks the error state of each channel from received error frames.
+#[derive(Debug, Default)]
+pub struct StateTracker {
+    states: BTreeMap<u8, BusState>,
+}
+impl StateTracker {
+    /// Update the state of the frame's channel. Returns the previous and the
+    /// new state if the state changed. Channels start in the active state.
+    pub fn update(&mut self, f: &Frame) -> Option<(BusState, BusState)> {
+        if !f.err {
+            return None;
+        }
+        let new = error_state(f)?;
+        let old = self
+            .states
+            .insert(f.channel, new)
+            .unwrap_or(BusState::Active);
+        if old != new {
+            Some((old, new))
+        } else {
+            None
+        }
+    }
+
+    /// The current state of a channel.
+    pub fn state(&self, channel: u8) -> BusState {
+        self.states
+            .get(&channel)
+            .copied()
+            .unwrap_or(BusState::Active)
+    }
+}
+
+/// Formats frames as text.
+pub struct Printer {
+    format: Format,
+    timestamps: Timestamps,
+    ascii: bool,
+    // time since the epoch when the interface was started
+    start: Duration,
+    last: Option<Duration>,
+}
+
+impl Printer {
+    /// Create a printer for frames from an interface started now.
+    pub fn new(format: Format, timestamps: Timestamps, ascii: bool) -> Printer {
+        Printer {
+            format,
+            timestamps,
+            ascii,
+            start: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default(),
+            last: None,
+        }
+    }
+
+    /// Print absolute timestamps relative to `base`, the time base of the
+    /// interface the frames are from.
+    pub fn set_time_base(&mut self, base: &TimeBase) {
+        self.start = base.since_epoch(Duration::from_secs(0));
+    }
+
+    /// Returns a line to print before any frames, if the format has one.
+    pub fn header(&self) -> Option<String> {
+        match self.format {
+            Format::Csv => {
+                let mut s = String::from(CSV_HEADER);
+                if self.ascii {
+                    s.push_str(",ascii");
+                }
+                Some(s)
+            }
+            _ => None,
+        }
+    }
+
+    fn timestamp(&mut self, f: &Frame) -> Option<String> {
+        let t = f.timestamp?;
+        let ts = match self.timestamps {
+            Timestamps::Absolute => self.start + t,
+            Timestamps::Relative => t,
+            Timestamps::Delta => t.saturating_sub(self.last.unwrap_or(t)),
+            Timestamps::None => return None,
+        };
+        self.last = Some(t);
+        Some(format_time(ts))
+    }
+
+    /// Format a frame as a single line, without a line ending.
+    pub fn format(&mut self, f: &Frame) -> String {
+        let ts = self.timestamp(f);
+        let data = &f.data[..if f.rtr { 0 } else { f.data_len() }];
+        match self.format {
+            Format::Human => self.human(f, ts, data),
+            Format::Log => self.log(f, ts, data),
+            Format::Json => self.json(f, ts, data),
+            Format::Csv => self.csv(f, ts, data),
+        }
+    }
+
+    fn human(&self, f: &Frame, ts: Option<String>, data: &[u8]) -> String {
+        let mut s = match ts {
+            Some(ts) => format!("{}\t  ch:{} ", ts, f.channel),
+            None => format!("  ch:{} ", f.channel),
+        };
+        if f.err {
+            let _ = write!(s, "error frame: {}", describe_error(f));
+            return s;
+        }
+
+        if f.ext {
+            let _ = write!(s, "{:08X}", f.can_id);
+        } else {
+            let _ = write!(s, "{:03X}", f.can_id);
+        }
+        if f.fd {
+            let _ = write!(s, "   [{:02}]  ", f.data_len());
+        } else {
+            let _ = write!(s, "   [{:01}]  ", f.data_len());
+        }
+        if f.rtr {
+            s.push_str("remote request");
+        }
+        for b in data {
+            let _ = write!(s, "{:02X} ", b);
+        }
+        if self.ascii && !data.is_empty() {
+            let _ = write!(s, " '{}'", ascii(data));
+        }
+        s
+    }
+
+    fn log(&self, f: &Frame, ts: Option<String>, data: &[u8]) -> String {
+        let mut s = match ts {
+            Some(ts) => format!("({}) ", ts),
+            None => String::new(),
+        };
+        let _ = write!(s, "can{} ", f.channel);
+        if f.err {
+            let _ = write!(s, "{:08X}#{}", f.can_id | CAN_ERR_FLAG, hex(&f.data[..8]));
+        } else {
+            if f.ext {
+                let _ = write!(s, "{:08X}", f.can_id);
+            } else {
+                let _ = write!(s, "{:03X}", f.can_id);
+            }
+            if f.fd {
+                let flags = (f.brs as u8) | (f.esi as u8) << 1;
+                let _ = write!(s, "##{:X}{}", flags, hex(data));
+            } else if f.rtr {
+                let _ = write!(s, "#R{}", f.can_dlc);
+            } else {
+                let _ = write!(s, "#{}", hex(data));
+            }
+        }
+        s
+    }
+
+    fn json(&self, f: &Frame, ts: Option<String>, data: &[u8]) -> String {
+        let mut s = String::from("{");
+        if let Some(ts) = ts {
+            let _ = write!(s, "\"timestamp\":{},", ts);
+        }
+        let _ = write!(
+            s,
+            "\"channel\":{},\"id\":{},\"ext\":{},\"rtr\":{},\"fd\":{},\"brs\":{},\"esi\":{},\"err\":{},\"dlc\":{},\"data\":\"{}\"",
+            f.channel, f.can_id, f.ext, f.rtr, f.fd, f.brs, f.esi, f.err, f.can_dlc, hex(data)
+        );
+        if self.ascii {
+            let escaped = ascii(data).replace('\\', "\\\\").replace('"', "\\\"");
+            let _ = write!(s, ",\"ascii\":\"{}\"", escaped);
+        }
+        if f.err {
+            let _ = write!(s, ",\"error\":\"{}\"", describe_error(f));
+        }
+        s.push('}');
+        s
+    }
+
+    fn csv(&self, f: &Frame, ts: Option<String>, data: &[u8]) -> String {
+        let mut s = format!(
+            "{},{},{:X},{},{},{},{},{},{},{},{}",
+            ts.unwrap_or_default(),
+            f.channel,
+            f.can_id,
+            f.ext as u8,
+            f.rtr as u8,
+            f.fd as u8,
+            f.brs as u8,
+            f.esi as u8,
+            f.err as u8,
+            f.can_dlc,
+            hex(data)
+        );
+        if self.ascii {
+            // quoted, with quotes doubled, so commas in the payload are kept
+            let _ = write!(s, ",\"{}\"", ascii(data).replace('"', "\"\""));
+        }
+        s
+    }
+}
+
+/// Parse a frame in candump log notation, e.g.
+/// `(1600000000.000000) can0 123#DEADBEEF`.
+///
+/// The timestamp and interface name are optional. The channel is taken from
+/// the number at the end of the interface name, and is 0 if there is none.
+/// The timestamp is returned in the frame's `timestamp`.
+pub fn parse_log_line(line: &str) -> Result<Frame, String> {
+    let mut fields = line.split_whitespace().peekable();
+
+    let mut timestamp = None;
+    if let Some(ts) = fields.peek().filter(|s| s.starts_with('(')) {
+        let ts = ts
+            .strip_prefix('(')
+            .and_then(|s| s.strip_suffix(')'))
+            .ok_or_else(|| String::from("invalid timestamp"))?;
+        let secs = ts
+            .parse::<f64>()
+            .ok()
+            .filter(|s| s.is_finite() && *s >= 0.0)
+            .ok_or_else(|| format!("invalid timestamp '{}'", ts))?;
+        timestamp = Some(Duration::from_secs_f64(secs));
+        fields.next();
+    }
+
+    let mut channel = 0;
+    let frame = match (fields.next(), fields.next()) {
+        (Some(f), None) => f,
+        (Some(iface), Some(f)) => {
+            let digits = iface.trim_start_matches(|c: char| !c.is_ascii_digit());
+            if !digits.is_empty() {
+                channel = digits
+                    .parse::<u8>()
+                    .map_err(|_| format!("invalid interface '{}'", iface))?;
+            }
+            f
+        }
+        (None, _) => return Err(String::from("missing frame")),
+    };
+    if fields.next().is_some() {
+        return Err(String::from("unexpected text after frame"));
+    }
+
+    let (id, rest) = frame
+        .split_once('#')
+        .ok_or_else(|| format!("invalid frame '{}', expected ID#DATA", frame))?;
+    let mut raw_id =
+        u32::from_str_radix(id, 16).map_err(|_| format!("invalid identifier '{}'", id))?;
+    let err = id.len() == 8 && raw_id & CAN_ERR_FLAG != 0;
+    if err {
+        raw_id &= !CAN_ERR_FLAG;
+    }
+    if raw_id > 0x1FFF_FFFF {
+        return Err(format!("identifier '{}' out of range", id));
+    }
+
+    let mut f = Frame {
+        can_id: raw_id,
+        channel,
+        // candump writes extended identifiers with 8 digits
+        ext: !err && (id.len() == 8 || raw_id > 0x7FF),
+        err,
+        timestamp,
+        ..Frame::default()
+    };
+
+    let data = if let Some(fd) = rest.strip_prefix('#') {
+        // CAN-FD: ID##<flags><data>
+        let mut chars = fd.chars();
+        let flags = chars
+            .next()
+            .and_then(|c| c.to_digit(16))
+            .ok_or_else(|| String::from("missing CAN-FD flags"))?;
+        f.fd = true;
+        f.brs = flags & 0x1 != 0;
+        f.esi = flags & 0x2 != 0;
+        chars.as_str()
+    } else if let Some(len) = rest.strip_prefix(|c| c == 'R' || c == 'r') {
+        f.rtr = true;
+        f.can_dlc = match len {
+            "" => 0,
+            len => len
+                .parse::<u8>()
+                .ok()
+                .filter(|l| *l <= 8)
+                .ok_or_else(|| format!("invalid RTR length '{}'", len))?,
+        };
+        return Ok(f);
+    } else {
+        rest
+    };
+
+    let message = |e: crate::Error| e.to_string();
+    let data = helpers::parse_data(data).map_err(message)?;
+    f.can_dlc = helpers::dlc_for_len(data.len(), f.fd).map_err(message)?;
+    f.data[..data.len()].copy_from_slice(&data);
+    Ok(f)
+}
+
+const CSV_HEADER: &str = "timestamp,channel,id,ext,rtr,fd,brs,esi,err,dlc,data";
+
+// set a frame's DLC and data, checking that they agree
+fn set_data(f: &mut Frame, dlc: u64, data: &str) -> Result<(), String> {
+    let data = helpers::parse_data(data).map_err(|e| e.to_string())?;
+    if dlc > if f.fd { 15 } else { 8 } {
+        return Err(format!("invalid DLC {}", dlc));
+    }
+    f.can_dlc = dlc as u8;
+    if !f.rtr && data.len() != f.data_len() {
+        return Err(format!(
+            "DLC {} does not match {} data bytes",
+            dlc,
+            data.len()
+        ));
+    }
+    f.data[..data.len()].copy_from_slice(&data);
+    Ok(())
+}
+
+fn parse_timestamp(s: &str) -> Result<Option<Duration>, String> {
+    if s.is_empty() {
+        return Ok(None);
+    }
+    s.parse::<f64>()
+        .ok()
+        .filter(|s| s.is_finite() && *s >= 0.0)
+        .map(|s| Some(Duration::from_secs_f64(s)))
+        .ok_or_else(|| format!("invalid timestamp '{}'", s))
+}
+
+/// Parse a frame printed in the JSON format.
+pub fn parse_json_line(line: &str) -> Result<Frame, String> {
+    let v: serde_json::Value = serde_json::from_str(line).map_err(|e| e.to_string())?;
+    let number = |name: &str| -> Result<u64, String> {
+        v[name]
+            .as_u64()
+            .ok_or_else(|| format!("missing or invalid {}", name))
+    };
+    let flag = |name: &str| v[name].as_bool().unwrap_or(false);
+
+    let mut f = Frame {
+        can_id: number("id")? as u32,
+        channel: number("channel")? as u8,
+        ext: flag("ext"),
+        rtr: flag("rtr"),
+        fd: flag("fd"),
+        brs: flag("brs"),
+        esi: flag("esi"),
+        err: flag("err"),
+        timestamp: match &v["timestamp"] {
+            serde_json::Value::Null => None,
+            t => Some(
+                t.as_f64()
+                    .filter(|t| *t >= 0.0)
+                    .map(Duration::from_secs_f64)
+                    .ok_or_else(|| String::from("invalid timestamp"))?,
+            ),
+        },
+        ..Frame::default()
+    };
+    if f.can_id > 0x1FFF_FFFF {
+        return Err(String::from("identifier out of range"));
+    }
+    set_data(&mut f, number("dlc")?, v["data"].as_str().unwrap_or(""))?;
+    Ok(f)
+}
+
+/// Parse a frame printed in the CSV format.
+pub fn parse_csv_line(line: &str) -> Result<Frame, String> {
+    // the optional ascii column is quoted and may contain commas, so
+    // only the leading columns are split
+    let fields: Vec<&str> = line.splitn(12, ',').collect();
+    if fields.len() < 11 {
+        return Err(format!("expected 11 columns, found {}", fields.len()));
+    }
+    let flag = |n: usize| match fields[n] {
+        "0" => Ok(false),
+        "1" => Ok(true),
+        s => Err(format!("invalid flag '{}'", s)),
+    };
+    let can_id = u32::from_str_radix(fields[2], 16)
+        .ok()
+        .filter(|id| *id <= 0x1FFF_FFFF)
+        .ok_or_else(|| format!("invalid identifier '{}'", fields[2]))?;
+
+    let mut f = Frame {
+        can_id,
+        channel: fields[1]
+            .parse::<u8>()
+            .map_err(|_| format!("invalid channel '{}'", fields[1]))?,
+        ext: flag(3)?,
+        rtr: flag(4)?,
+        fd: flag(5)?,
+        brs: flag(6)?,
+        esi: flag(7)?,
+        err: flag(8)?,
+        timestamp: parse_timestamp(fields[0])?,
+        ..Frame::default()
+    };
+    let dlc = fields[9]
+        .parse::<u64>()
+        .map_err(|_| format!("invalid DLC '{}'", fields[9]))?;
+    set_data(&mut f, dlc, fields[10])?;
+    Ok(f)
+}
+
+/// Reads frames from a file written in the log, JSON or CSV format. The
+/// format is detected from the first line.
+///
+/// Blank lines are skipped. Errors include the line number.
+pub struct LogReader<R> {
+    lines: io::Lines<R>,
+    format: Option<Format>,
+    line: usize,
+}
+
+impl<R: BufRead> LogReader<R> {
+    /// Create a reader for the lines of `reader`.
+    pub fn new(reader: R) -> LogReader<R> {
+        LogReader {
+            lines: reader.lines(),
+            format: None,
+            line: 0,
+        }
+    }
+}
+
+impl<R: BufRead> Iterator for LogReader<R> {
+    type Item = Result<Frame, String>;
+
+    fn next(&mut self) -> Option<Result<Frame, String>> {
+        loop {
+            let line = match self.lines.next()? {
+                Ok(l) => l,
+                Err(e) => return Some(Err(e.to_string())),
+            };
+            self.line += 1;
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let format = match self.format {
+                Some(f) => f,
+                None => {
+                    let format = if line.starts_with('{') {
+                        Format::Json
+                    } else if line.starts_with(CSV_HEADER) {
+                        Format::Csv
+                    } else {
+                        Format::Log
+                    };
+                    self.format = Some(format);
+                    if format == Format::Csv {
+                        // skip the header
+                        continue;
+                    }
+                    format
+                }
+            };
+            let result = match format {
+                Format::Json => parse_json_line(line),
+                Format::Csv => parse_csv_line(line),
+                _ => parse_log_line(line),
+            };
+            return Some(result.map_err(|e| format!("line {}: {}", self.line, e)));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(can_id: u32, data: &[u8]) -> Frame {
+        let mut f = Frame {
+            can_id,
+            can_dlc: data.len() as u8,
+            timestamp: Some(Duration::from_micros(1_500_250)),
+            ..Frame::default()
+        };
+        f.data[..data.len()].copy_from_slice(data);
+        f
+    }
+
+    #[test]
+    fn test_formats() {
+        let f = frame(0x123, b"AB\x01");
+        let mut p = Printer::new(Format::Human, Timestamps::Relative, true);
+        assert_eq!(p.format(&f), "1.500250\t  ch:0 123   [3]  41 42 01  'AB.'");
+
+        let mut p = Printer::new(Format::Log, Timestamps::Relative, false);
+        assert_eq!(p.format(&f), "(1.500250) can0 123#414201");
+
+        let mut p = Printer::new(Format::Json, Timestamps::None, false);
+        assert_eq!(
+            p.format(&f),
+            "{\"channel\":0,\"id\":291,\"ext\":false,\"rtr\":false,\"fd\":false,\"brs\":false,\"esi\":false,\"err\":false,\"dlc\":3,\"data\":\"414201\"}"
+        );
+
+        let mut p = Printer::new(Format::Csv, Timestamps::Relative, false);
+        assert_eq!(
+            p.header().unwrap(),
+            "timestamp,channel,id,ext,rtr,fd,brs,esi,err,dlc,data"
+        );
+        assert_eq!(p.format(&f), "1.500250,0,123,0,0,0,0,0,0,3,414201");
+    }
+
+    #[test]
+    fn test_delta_timestamps() {
+        let mut p = Printer::new(Format::Log, Timestamps::Delta, false);
+        let mut f = frame(0x1, &[]);
+        assert_eq!(p.format(&f), "(0.000000) can0 001#");
+        f.timestamp = Some(Duration::from_micros(1_600_251));
+        assert_eq!(p.format(&f), "(0.100001) can0 001#");
+    }
+
+    #[test]
+    fn test_error_frames() {
+        let mut f = frame(
+            0x0004 | 0x0080 | CAN_ERR_CNT,
+            &[0, 0x20, 0x04, 0, 0, 0, 130, 0],
+        );
+        f.err = true;
+        assert_eq!(
+            describe_error(&f),
+            "tx error passive, stuff error (tx errors: 130, rx errors: 0)"
+        );
+
+        let mut p = Printer::new(Format::Log, Timestamps::None, false);
+        let line = p.format(&f);
+        assert_eq!(line, "can0 20000284#0020040000008200");
+        let parsed = parse_log_line(&line).unwrap();
+        assert!(parsed.err && !parsed.ext);
+        assert_eq!(parsed.can_id, f.can_id);
+
+        let mut crc = frame(0x0008 | 0x0020, &[0, 0, 0, 0x08, 0, 0, 0, 0]);
+        crc.err = true;
+        assert_eq!(
+            describe_error(&crc),
+            "CRC error in CRC sequence, ACK missing"
+        );
+        assert_eq!(error_kinds(&crc), vec!["crc", "ack"]);
+        assert_eq!(error_kinds(&f), vec!["stuff"]);
+    }
+
+    #[test]
+    fn test_bus_state() {
+        let error = |class: u32, data: [u8; 8]| {
+            let mut f = frame(class, &data);
+            f.err = true;
+            f
+        };
+        let mut states = StateTracker::default();
+        assert_eq!(states.update(&frame(0x123, &[])), None);
+        // counters alone
+        let warning = error(CAN_ERR_CNT, [0, 0, 0, 0, 0, 0, 100, 0]);
+        assert_eq!(
+            states.update(&warning),
+            Some((BusState::Active, BusState::Warning))
+        );
+        assert_eq!(states.update(&warning), None);
+        let passive = error(CAN_ERR_CRTL, [0, 0x10, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(
+            states.update(&passive),
+            Some((BusState::Warning, BusState::Passive))
+        );
+        let bus_off = error(CAN_ERR_BUSOFF, [0; 8]);
+        assert_eq!(
+            states.update(&bus_off),
+            Some((BusState::Passive, BusState::BusOff))
+        );
+        assert_eq!(describe_error(&bus_off), "bus-off");
+        let restarted = error(CAN_ERR_RESTARTED, [0; 8]);
+        assert_eq!(
+            states.update(&restarted),
+            Some((BusState::BusOff, BusState::Active))
+        );
+        // other channels are tracked separately
+        assert_eq!(states.state(1), BusState::Active);
+        // frames without state information don't change it
+        assert_eq!(
+            states.update(&error(CAN_ERR_LOSTARB, [5, 0, 0, 0, 0, 0, 0, 0])),
+            None
+        );
+    }
+
+    #[test]
+    fn test_log_round_trip() {
+        let mut fd = frame(0x1234567, &[0xAA; 12]);
+        fd.ext = true;
+        fd.fd = true;
+        fd.brs = true;
+        fd.can_dlc = 9;
+        fd.channel = 1;
+        let mut rtr = frame(0x7FF, &[]);
+        rtr.rtr = true;
+        rtr.can_dlc = 4;
+
+        let mut p = Printer::new(Format::Log, Timestamps::Relative, false);
+        for f in &[frame(0x100, &[1, 2, 3]), fd, rtr] {
+            let line = p.format(f);
+            let parsed = parse_log_line(&line).unwrap();
+            assert_eq!(p.format(&parsed), line);
+        }
+    }
+
+    #[test]
+    fn test_parse_log_line() {
+        let f = parse_log_line("123#DE.AD.BE.EF").unwrap();
+        assert_eq!((f.can_id, f.can_dlc, f.ext), (0x123, 4, false));
+        assert_eq!(f.timestamp, None);
+
+        let f = parse_log_line("(1600000000.250000) vcan2 00000123#").unwrap();
+        assert_eq!((f.can_id, f.channel, f.ext), (0x123, 2, true));
+        assert_eq!(f.timestamp, Some(Duration::from_millis(1_600_000_000_250)));
+
+        assert!(parse_log_line("").is_err());
+        assert!(parse_log_line("123").is_err());
+        assert!(parse_log_line("xyz#00").is_err());
+        assert!(parse_log_line("123#0").is_err());
+        assert!(parse_log_line("123#00112233445566778899").is_err());
+        assert!(parse_log_line("(abc) can0 123#").is_err());
+        assert!(parse_log_line("can0 123# extra").is_err());
+    }
+
+    #[test]
+    fn test_log_reader() {
+        let mut fd = frame(0x1234567, &[0xAA; 12]);
+        fd.ext = true;
+        fd.fd = true;
+        fd.can_dlc = 9;
+        let mut rtr = frame(0x7FF, &[]);
+        rtr.rtr = true;
+        rtr.can_dlc = 2;
+        let frames = [frame(0x100, b"a,\""), fd, rtr];
+
+        for format in &[Format::Log, Format::Json, Format::Csv] {
+            let mut p = Printer::new(*format, Timestamps::Relative, true);
+            let mut text = p.header().map(|h| h + "\n").unwrap_or_default();
+            for f in frames.iter() {
+                text.push_str(&p.format(f));
+                text.push_str("\n\n");
+            }
+
+            let parsed: Vec<Frame> = LogReader::new(text.as_bytes())
+                .collect::<Result<_, _>>()
+                .unwrap();
+            assert_eq!(parsed.len(), frames.len());
+            for (a, b) in parsed.iter().zip(frames.iter()) {
+                assert_eq!(p.format(a), p.format(b));
+                assert_eq!(a.timestamp, b.timestamp);
+            }
+        }
+
+        let text = "123#00\n\n123#0\n";
+        let result: Vec<Result<Frame, String>> = LogReader::new(text.as_bytes()).collect();
+        assert!(result[0].is_ok());
+        assert!(result[1].as_ref().unwrap_err().starts_with("line 3: "));
+    }
+}
@@ -0,0 +1,311 @@
+use crate::Error;
+use cantact::{Frame, Interface};
+use clap::ArgMatches;
+use log::info;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::config::Config;
+use crate::helpers;
+
+// default time between frames, matching cangen
+const DEFAULT_GAP_MS: f64 = 200.0;
+
+// splitmix64, a small and fast generator that is reproducible for a given seed
+struct Rng(u64);
+impl Rng {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+    // random value in the inclusive range
+    fn range(&mut self, min: u64, max: u64) -> u64 {
+        min + self.next_u64() % (max - min + 1)
+    }
+    fn bool(&mut self) -> bool {
+        self.next_u64() & 1 == 1
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Mode<T> {
+    Fixed(T),
+    Increment,
+    Random,
+}
+
+fn parse_mode<T>(
+    s: Option<&str>,
+    parse_fixed: impl Fn(&str) -> Result<T, Error>,
+) -> Result<Mode<T>, Error> {
+    match s {
+        None | Some("r") => Ok(Mode::Random),
+        Some("i") => Ok(Mode::Increment),
+        Some(s) => Ok(Mode::Fixed(parse_fixed(s)?)),
+    }
+}
+
+struct Generator {
+    channel: u8,
+    id: Mode<u32>,
+    id_min: u32,
+    id_max: u32,
+    data: Mode<Vec<u8>>,
+    dlc: Mode<u8>,
+    ext: bool,
+    fd: bool,
+    brs: bool,
+    // randomly mix standard/extended, and classic/FD if fd is set
+    mix: bool,
+
+    rng: Rng,
+    count: u64,
+}
+
+impl Generator {
+    fn next_frame(&mut self) -> Frame {
+        let ext = if self.mix { self.rng.bool() } else { self.ext };
+        let fd = self.fd && (!self.mix || self.rng.bool());
+
+        // standard identifiers are limited to 11 bits
+        let id_max = if ext {
+            self.id_max
+        } else {
+            std::cmp::min(self.id_max, 0x7FF)
+        };
+        let id_min = std::cmp::min(self.id_min, id_max);
+        let can_id = match self.id {
+            Mode::Fixed(id) => id,
+            Mode::Increment => id_min + (self.count % (id_max - id_min + 1) as u64) as u32,
+            Mode::Random => self.rng.range(id_min as u64, id_max as u64) as u32,
+        };
+
+        let max_dlc = if fd { 15 } else { 8 };
+        let can_dlc = match &self.dlc {
+            Mode::Fixed(dlc) => std::cmp::min(*dlc, max_dlc),
+            Mode::Increment => (self.count % (max_dlc as u64 + 1)) as u8,
+            Mode::Random => self.rng.range(0, max_dlc as u64) as u8,
+        };
+
+        let mut f = Frame {
+            can_id,
+            can_dlc,
+            channel: self.channel,
+            ext: ext || can_id > 0x7FF,
+            fd,
+            brs: fd && self.brs,
+            ..Frame::default()
+        };
+        let len = f.data_len();
+        match &self.data {
+            Mode::Fixed(data) => {
+                let n = std::cmp::min(len, data.len());
+                f.data[..n].copy_from_slice(&data[..n]);
+            }
+            Mode::Increment => {
+                let bytes = self.count.to_le_bytes();
+                let n = std::cmp::min(len, bytes.len());
+                f.data[..n].copy_from_slice(&bytes[..n]);
+            }
+            Mode::Random => {
+                for b in f.data[..len].iter_mut() {
+                    *b = self.rng.next_u64() as u8;
+                }
+            }
+        }
+
+        self.count += 1;
+        f
+    }
+}
+
+fn parse_generator(matches: &ArgMatches, channel: usize, seed: u64) -> Result<Generator, Error> {
+    let ext = matches.is_present("extended");
+    let id = parse_mode(matches.value_of("id"), |s| Ok(helpers::parse_id(s)?.0))?;
+    let parse_limit = |name: &str, default: u32| -> Result<u32, Error> {
+        match matches.value_of(name) {
+            Some(s) => Ok(helpers::parse_id(s)?.0),
+            None => Ok(default),
+        }
+    };
+    let id_min = parse_limit("id_min", 0)?;
+    let id_max = parse_limit("id_max", 0x1FFF_FFFF)?;
+    if id_min > id_max {
+        return Err(Error::InvalidArgument(String::from(
+            "minimum identifier is larger than maximum identifier",
+        )));
+    }
+    let data = parse_mode(matches.value_of("data"), helpers::parse_data)?;
+    let dlc = parse_mode(matches.value_of("dlc"), |s| {
+        s.parse::<u8>()
+            .ok()
+            .filter(|dlc| *dlc <= 15)
+            .ok_or_else(|| Error::InvalidArgument(String::from("invalid dlc value")))
+    })?;
+
+    Ok(Generator {
+        channel: channel as u8,
+        id,
+        id_min,
+        id_max,
+        data,
+        dlc,
+        ext,
+        fd: matches.is_present("fd"),
+        brs: matches.is_present("brs"),
+        mix: matches.is_present("mix"),
+        rng: Rng(seed),
+        count: 0,
+    })
+}
+
+pub fn cmd(matches: &ArgMatches) -> Result<(), Error> {
+    let flag = helpers::initialize_ctrlc()?;
+
+    let mut config = Config::read(matches)?;
+
+    let ch = helpers::parse_channel(matches)?.unwrap_or(0);
+
+    let seed = match helpers::parse_arg::<u64>(matches, "seed")? {
+        Some(s) => s,
+        None => SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or(0),
+    };
+    let mut gen = parse_generator(matches, ch, seed)?;
+
+    let gap_ms = match helpers::parse_arg::<f64>(matches, "rate")? {
+        Some(rate) if rate > 0.0 => 1000.0 / rate,
+        Some(_) => return Err(Error::InvalidArgument(String::from("invalid rate value"))),
+        None => helpers::parse_arg::<f64>(matches, "gap")?.unwrap_or(DEFAULT_GAP_MS),
+    };
+    if gap_ms < 0.0 {
+        return Err(Error::InvalidArgument(String::from("invalid gap value")));
+    }
+    let gap = Duration::from_secs_f64(gap_ms / 1000.0);
+    let limit = helpers::parse_arg::<u64>(matches, "count")?;
+
+    // initialize the interface
+    let mut i = Interface::new()?;
+    config.fit_to_interface(&i)?;
+    // only the transmit channel is used
+    config.select_channels(&[ch])?;
+    if matches.is_present("fd") && !config.channels[ch].fd {
+        return Err(Error::InvalidArgument(format!(
+            "channel {} is not configured for CAN-FD, use `can cfg -c {} --fd`",
+            ch, ch
+        )));
+    }
+    info!("config: {:?}", config);
+    info!("seed: {}", seed);
+    config.apply_to_interface(&mut i)?;
+
+    // start the device
+    info!("starting gen");
+    i.start(move |_: Frame| {})?;
+
+    let mut sent: u64 = 0;
+    let mut failed: u64 = 0;
+    let start = Instant::now();
+    while limit.is_none_or(|l| sent + failed < l) && !helpers::check_ctrlc(&flag) {
+        // absolute deadlines keep the rate from drifting
+        let deadline = start + gap.mul_f64((sent + failed) as f64);
+        let now = Instant::now();
+        if deadline > now {
+            thread::sleep(deadline - now);
+        }
+        match i.send(gen.next_frame()) {
+            Ok(()) => sent += 1,
+            Err(e) => {
+                info!("send failed: {}", e);
+                failed += 1;
+            }
+        }
+    }
+    let elapsed = start.elapsed().as_secs_f64();
+
+    i.stop()?;
+
+    println!(
+        "sent {} frames in {:.3} s ({:.1} frames/s), {} failed",
+        sent,
+        elapsed,
+        if elapsed > 0.0 {
+            sent as f64 / elapsed
+        } else {
+            0.0
+        },
+        failed
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn generator(id: Mode<u32>, data: Mode<Vec<u8>>, dlc: Mode<u8>) -> Generator {
+        Generator {
+            channel: 0,
+            id,
+            id_min: 0x100,
+            id_max: 0x1FFF_FFFF,
+            data,
+            dlc,
+            ext: false,
+            fd: false,
+            brs: false,
+            mix: false,
+            rng: Rng(1234),
+            count: 0,
+        }
+    }
+
+    #[test]
+    fn test_reproducible() {
+        let mut a = generator(Mode::Random, Mode::Random, Mode::Random);
+        let mut b = generator(Mode::Random, Mode::Random, Mode::Random);
+        for _ in 0..100 {
+            let (fa, fb) = (a.next_frame(), b.next_frame());
+            assert_eq!(
+                (fa.can_id, fa.can_dlc, fa.data),
+                (fb.can_id, fb.can_dlc, fb.data)
+            );
+            assert!((0x100..=0x7FF).contains(&fa.can_id));
+            assert!(fa.can_dlc <= 8);
+            assert!(!fa.ext);
+        }
+    }
+
+    #[test]
+    fn test_increment() {
+        let mut g = generator(Mode::Increment, Mode::Increment, Mode::Fixed(2));
+        let frames: Vec<Frame> = (0..0x701).map(|_| g.next_frame()).collect();
+        assert_eq!(frames[0].can_id, 0x100);
+        assert_eq!(frames[0x6FF].can_id, 0x7FF);
+        // identifiers wrap around within the range
+        assert_eq!(frames[0x700].can_id, 0x100);
+        assert_eq!(frames[0x102].data[..2], [0x02, 0x01]);
+        assert!(frames.iter().all(|f| f.can_dlc == 2));
+    }
+
+    #[test]
+    fn test_fixed_fd() {
+        let mut g = generator(
+            Mode::Fixed(0x12345),
+            Mode::Fixed(vec![0xAA; 3]),
+            Mode::Fixed(9),
+        );
+        g.fd = true;
+        g.brs = true;
+        let f = g.next_frame();
+        assert_eq!(f.can_id, 0x12345);
+        assert!(f.ext && f.fd && f.brs);
+        assert_eq!(f.data_len(), 12);
+        assert_eq!(f.data[..4], [0xAA, 0xAA, 0xAA, 0x00]);
+    }
+}
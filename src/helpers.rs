@@ -1,20 +1,24 @@
 use crate::Error;
 use clap::ArgMatches;
+use std::collections::BTreeSet;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-const MAX_CHANNELS: usize = 2;
+// devices report their channel count in a byte, so this is the highest
+// channel any device can have
+const MAX_CHANNEL: usize = 255;
 
-pub fn initialize_ctrlc() -> Arc<AtomicBool> {
+pub fn initialize_ctrlc() -> Result<Arc<AtomicBool>, Error> {
     let flag = Arc::new(AtomicBool::new(false));
     let f = flag.clone();
 
     ctrlc::set_handler(move || {
         f.store(true, Ordering::SeqCst);
     })
-    .expect("Error setting Ctrl-C handler");
+    .map_err(|e| Error::IoError(format!("could not set the ctrl-c handler: {}", e)))?;
 
-    flag
+    Ok(flag)
 }
 
 pub fn check_ctrlc(f: &Arc<AtomicBool>) -> bool {
@@ -27,6 +31,21 @@ pub fn wait_for_ctrlc(f: &Arc<AtomicBool>) {
     }
 }
 
+// sleep until the deadline, returning false if interrupted by ctrl-c
+pub fn sleep_until(deadline: Instant, flag: &Arc<AtomicBool>) -> bool {
+    loop {
+        if check_ctrlc(flag) {
+            return false;
+        }
+        let now = Instant::now();
+        if now >= deadline {
+            return true;
+        }
+        std::thread::sleep(std::cmp::min(deadline - now, Duration::from_millis(100)));
+    }
+}
+
+// whether the channel exists on the device is checked once it is opened
 pub fn parse_channel(matches: &ArgMatches) -> Result<Option<usize>, Error> {
     if !matches.is_present("channel") {
         return Ok(None);
@@ -36,9 +55,215 @@ pub fn parse_channel(matches: &ArgMatches) -> Result<Option<usize>, Error> {
         Err(_) => Err(Error::InvalidArgument(String::from(
             "invalid channel value",
         ))),
-        Ok(ch) if ch > MAX_CHANNELS => Err(Error::InvalidArgument(String::from(
+        Ok(ch) if ch > MAX_CHANNEL => Err(Error::InvalidArgument(String::from(
             "channel value out of range",
         ))),
         Ok(ch) => Ok(Some(ch)),
     }
 }
+
+// parses a list of channels and ranges of channels, such as 0,2-3
+pub fn parse_channel_list(s: &str) -> Result<Vec<usize>, Error> {
+    let invalid = || Error::InvalidArgument(format!("invalid channel list {}", s));
+    let parse = |n: &str| match n.trim().parse::<usize>() {
+        Ok(n) if n <= MAX_CHANNEL => Ok(n),
+        _ => Err(invalid()),
+    };
+    let mut channels = BTreeSet::new();
+    for part in s.split(',') {
+        let (start, end) = match part.split_once('-') {
+            Some((start, end)) => (parse(start)?, parse(end)?),
+            None => (parse(part)?, parse(part)?),
+        };
+        if start > end {
+            return Err(invalid());
+        }
+        channels.extend(start..=end);
+    }
+    Ok(channels.into_iter().collect())
+}
+
+pub fn parse_channels(matches: &ArgMatches) -> Result<Option<Vec<usize>>, Error> {
+    match matches.value_of("channel") {
+        Some(s) => parse_channel_list(s).map(Some),
+        None => Ok(None),
+    }
+}
+
+pub fn parse_arg<T: std::str::FromStr>(
+    matches: &ArgMatches,
+    name: &str,
+) -> Result<Option<T>, Error> {
+    match matches.value_of(name) {
+        None => Ok(None),
+        Some(s) => match s.parse::<T>() {
+            Ok(v) => Ok(Some(v)),
+            Err(_) => Err(Error::InvalidArgument(format!("invalid {} value", name))),
+        },
+    }
+}
+
+// parse a hex CAN identifier, returning the identifier and true if it must
+// be sent as an extended identifier
+pub fn parse_id(s: &str) -> Result<(u32, bool), Error> {
+    let digits = s.trim_start_matches("0x").trim_start_matches("0X");
+    match u32::from_str_radix(digits, 16) {
+        Ok(id) if id <= 0x7FF => Ok((id, false)),
+        Ok(id) if id <= 0x1FFF_FFFF => Ok((id, true)),
+        Ok(_) => Err(Error::InvalidArgument(String::from(
+            "identifier value out of range",
+        ))),
+        Err(_) => Err(Error::InvalidArgument(String::from(
+            "invalid identifier value",
+        ))),
+    }
+}
+
+// parse hex data bytes, optionally separated by '.', ':', or whitespace
+pub fn parse_data(s: &str) -> Result<Vec<u8>, Error> {
+    let digits: Vec<char> = s
+        .chars()
+        .filter(|c| !(c.is_whitespace() || *c == '.' || *c == ':'))
+        .collect();
+    if !digits.len().is_multiple_of(2) {
+        return Err(Error::InvalidArgument(String::from(
+            "data must contain an even number of hex digits",
+        )));
+    }
+    digits
+        .chunks(2)
+        .map(|pair| {
+            let byte: String = pair.iter().collect();
+            u8::from_str_radix(&byte, 16)
+                .map_err(|_| Error::InvalidArgument(format!("invalid data byte {}", byte)))
+        })
+        .collect()
+}
+
+// returns the smallest DLC that can hold `len` bytes of data
+pub fn dlc_for_len(len: usize, fd: bool) -> Result<u8, Error> {
+    let dlc = match len {
+        0..=8 => len as u8,
+        9..=12 if fd => 9,
+        13..=16 if fd => 10,
+        17..=20 if fd => 11,
+        21..=24 if fd => 12,
+        25..=32 if fd => 13,
+        33..=48 if fd => 14,
+        49..=64 if fd => 15,
+        _ if fd => {
+            return Err(Error::InvalidArgument(String::from(
+                "CAN-FD frames can contain at most 64 data bytes",
+            )))
+        }
+        _ => {
+            return Err(Error::InvalidArgument(String::from(
+                "CAN frames can contain at most 8 data bytes",
+            )))
+        }
+    };
+    Ok(dlc)
+}
+
+// parse a size in bytes, with an optional k, M or G suffix (powers of 1024)
+pub fn parse_size(s: &str) -> Result<u64, Error> {
+    let s = s.trim();
+    let (digits, scale) = match s.chars().last() {
+        Some('k') | Some('K') => (&s[..s.len() - 1], 1 << 10),
+        Some('m') | Some('M') => (&s[..s.len() - 1], 1 << 20),
+        Some('g') | Some('G') => (&s[..s.len() - 1], 1 << 30),
+        _ => (s, 1),
+    };
+    digits
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(scale))
+        .filter(|n| *n > 0)
+        .ok_or_else(|| Error::InvalidArgument(format!("invalid size {}", s)))
+}
+
+// parse a duration in seconds, with an optional ms, s, m or h suffix
+pub fn parse_duration(s: &str) -> Result<Duration, Error> {
+    let s = s.trim();
+    let (digits, scale) = if let Some(d) = s.strip_suffix("ms") {
+        (d, 0.001)
+    } else if let Some(d) = s.strip_suffix('s') {
+        (d, 1.0)
+    } else if let Some(d) = s.strip_suffix('m') {
+        (d, 60.0)
+    } else if let Some(d) = s.strip_suffix('h') {
+        (d, 3600.0)
+    } else {
+        (s, 1.0)
+    };
+    digits
+        .parse::<f64>()
+        .ok()
+        .and_then(|n| Duration::try_from_secs_f64(n * scale).ok())
+        .ok_or_else(|| Error::InvalidArgument(format!("invalid duration {}", s)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_channel_list() {
+        assert_eq!(parse_channel_list("1").unwrap(), vec![1]);
+        assert_eq!(parse_channel_list("0,2-3").unwrap(), vec![0, 2, 3]);
+        assert_eq!(parse_channel_list("3, 1-2,2").unwrap(), vec![1, 2, 3]);
+        assert!(parse_channel_list("2-1").is_err());
+        assert!(parse_channel_list("0,").is_err());
+        assert!(parse_channel_list("0-1000").is_err());
+    }
+
+    #[test]
+    fn test_parse_id() {
+        assert_eq!(parse_id("123").unwrap(), (0x123, false));
+        assert_eq!(parse_id("0x7FF").unwrap(), (0x7FF, false));
+        assert_eq!(parse_id("800").unwrap(), (0x800, true));
+        assert_eq!(parse_id("1FFFFFFF").unwrap(), (0x1FFF_FFFF, true));
+        assert!(parse_id("20000000").is_err());
+        assert!(parse_id("xyz").is_err());
+    }
+
+    #[test]
+    fn test_parse_data() {
+        assert_eq!(parse_data("").unwrap(), Vec::<u8>::new());
+        assert_eq!(
+            parse_data("DEADbeef").unwrap(),
+            vec![0xDE, 0xAD, 0xBE, 0xEF]
+        );
+        assert_eq!(parse_data("01.02:03 04").unwrap(), vec![1, 2, 3, 4]);
+        assert!(parse_data("123").is_err());
+        assert!(parse_data("0G").is_err());
+    }
+
+    #[test]
+    fn test_dlc_for_len() {
+        assert_eq!(dlc_for_len(8, false).unwrap(), 8);
+        assert!(dlc_for_len(9, false).is_err());
+        assert_eq!(dlc_for_len(9, true).unwrap(), 9);
+        assert_eq!(dlc_for_len(64, true).unwrap(), 15);
+        assert!(dlc_for_len(65, true).is_err());
+    }
+
+    #[test]
+    fn test_parse_size_and_duration() {
+        assert_eq!(parse_size("512").unwrap(), 512);
+        assert_eq!(parse_size("10k").unwrap(), 10 * 1024);
+        assert_eq!(parse_size("2M").unwrap(), 2 * 1024 * 1024);
+        assert!(parse_size("0").is_err());
+        assert!(parse_size("1T").is_err());
+
+        assert_eq!(parse_duration("1.5").unwrap(), Duration::from_millis(1500));
+        assert_eq!(parse_duration("250ms").unwrap(), Duration::from_millis(250));
+        assert_eq!(parse_duration("10m").unwrap(), Duration::from_secs(600));
+        assert_eq!(parse_duration("2h").unwrap(), Duration::from_secs(7200));
+        assert!(parse_duration("-1s").is_err());
+        assert!(parse_duration("abc").is_err());
+        // too long to be represented
+        assert!(parse_duration("1e300s").is_err());
+        assert!(parse_duration("inf").is_err());
+    }
+}
@@ -0,0 +1,118 @@
+use crate::Error;
+use cantact::{feature_names, BitTimingLimits, DeviceInfo, DeviceSelector, Interface};
+use clap::ArgMatches;
+use serde::Serialize;
+
+// what the device reported when it was opened
+#[derive(Serialize)]
+struct Capabilities {
+    sw_version: u32,
+    hw_version: u32,
+    can_clock: u32,
+    channels: usize,
+    features: u32,
+    feature_names: Vec<&'static str>,
+    bit_timing: BitTimingLimits,
+}
+
+#[derive(Serialize)]
+struct Adapter {
+    #[serde(flatten)]
+    device: DeviceInfo,
+    path: String,
+    // None if the device could not be opened
+    capabilities: Option<Capabilities>,
+    error: Option<String>,
+}
+
+fn query(device: DeviceInfo) -> Adapter {
+    let result = Interface::builder()
+        .device(DeviceSelector::Index(device.index))
+        .build();
+    let (capabilities, error) = match result {
+        Ok(i) => (
+            Some(Capabilities {
+                sw_version: i.sw_version(),
+                hw_version: i.hw_version(),
+                can_clock: i.can_clock(),
+                channels: i.channels(),
+                features: i.features(),
+                feature_names: feature_names(i.features()),
+                bit_timing: i.bit_timing_limits(),
+            }),
+            None,
+        ),
+        Err(e) => (None, Some(e.to_string())),
+    };
+    Adapter {
+        path: device.path(),
+        device,
+        capabilities,
+        error,
+    }
+}
+
+fn print_adapter(a: &Adapter) {
+    println!("device {}", a.device.index);
+    println!(
+        "  serial:           {}",
+        a.device.serial.as_deref().unwrap_or("unknown")
+    );
+    println!(
+        "  usb path:         {} (address {})",
+        a.path, a.device.address
+    );
+    let c = match (&a.capabilities, &a.error) {
+        (Some(c), _) => c,
+        (None, e) => {
+            println!(
+                "  error:            {}",
+                e.as_deref().unwrap_or("could not open device")
+            );
+            return;
+        }
+    };
+    println!("  firmware version: {}", c.sw_version);
+    println!("  hardware version: {}", c.hw_version);
+    println!("  can clock:        {} Hz", c.can_clock);
+    println!("  channels:         {}", c.channels);
+    println!(
+        "  features:         0x{:08X} ({})",
+        c.features,
+        c.feature_names.join(", ")
+    );
+    let bt = &c.bit_timing;
+    println!(
+        "  bit timing:       tseg1 {}-{}, tseg2 {}-{}, sjw <= {}, brp {}-{} (step {})",
+        bt.tseg1_min,
+        bt.tseg1_max,
+        bt.tseg2_min,
+        bt.tseg2_max,
+        bt.sjw_max,
+        bt.brp_min,
+        bt.brp_max,
+        bt.brp_inc
+    );
+}
+
+pub fn cmd(matches: &ArgMatches) -> Result<(), Error> {
+    let adapters: Vec<Adapter> = cantact::list_devices()?.into_iter().map(query).collect();
+
+    if matches.is_present("json") {
+        let json = serde_json::to_string_pretty(&adapters)
+            .map_err(|e| Error::InvalidArgument(format!("could not encode JSON: {}", e)))?;
+        println!("{}", json);
+        return Ok(());
+    }
+
+    if adapters.is_empty() {
+        println!("no devices found");
+    }
+    for (n, a) in adapters.iter().enumerate() {
+        if n > 0 {
+            println!();
+        }
+        print_adapter(a);
+    }
+    Ok(())
+}
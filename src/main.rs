@@ -2,19 +2,76 @@ use cantact::Error as DevError;
 use clap::load_yaml;
 use clap::App;
 use simplelog::*;
+use std::fmt;
 
 // commands
+mod bridge;
 mod cfg;
 mod dump;
+mod errors;
+mod gen;
+mod info;
+mod record;
+mod replay;
 mod send;
+mod shell;
+mod test;
+mod timing;
+mod top;
 
+pub mod analysis;
 pub mod config;
+pub mod filter;
+pub mod format;
 pub mod helpers;
 
+// exit status for each class of error, usage errors from clap use the
+// same status as invalid arguments
+const EXIT_INVALID_ARGUMENT: i32 = 2;
+const EXIT_CONFIG_ERROR: i32 = 3;
+const EXIT_IO_ERROR: i32 = 4;
+const EXIT_DEVICE_NOT_FOUND: i32 = 5;
+const EXIT_DEVICE_ERROR: i32 = 6;
+const EXIT_TRANSMIT_FAILED: i32 = 7;
+const EXIT_TEST_FAILED: i32 = 8;
+const EXIT_TIMING_VIOLATION: i32 = 9;
+
 #[derive(Debug)]
 pub enum Error {
     DeviceError(DevError),
     InvalidArgument(String),
+    TransmitFailed(String),
+    ConfigError(String),
+    IoError(String),
+    TestFailed(String),
+    TimingViolation(String),
+}
+impl Error {
+    fn exit_code(&self) -> i32 {
+        match self {
+            Error::DeviceError(DevError::DeviceNotFound) => EXIT_DEVICE_NOT_FOUND,
+            Error::DeviceError(_) => EXIT_DEVICE_ERROR,
+            Error::InvalidArgument(_) => EXIT_INVALID_ARGUMENT,
+            Error::TransmitFailed(_) => EXIT_TRANSMIT_FAILED,
+            Error::ConfigError(_) => EXIT_CONFIG_ERROR,
+            Error::IoError(_) => EXIT_IO_ERROR,
+            Error::TestFailed(_) => EXIT_TEST_FAILED,
+            Error::TimingViolation(_) => EXIT_TIMING_VIOLATION,
+        }
+    }
+}
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::DeviceError(e) => write!(f, "{}", e),
+            Error::InvalidArgument(msg) => write!(f, "{}", msg),
+            Error::TransmitFailed(msg) => write!(f, "transmit failed: {}", msg),
+            Error::ConfigError(msg) => write!(f, "configuration error: {}", msg),
+            Error::IoError(msg) => write!(f, "{}", msg),
+            Error::TestFailed(msg) => write!(f, "{}", msg),
+            Error::TimingViolation(msg) => write!(f, "{}", msg),
+        }
+    }
 }
 impl From<DevError> for Error {
     fn from(de: DevError) -> Error {
@@ -24,7 +81,15 @@ impl From<DevError> for Error {
 
 fn main() {
     let yaml = load_yaml!("cli.yml");
-    let matches = App::from(yaml).get_matches();
+    let matches = match App::from(yaml).get_matches_safe() {
+        Ok(m) => m,
+        // help and version are printed to stdout and exit successfully
+        Err(e) if !e.use_stderr() => e.exit(),
+        Err(e) => {
+            eprintln!("{}", e.message);
+            std::process::exit(EXIT_INVALID_ARGUMENT);
+        }
+    };
 
     let logger = if matches.is_present("verbose") {
         TermLogger::init(LevelFilter::Info, Config::default(), TerminalMode::Mixed)
@@ -36,12 +101,22 @@ fn main() {
     let result = match matches.subcommand() {
         ("dump", Some(m)) => dump::cmd(m),
         ("send", Some(m)) => send::cmd(m),
+        ("gen", Some(m)) => gen::cmd(m),
+        ("top", Some(m)) => top::cmd(m),
+        ("errors", Some(m)) => errors::cmd(m),
+        ("timing", Some(m)) => timing::cmd(m),
+        ("info", Some(m)) => info::cmd(m),
+        ("record", Some(m)) => record::cmd(m),
+        ("replay", Some(m)) => replay::cmd(m),
+        ("bridge", Some(m)) => bridge::cmd(m),
+        ("shell", Some(m)) => shell::cmd(m),
+        ("test", Some(m)) => test::cmd(m),
         ("cfg", Some(m)) => cfg::cmd(m),
         _ => Ok(()),
     };
 
-    match result {
-        Ok(_) => {}
-        Err(e) => println!("error: {:?}", e),
+    if let Err(e) = result {
+        eprintln!("error: {}", e);
+        std::process::exit(e.exit_code());
     }
 }
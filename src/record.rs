@@ -0,0 +1,477 @@
+use crate::Error;
+use cantact::{Frame, Interface, TimeBase};
+use clap::ArgMatches;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use log::info;
+use std::collections::VecDeque;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::config::Config;
+use crate::filter::Filter;
+use crate::format::{Format, Printer, Timestamps};
+use crate::helpers;
+
+// how often the main loop checks for ctrl-c
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+// compress a closed segment, replacing it with a .gz file
+fn compress(path: &Path) -> io::Result<PathBuf> {
+    let mut gz_path = path.as_os_str().to_owned();
+    gz_path.push(".gz");
+    let gz_path = PathBuf::from(gz_path);
+
+    let mut input = File::open(path)?;
+    let mut output = GzEncoder::new(File::create(&gz_path)?, Compression::default());
+    io::copy(&mut input, &mut output)?;
+    output.finish()?.sync_all()?;
+    fs::remove_file(path)?;
+    Ok(gz_path)
+}
+
+// writes lines to a sequence of files, starting a new file when the current
+// one reaches its size or time limit
+struct Segments {
+    base: PathBuf,
+    max_size: Option<u64>,
+    max_time: Option<Duration>,
+    compress: bool,
+
+    count: usize,
+    file: Option<BufWriter<File>>,
+    path: PathBuf,
+    bytes: u64,
+    // frame time the current segment was started
+    started: Duration,
+    closed: Vec<thread::JoinHandle<io::Result<PathBuf>>>,
+}
+
+impl Segments {
+    fn new(
+        base: PathBuf,
+        max_size: Option<u64>,
+        max_time: Option<Duration>,
+        compress: bool,
+    ) -> Segments {
+        Segments {
+            path: base.clone(),
+            base,
+            max_size,
+            max_time,
+            compress,
+            count: 0,
+            file: None,
+            bytes: 0,
+            started: Duration::from_secs(0),
+            closed: Vec::new(),
+        }
+    }
+
+    // returns the path of segment n, e.g. capture-0001.log for capture.log
+    fn segment_path(&self, n: usize) -> PathBuf {
+        if self.max_size.is_none() && self.max_time.is_none() {
+            return self.base.clone();
+        }
+        let stem = self
+            .base
+            .file_stem()
+            .map(|s| s.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let name = match self.base.extension() {
+            Some(ext) => format!("{}-{:04}.{}", stem, n, ext.to_string_lossy()),
+            None => format!("{}-{:04}", stem, n),
+        };
+        self.base.with_file_name(name)
+    }
+
+    fn write_line(&mut self, line: &str, t: Duration, header: Option<&str>) -> io::Result<()> {
+        let len = line.len() as u64 + 1;
+        if self.file.is_some() {
+            let full = self.max_size.is_some_and(|max| self.bytes + len > max);
+            let expired = self
+                .max_time
+                .is_some_and(|max| t.saturating_sub(self.started) >= max);
+            if full || expired {
+                self.close()?;
+            }
+        }
+
+        let file = match self.file.as_mut() {
+            Some(f) => f,
+            None => {
+                self.count += 1;
+                self.path = self.segment_path(self.count);
+                info!("writing {:?}", self.path);
+                let mut f = BufWriter::new(File::create(&self.path)?);
+                self.bytes = 0;
+                self.started = t;
+                if let Some(header) = header {
+                    writeln!(f, "{}", header)?;
+                    self.bytes += header.len() as u64 + 1;
+                }
+                self.file.insert(f)
+            }
+        };
+        writeln!(file, "{}", line)?;
+        self.bytes += len;
+        Ok(())
+    }
+
+    fn close(&mut self) -> io::Result<()> {
+        let mut f = match self.file.take() {
+            Some(f) => f,
+            None => return Ok(()),
+        };
+        f.flush()?;
+        f.get_ref().sync_all()?;
+        drop(f);
+
+        let path = self.path.clone();
+        self.closed.push(if self.compress {
+            // compressing can take a while, so frames keep being written
+            thread::spawn(move || compress(&path))
+        } else {
+            thread::spawn(move || Ok(path))
+        });
+        Ok(())
+    }
+
+    // close the current segment, returning the paths of every segment written
+    fn finish(&mut self) -> io::Result<Vec<PathBuf>> {
+        self.close()?;
+        self.closed
+            .drain(..)
+            .map(|t| {
+                t.join()
+                    .unwrap_or_else(|_| Err(io::Error::other("compression failed")))
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum State {
+    // waiting for the start trigger
+    Waiting,
+    Recording,
+    // the stop trigger was seen
+    Stopped,
+}
+
+struct Recorder {
+    printer: Printer,
+    segments: Segments,
+    filter: Option<Filter>,
+    start: Option<Filter>,
+    stop: Option<Filter>,
+    // frames before the start trigger that are kept and recorded with it
+    pre_trigger: Duration,
+    buffer: VecDeque<Frame>,
+    state: State,
+    count: u64,
+}
+
+impl Recorder {
+    fn write(&mut self, f: &Frame) -> io::Result<()> {
+        let line = self.printer.format(f);
+        let header = self.printer.header();
+        let t = f.timestamp.unwrap_or_default();
+        self.segments.write_line(&line, t, header.as_deref())?;
+        self.count += 1;
+        Ok(())
+    }
+
+    // record a frame, returning false once the stop trigger has been seen
+    fn push(&mut self, f: Frame) -> io::Result<bool> {
+        let recorded = self.filter.as_ref().is_none_or(|filter| filter.matches(&f));
+        match self.state {
+            State::Waiting => {
+                if self.start.as_ref().is_none_or(|start| start.matches(&f)) {
+                    info!("start trigger");
+                    self.state = State::Recording;
+                    while let Some(b) = self.buffer.pop_front() {
+                        self.write(&b)?;
+                    }
+                    return self.push(f);
+                }
+                if recorded && self.pre_trigger > Duration::from_secs(0) {
+                    let t = f.timestamp.unwrap_or_default();
+                    self.buffer.push_back(f);
+                    while self.buffer.front().is_some_and(|b| {
+                        t.saturating_sub(b.timestamp.unwrap_or_default()) > self.pre_trigger
+                    }) {
+                        self.buffer.pop_front();
+                    }
+                }
+            }
+            State::Recording => {
+                if recorded {
+                    self.write(&f)?;
+                }
+                if self.stop.as_ref().is_some_and(|stop| stop.matches(&f)) {
+                    info!("stop trigger");
+                    self.state = State::Stopped;
+                }
+            }
+            State::Stopped => {}
+        }
+        Ok(self.state != State::Stopped)
+    }
+}
+
+fn parse_filter(matches: &ArgMatches, name: &str) -> Result<Option<Filter>, Error> {
+    match matches.value_of(name) {
+        Some(s) => Filter::parse(s)
+            .map(Some)
+            .map_err(|e| Error::InvalidArgument(format!("invalid {} expression: {}", name, e))),
+        None => Ok(None),
+    }
+}
+
+fn io_error(path: &Path, e: io::Error) -> Error {
+    Error::IoError(format!("could not write {}: {}", path.display(), e))
+}
+
+pub fn cmd(matches: &ArgMatches) -> Result<(), Error> {
+    let flag = helpers::initialize_ctrlc()?;
+    let mut config = Config::read(matches)?;
+
+    let channels = helpers::parse_channels(matches)?;
+
+    let format = match matches.value_of("format") {
+        Some(s) => Format::parse(s)
+            .ok_or_else(|| Error::InvalidArgument(String::from("invalid format value")))?,
+        None => Format::Log,
+    };
+    let timestamps = match matches.value_of("timestamp") {
+        Some(s) => Timestamps::parse(s)
+            .ok_or_else(|| Error::InvalidArgument(String::from("invalid timestamp value")))?,
+        None => Timestamps::Absolute,
+    };
+    let max_size = match matches.value_of("max_size") {
+        Some(s) => Some(helpers::parse_size(s)?),
+        None => None,
+    };
+    let max_time = match matches.value_of("max_time") {
+        Some(s) => Some(helpers::parse_duration(s)?),
+        None => None,
+    };
+    let pre_trigger = match matches.value_of("pre_trigger") {
+        Some(s) => helpers::parse_duration(s)?,
+        None => Duration::from_secs(0),
+    };
+    let output = PathBuf::from(matches.value_of("output").unwrap());
+    let segments = Segments::new(
+        output.clone(),
+        max_size,
+        max_time,
+        matches.is_present("compress"),
+    );
+    let filter = parse_filter(matches, "filter")?;
+    let start = parse_filter(matches, "start")?;
+    let stop = parse_filter(matches, "stop")?;
+    let state = if start.is_some() {
+        State::Waiting
+    } else {
+        State::Recording
+    };
+
+    // initialize the interface
+    let mut i = Interface::new()?;
+    config.fit_to_interface(&i)?;
+    if let Some(channels) = channels {
+        // channels specified, disable all others
+        config.select_channels(&channels)?;
+    }
+    info!("config: {:?}", config);
+    config.apply_to_interface(&mut i)?;
+
+    // frames are written from this thread, so slow disks don't hold up the
+    // receive thread
+    let (frame_send, frame_recv) = channel();
+    info!("starting record");
+    // frame timestamps are relative to the interface's time base
+    let base = TimeBase::now();
+    i.set_time_base(base);
+    let mut printer = Printer::new(format, timestamps, false);
+    printer.set_time_base(&base);
+    let mut recorder = Recorder {
+        printer,
+        segments,
+        filter,
+        start,
+        stop,
+        pre_trigger,
+        buffer: VecDeque::new(),
+        state,
+        count: 0,
+    };
+    i.start(move |mut f: Frame| {
+        if f.timestamp.is_none() {
+            f.timestamp = Some(base.elapsed(Instant::now()));
+        }
+        let _ = frame_send.send(f);
+    })?;
+
+    let mut result = Ok(());
+    while !helpers::check_ctrlc(&flag) {
+        match frame_recv.recv_timeout(POLL_INTERVAL) {
+            Ok(f) => match recorder.push(f) {
+                Ok(true) => {}
+                Ok(false) => break,
+                Err(e) => {
+                    result = Err(io_error(&recorder.segments.path, e));
+                    break;
+                }
+            },
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+    }
+
+    i.stop()?;
+    // record frames received before the interface stopped
+    if result.is_ok() {
+        for f in frame_recv.try_iter() {
+            match recorder.push(f) {
+                Ok(true) => {}
+                Ok(false) => break,
+                Err(e) => {
+                    result = Err(io_error(&recorder.segments.path, e));
+                    break;
+                }
+            }
+        }
+    }
+
+    let paths = recorder
+        .segments
+        .finish()
+        .map_err(|e| io_error(&output, e))?;
+    result?;
+    eprintln!(
+        "recorded {} frames to {} file{}",
+        recorder.count,
+        paths.len(),
+        if paths.len() == 1 { "" } else { "s" }
+    );
+    for p in paths {
+        info!("wrote {:?}", p);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::GzDecoder;
+    use std::io::Read;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("can-record-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn frame(can_id: u32, ms: u64) -> Frame {
+        Frame {
+            can_id,
+            can_dlc: 1,
+            timestamp: Some(Duration::from_millis(ms)),
+            ..Frame::default()
+        }
+    }
+
+    fn recorder(segments: Segments) -> Recorder {
+        Recorder {
+            printer: Printer::new(Format::Log, Timestamps::Relative, false),
+            segments,
+            filter: None,
+            start: None,
+            stop: None,
+            pre_trigger: Duration::from_secs(0),
+            buffer: VecDeque::new(),
+            state: State::Recording,
+            count: 0,
+        }
+    }
+
+    #[test]
+    fn test_rotation() {
+        let dir = temp_dir("rotation");
+        // each line is 23 bytes, e.g. "(0.001000) can0 100#00\n"
+        let mut r = recorder(Segments::new(
+            dir.join("capture.log"),
+            Some(50),
+            Some(Duration::from_secs(1)),
+            false,
+        ));
+        for n in 0..5 {
+            r.push(frame(0x100, n)).unwrap();
+        }
+        // time limit starts a new segment
+        r.push(frame(0x100, 1004)).unwrap();
+        let paths = r.segments.finish().unwrap();
+
+        let names: Vec<String> = paths
+            .iter()
+            .map(|p| p.file_name().unwrap().to_string_lossy().into_owned())
+            .collect();
+        assert_eq!(
+            names,
+            vec![
+                "capture-0001.log",
+                "capture-0002.log",
+                "capture-0003.log",
+                "capture-0004.log"
+            ]
+        );
+        let lines = |p: &PathBuf| fs::read_to_string(p).unwrap().lines().count();
+        assert_eq!(lines(&paths[0]), 2);
+        assert_eq!(lines(&paths[2]), 1);
+        assert_eq!(lines(&paths[3]), 1);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_triggers() {
+        let dir = temp_dir("triggers");
+        let mut r = recorder(Segments::new(dir.join("capture.log"), None, None, true));
+        r.state = State::Waiting;
+        r.start = Some(Filter::parse("id == 0x7E8").unwrap());
+        r.stop = Some(Filter::parse("id == 0x7FF").unwrap());
+        r.filter = Some(Filter::parse("id != 0x200").unwrap());
+        r.pre_trigger = Duration::from_millis(100);
+
+        assert!(r.push(frame(0x101, 0)).unwrap());
+        assert!(r.push(frame(0x102, 150)).unwrap());
+        assert!(r.push(frame(0x200, 200)).unwrap());
+        assert!(r.push(frame(0x103, 200)).unwrap());
+        assert!(r.push(frame(0x7E8, 250)).unwrap());
+        assert!(r.push(frame(0x104, 300)).unwrap());
+        assert!(!r.push(frame(0x7FF, 350)).unwrap());
+        assert!(!r.push(frame(0x105, 400)).unwrap());
+
+        let paths = r.segments.finish().unwrap();
+        assert_eq!(paths, vec![dir.join("capture.log.gz")]);
+        assert!(!dir.join("capture.log").exists());
+
+        let mut s = String::new();
+        GzDecoder::new(File::open(&paths[0]).unwrap())
+            .read_to_string(&mut s)
+            .unwrap();
+        let ids: Vec<&str> = s
+            .lines()
+            .map(|l| l.split_whitespace().nth(2).unwrap())
+            .collect();
+        // 0x101 is older than the pre-trigger time, 0x200 is filtered
+        assert_eq!(ids, vec!["102#00", "103#00", "7E8#00", "104#00", "7FF#00"]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
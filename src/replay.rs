@@ -0,0 +1,311 @@
+use crate::Error;
+use cantact::{Frame, Interface};
+use clap::ArgMatches;
+use flate2::read::GzDecoder;
+use log::info;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+use std::time::Instant;
+
+use crate::config::Config;
+use crate::filter::Filter;
+use crate::format::LogReader;
+use crate::helpers;
+
+pub fn open(path: &Path) -> Result<LogReader<Box<dyn BufRead>>, Error> {
+    let file = File::open(path)
+        .map_err(|e| Error::IoError(format!("could not open {}: {}", path.display(), e)))?;
+    let reader: Box<dyn BufRead> = if path.extension().is_some_and(|e| e == "gz") {
+        Box::new(BufReader::new(GzDecoder::new(file)))
+    } else {
+        Box::new(BufReader::new(file))
+    };
+    Ok(LogReader::new(reader))
+}
+
+#[derive(Debug, Default)]
+pub struct Summary {
+    pub sent: u64,
+    // frames excluded by the include/exclude filters
+    pub filtered: u64,
+    // number of frames that could not be sent, for each reason
+    pub unsent: BTreeMap<String, u64>,
+}
+
+impl Summary {
+    pub fn unsent_count(&self) -> u64 {
+        self.unsent.values().sum()
+    }
+
+    pub fn print(&self) {
+        println!("sent {} frames", self.sent);
+        if self.filtered > 0 {
+            println!("skipped {} filtered frames", self.filtered);
+        }
+        if self.unsent_count() > 0 {
+            println!("{} frames could not be sent:", self.unsent_count());
+            for (reason, count) in self.unsent.iter() {
+                println!("{:10}  {}", count, reason);
+            }
+        }
+    }
+}
+
+// plays frames from logs, sending them with `send`, also used by send to
+// transmit frames read from stdin
+pub struct Player<S> {
+    // None to send as fast as possible
+    pub speed: Option<f64>,
+    pub map: HashMap<u8, u8>,
+    pub include: Option<Filter>,
+    pub exclude: Option<Filter>,
+    // (enabled, fd) for each channel of the device
+    pub channels: Vec<(bool, bool)>,
+    pub send: S,
+    pub summary: Summary,
+}
+
+impl<S: FnMut(Frame) -> Result<(), String>> Player<S> {
+    // returns why a frame can't be sent, if it can't
+    fn check(&self, f: &Frame) -> Option<String> {
+        if f.err {
+            return Some(String::from("error frames cannot be transmitted"));
+        }
+        match self.channels.get(f.channel as usize) {
+            None => Some(format!("channel {} does not exist", f.channel)),
+            Some((false, _)) => Some(format!("channel {} is disabled", f.channel)),
+            Some((true, false)) if f.fd => Some(format!(
+                "CAN-FD frame on channel {}, which is not configured for CAN-FD",
+                f.channel
+            )),
+            _ => None,
+        }
+    }
+
+    // play the frames once, returning false if interrupted by ctrl-c
+    pub fn play(
+        &mut self,
+        frames: impl Iterator<Item = Result<Frame, String>>,
+        flag: &Arc<AtomicBool>,
+    ) -> Result<bool, String> {
+        let start = Instant::now();
+        let mut first = None;
+        for f in frames {
+            let mut f = f?;
+            if !self.include.as_ref().is_none_or(|i| i.matches(&f))
+                || self.exclude.as_ref().is_some_and(|x| x.matches(&f))
+            {
+                self.summary.filtered += 1;
+                continue;
+            }
+
+            // frames are sent at their time since the first frame
+            if let (Some(speed), Some(t)) = (self.speed, f.timestamp) {
+                let t0 = *first.get_or_insert(t);
+                let offset = t.saturating_sub(t0).div_f64(speed);
+                if !helpers::sleep_until(start + offset, flag) {
+                    return Ok(false);
+                }
+            } else if helpers::check_ctrlc(flag) {
+                return Ok(false);
+            }
+
+            if let Some(ch) = self.map.get(&f.channel) {
+                f.channel = *ch;
+            }
+            let result = match self.check(&f) {
+                Some(reason) => Err(reason),
+                None => (self.send)(f),
+            };
+            match result {
+                Ok(()) => self.summary.sent += 1,
+                Err(reason) => *self.summary.unsent.entry(reason).or_insert(0) += 1,
+            }
+        }
+        Ok(true)
+    }
+}
+
+fn parse_map(matches: &ArgMatches) -> Result<HashMap<u8, u8>, Error> {
+    let mut map = HashMap::new();
+    for m in matches.values_of("map").into_iter().flatten() {
+        let pair = m.split_once(':').and_then(|(from, to)| {
+            Some((
+                from.trim().parse::<u8>().ok()?,
+                to.trim().parse::<u8>().ok()?,
+            ))
+        });
+        match pair {
+            Some((from, to)) => map.insert(from, to),
+            None => {
+                return Err(Error::InvalidArgument(format!(
+                    "invalid channel mapping {}, expected FROM:TO",
+                    m
+                )))
+            }
+        };
+    }
+    Ok(map)
+}
+
+fn parse_filter(matches: &ArgMatches, name: &str) -> Result<Option<Filter>, Error> {
+    match matches.value_of(name) {
+        Some(s) => Filter::parse(s)
+            .map(Some)
+            .map_err(|e| Error::InvalidArgument(format!("invalid {} expression: {}", name, e))),
+        None => Ok(None),
+    }
+}
+
+pub fn cmd(matches: &ArgMatches) -> Result<(), Error> {
+    let flag = helpers::initialize_ctrlc()?;
+    let mut config = Config::read(matches)?;
+    info!("config: {:?}", config);
+
+    let path = Path::new(matches.value_of("file").unwrap());
+    let speed = if matches.is_present("fast") {
+        None
+    } else {
+        match helpers::parse_arg::<f64>(matches, "speed")? {
+            Some(s) if s > 0.0 && s.is_finite() => Some(s),
+            Some(_) => return Err(Error::InvalidArgument(String::from("invalid speed value"))),
+            None => Some(1.0),
+        }
+    };
+    // 0 loops forever
+    let loops = helpers::parse_arg::<u64>(matches, "loop")?.unwrap_or(1);
+
+    // check the file can be read before starting the device
+    open(path)?;
+
+    // initialize the interface
+    let mut i = Interface::new()?;
+    config.fit_to_interface(&i)?;
+    config.apply_to_interface(&mut i)?;
+
+    let channels = config.channels.iter().map(|c| (c.enabled, c.fd)).collect();
+
+    info!("starting replay");
+    i.start(move |_: Frame| {})?;
+
+    // the player borrows the interface until playing is finished
+    let (summary, result) = {
+        let mut player = Player {
+            speed,
+            map: parse_map(matches)?,
+            include: parse_filter(matches, "include")?,
+            exclude: parse_filter(matches, "exclude")?,
+            channels,
+            send: |f: Frame| i.send(f).map_err(|e| e.to_string()),
+            summary: Summary::default(),
+        };
+
+        let mut result = Ok(());
+        let mut n = 0;
+        while loops == 0 || n < loops {
+            n += 1;
+            info!("playing {:?}, pass {}", path, n);
+            match open(path).and_then(|frames| {
+                player
+                    .play(frames, &flag)
+                    .map_err(|e| Error::InvalidArgument(format!("{}: {}", path.display(), e)))
+            }) {
+                Ok(true) => {}
+                Ok(false) => break,
+                Err(e) => {
+                    result = Err(e);
+                    break;
+                }
+            }
+        }
+        (player.summary, result)
+    };
+
+    i.stop()?;
+
+    summary.print();
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn frame(channel: u8, can_id: u32, ms: u64) -> Result<Frame, String> {
+        Ok(Frame {
+            channel,
+            can_id,
+            timestamp: Some(Duration::from_millis(ms)),
+            ..Frame::default()
+        })
+    }
+
+    #[test]
+    fn test_play() {
+        let flag = Arc::new(AtomicBool::new(false));
+        let mut sent = Vec::new();
+        let mut player = Player {
+            speed: Some(2.0),
+            map: vec![(1, 0), (0, 2)].into_iter().collect(),
+            include: Some(Filter::parse("id < 0x400").unwrap()),
+            exclude: Some(Filter::parse("id == 0x200").unwrap()),
+            channels: vec![(true, false), (true, false), (false, false)],
+            send: |f: Frame| {
+                sent.push((f.channel, f.can_id, Instant::now()));
+                Ok(())
+            },
+            summary: Summary::default(),
+        };
+
+        let mut fd = frame(1, 0x300, 120).unwrap();
+        fd.fd = true;
+        let frames = vec![
+            frame(1, 0x100, 1000),
+            frame(1, 0x200, 1050),
+            frame(1, 0x400, 1100),
+            frame(0, 0x100, 1100),
+            Ok(fd),
+            frame(1, 0x101, 1200),
+        ];
+        let start = Instant::now();
+        assert!(player.play(frames.into_iter(), &flag).unwrap());
+        let summary = player.summary;
+
+        assert_eq!(summary.sent, 2);
+        assert_eq!(summary.filtered, 2);
+        assert_eq!(summary.unsent["channel 2 is disabled"], 1);
+        assert_eq!(
+            summary.unsent["CAN-FD frame on channel 0, which is not configured for CAN-FD"],
+            1
+        );
+        assert_eq!(sent[0].0, 0);
+        assert_eq!(sent[1].1, 0x101);
+        // 200 ms of log time at double speed, which may take longer on a
+        // loaded machine
+        assert!(sent[1].2 >= sent[0].2);
+        assert!(sent[1].2 - start >= Duration::from_millis(100));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let flag = Arc::new(AtomicBool::new(false));
+        let mut player = Player {
+            speed: None,
+            map: HashMap::new(),
+            include: None,
+            exclude: None,
+            channels: vec![(true, false)],
+            send: |_: Frame| Err(String::from("bus off")),
+            summary: Summary::default(),
+        };
+        let text = "123#00\n123#XY\n";
+        let result = player.play(LogReader::new(text.as_bytes()), &flag);
+        assert!(result.unwrap_err().starts_with("line 2: "));
+        assert_eq!(player.summary.unsent["bus off"], 1);
+    }
+}
@@ -2,54 +2,237 @@ use crate::Error;
 use cantact::{Frame, Interface};
 use clap::ArgMatches;
 use log::info;
+use std::collections::HashMap;
+use std::io;
+use std::sync::atomic::AtomicBool;
+use std::sync::mpsc::{channel, sync_channel, Receiver, RecvTimeoutError};
+use std::sync::Arc;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use crate::config::Config;
+use crate::format::LogReader;
 use crate::helpers;
+use crate::replay::{Player, Summary};
 
-pub fn cmd(matches: &ArgMatches) -> Result<(), Error> {
-    let flag = helpers::initialize_ctrlc();
-
-    let mut config = Config::read();
-
-    let ch = helpers::parse_channel(matches)?;
-    match ch {
-        None => { /* no channel specified, follow config */ }
-        Some(ch) => {
-            // channel specified, disable all others
-            for n in 0..config.channels.len() {
-                if n != ch {
-                    config.channels[n].enabled = false;
-                }
+// time to wait for the device to report that a frame was transmitted
+const ECHO_TIMEOUT: Duration = Duration::from_secs(1);
+// how often reading from stdin checks for ctrl-c
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+// number of frames read ahead from stdin
+const STDIN_QUEUE_SIZE: usize = 64;
+// options that describe the frame to send, which can't be used with stdin
+const FRAME_OPTIONS: [&str; 7] = ["data", "extended", "rtr", "fd", "brs", "count", "interval"];
+
+fn parse_frame(matches: &ArgMatches, channel: usize) -> Result<Frame, Error> {
+    let identifier = matches
+        .value_of("identifier")
+        .ok_or_else(|| Error::InvalidArgument(String::from("missing identifier to send")))?;
+    let (can_id, ext) = helpers::parse_id(identifier)?;
+    let data = match matches.value_of("data") {
+        Some(s) => helpers::parse_data(s)?,
+        None => vec![],
+    };
+    let rtr = matches.is_present("rtr");
+    let fd = matches.is_present("fd");
+
+    if rtr && fd {
+        return Err(Error::InvalidArgument(String::from(
+            "CAN-FD frames cannot be RTR frames",
+        )));
+    }
+    if rtr && !data.is_empty() {
+        return Err(Error::InvalidArgument(String::from(
+            "RTR frames cannot contain data",
+        )));
+    }
+
+    // pad FD data to the length of the smallest DLC that fits it
+    let can_dlc = helpers::dlc_for_len(data.len(), fd)?;
+    let mut f = Frame {
+        can_id,
+        can_dlc,
+        channel: channel as u8,
+        ext: ext || matches.is_present("extended"),
+        fd,
+        brs: matches.is_present("brs"),
+        rtr,
+        ..Frame::default()
+    };
+    f.data[..data.len()].copy_from_slice(&data);
+    Ok(f)
+}
+
+// send a frame, and wait for the device to echo it back once it has been
+// transmitted on the bus
+fn transmit(i: &Interface, f: &Frame, echoes: &Receiver<Frame>) -> Result<(), Error> {
+    i.send(f.clone())?;
+
+    let deadline = Instant::now() + ECHO_TIMEOUT;
+    loop {
+        let timeout = deadline.saturating_duration_since(Instant::now());
+        match echoes.recv_timeout(timeout) {
+            Ok(e) if e.channel == f.channel && e.can_id == f.can_id => return Ok(()),
+            Ok(_) => continue,
+            Err(RecvTimeoutError::Timeout) => {
+                return Err(Error::TransmitFailed(String::from(
+                    "frame was not acknowledged by another node",
+                )))
+            }
+            Err(RecvTimeoutError::Disconnected) => {
+                return Err(Error::TransmitFailed(String::from("device stopped")))
             }
         }
     }
+}
+
+// frames read from stdin on a separate thread, so that reading can be
+// interrupted by ctrl-c. Ends when stdin is closed or ctrl-c is pressed.
+fn stdin_frames(flag: Arc<AtomicBool>) -> impl Iterator<Item = Result<Frame, String>> {
+    let (send, recv) = sync_channel(STDIN_QUEUE_SIZE);
+    thread::spawn(move || {
+        for f in LogReader::new(io::stdin().lock()) {
+            if send.send(f).is_err() {
+                break;
+            }
+        }
+    });
+    std::iter::from_fn(move || loop {
+        if helpers::check_ctrlc(&flag) {
+            return None;
+        }
+        match recv.recv_timeout(POLL_INTERVAL) {
+            Ok(f) => return Some(f),
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Disconnected) => return None,
+        }
+    })
+}
+
+// send frames read from stdin, one per line in the log, JSON or CSV format
+fn send_stdin(matches: &ArgMatches, ch: Option<usize>, flag: Arc<AtomicBool>) -> Result<(), Error> {
+    if let Some(o) = FRAME_OPTIONS.iter().find(|o| matches.is_present(o)) {
+        return Err(Error::InvalidArgument(format!(
+            "--{} cannot be used when reading frames from stdin",
+            o
+        )));
+    }
+    let mut config = Config::read(matches)?;
+
+    let mut i = Interface::new()?;
+    config.fit_to_interface(&i)?;
+    if let Some(ch) = ch {
+        config.select_channels(&[ch])?;
+    }
     info!("config: {:?}", config);
+    config.apply_to_interface(&mut i)?;
+    let channels = config.channels.iter().map(|c| (c.enabled, c.fd)).collect();
+
+    info!("sending frames from stdin");
+    i.start(move |_: Frame| {})?;
+
+    let (summary, result) = {
+        let mut player = Player {
+            speed: if matches.is_present("timestamps") {
+                Some(1.0)
+            } else {
+                None
+            },
+            map: HashMap::new(),
+            include: None,
+            exclude: None,
+            channels,
+            send: |f: Frame| i.send(f).map_err(|e| e.to_string()),
+            summary: Summary::default(),
+        };
+        let frames = stdin_frames(flag.clone()).map(|f| {
+            f.map(|mut f| {
+                if let Some(ch) = ch {
+                    f.channel = ch as u8;
+                }
+                f
+            })
+        });
+        let result = player
+            .play(frames, &flag)
+            .map_err(|e| Error::InvalidArgument(format!("stdin: {}", e)));
+        (player.summary, result)
+    };
+
+    i.stop()?;
+    summary.print();
+    result?;
+    match summary.unsent_count() {
+        0 => Ok(()),
+        n => Err(Error::TransmitFailed(format!(
+            "{} frames could not be sent",
+            n
+        ))),
+    }
+}
+
+pub fn cmd(matches: &ArgMatches) -> Result<(), Error> {
+    let flag = helpers::initialize_ctrlc()?;
+    // frames read from stdin are sent on the channel given, or on the channel
+    // they were logged on if the channel is also -
+    match (matches.value_of("channel"), matches.value_of("identifier")) {
+        (Some("-"), None) | (Some("-"), Some("-")) => return send_stdin(matches, None, flag),
+        (Some("-"), Some(_)) => {
+            return Err(Error::InvalidArgument(String::from(
+                "channel - can only be used when reading frames from stdin",
+            )))
+        }
+        (_, Some("-")) => return send_stdin(matches, helpers::parse_channel(matches)?, flag),
+        _ => {}
+    }
+
+    let mut config = Config::read(matches)?;
+
+    let ch = helpers::parse_channel(matches)?.unwrap_or(0);
+    let f = parse_frame(matches, ch)?;
+    let count = helpers::parse_arg::<u64>(matches, "count")?.unwrap_or(1);
+    let interval = match matches.value_of("interval") {
+        Some(s) => helpers::parse_duration(s)?,
+        None => Duration::from_millis(100),
+    };
 
     // initialize the interface
     let mut i = Interface::new()?;
+    config.fit_to_interface(&i)?;
+    // only the transmit channel is used
+    config.select_channels(&[ch])?;
+    if f.fd && !config.channels[ch].fd {
+        return Err(Error::InvalidArgument(format!(
+            "channel {} is not configured for CAN-FD, use `can cfg -c {} --fd`",
+            ch, ch
+        )));
+    }
+    info!("config: {:?}", config);
     config.apply_to_interface(&mut i)?;
 
     // start the device
-    info!("starting dump");
-    i.start(move |_: Frame| {}).expect("failed to start device");
+    info!("starting send");
+    let (echo_send, echo_recv) = channel();
+    i.start(move |f: Frame| {
+        if f.loopback {
+            let _ = echo_send.send(f);
+        }
+    })?;
 
-    let mut count = 0;
-    let mut f = Frame::default();
-    f.can_dlc = 8;
-    loop {
-        f.can_id = count % 0x800;
-        i.send(f.clone()).unwrap();
-        count += 1;
-        if count % 1000 == 0 {
-            println!("{}", count)
+    let mut result = Ok(());
+    let start = Instant::now();
+    for n in 0..count {
+        // absolute deadlines keep repeated frames from drifting
+        if !helpers::sleep_until(start + interval * n as u32, &flag) {
+            break;
         }
-        thread::sleep(Duration::from_millis(10));
-        if helpers::check_ctrlc(&flag) {
+        if let Err(e) = transmit(&i, &f, &echo_recv) {
+            result = Err(e);
             break;
         }
+        info!("sent frame {} of {}", n + 1, count);
     }
+
     i.stop()?;
-    Ok(())
+    result
 }
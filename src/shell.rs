@@ -0,0 +1,562 @@
+use crate::Error;
+use cantact::{Channel, Frame, Interface, Job, JobId};
+use clap::ArgMatches;
+use log::{info, warn};
+use rustyline::error::ReadlineError;
+use rustyline::{DefaultEditor, ExternalPrinter};
+use std::collections::BTreeMap;
+use std::fs;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::config::Config;
+use crate::filter::Filter;
+use crate::format::{self, Format, Printer, Timestamps};
+use crate::helpers;
+
+// history of entered lines, in the configuration directory
+const HISTORY_FILE: &str = "shell_history";
+// limits macros that run themselves
+const MAX_MACRO_DEPTH: usize = 16;
+
+const HELP: &str = "\
+commands:
+  send FRAME             send a frame, e.g. 123#DEADBEEF, 123##1DEADBEEF (CAN-FD)
+                         or can1 123#00 to send on another channel
+  every PERIOD FRAME     send a frame periodically, e.g. every 100ms 123#00
+  jobs                   list periodic jobs
+  stop [N]               stop periodic job N, or every job
+  rx [on|off]            show received frames, or toggle showing them
+  filter [EXPR|off]      only show received frames matching a filter expression
+  channel [N]            select the channel used by send, every and set
+  set OPTION VALUE       change the configuration of the selected channel
+                         options: bitrate, data-bitrate, sample-point, sjw,
+                         enabled, monitor, loopback, fd, one-shot, triple-sample
+  config                 show the channel configuration
+  macro [NAME [COMMANDS]]
+                         define a macro running commands separated by ';',
+                         or show macros
+  unmacro NAME           remove a macro
+  wait DURATION          pause, e.g. wait 500ms
+  help                   show this help
+  quit                   leave the shell
+Commands can be separated by ';'. Changes made with set are not saved, use can cfg for that.";
+
+#[derive(Debug)]
+enum Command {
+    Help,
+    Send(Frame),
+    Every(Duration, Frame),
+    Jobs,
+    // None stops every job
+    Stop(Option<usize>),
+    // None toggles
+    Rx(Option<bool>),
+    // None shows the filter, Some(None) removes it
+    Filter(Option<Option<String>>),
+    Channel(Option<usize>),
+    Set(String, String),
+    Config,
+    Macro(Option<(String, Option<String>)>),
+    Unmacro(String),
+    Wait(Duration),
+    Quit,
+    // a macro, or an unknown command
+    Run(String),
+}
+
+// commands that can't be used as macro names
+const COMMANDS: [&str; 16] = [
+    "help", "?", "send", "every", "jobs", "stop", "rx", "filter", "channel", "set", "config",
+    "macro", "unmacro", "wait", "quit", "exit",
+];
+
+fn parse_bool(s: &str) -> Result<bool, String> {
+    match s {
+        "on" | "true" | "yes" | "1" => Ok(true),
+        "off" | "false" | "no" | "0" => Ok(false),
+        s => Err(format!("invalid value '{}', expected on or off", s)),
+    }
+}
+
+// parses a frame in candump notation, sent on `channel` unless an interface
+// is given
+fn parse_frame(s: &str, channel: usize) -> Result<Frame, String> {
+    let mut f = format::parse_log_line(s)?;
+    if f.err {
+        return Err(String::from("error frames cannot be sent"));
+    }
+    if s.split_whitespace().count() == 1 {
+        f.channel = channel as u8;
+    }
+    Ok(f)
+}
+
+fn parse_command(line: &str, channel: usize) -> Result<Command, String> {
+    let line = line.trim();
+    let (cmd, rest) = match line.split_once(char::is_whitespace) {
+        Some((cmd, rest)) => (cmd, rest.trim()),
+        None => (line, ""),
+    };
+    let none = |c: Command| {
+        if rest.is_empty() {
+            Ok(c)
+        } else {
+            Err(format!("{} takes no arguments", cmd))
+        }
+    };
+    let required = |what: &str| {
+        if rest.is_empty() {
+            Err(format!("{} requires {}", cmd, what))
+        } else {
+            Ok(rest)
+        }
+    };
+    match cmd {
+        "help" | "?" => none(Command::Help),
+        "send" => Ok(Command::Send(parse_frame(required("a frame")?, channel)?)),
+        "every" => {
+            let (period, frame) = required("a period and a frame")?
+                .split_once(char::is_whitespace)
+                .ok_or_else(|| String::from("every requires a period and a frame"))?;
+            let period = helpers::parse_duration(period).map_err(|e| e.to_string())?;
+            if period == Duration::from_secs(0) {
+                return Err(String::from("period must be greater than 0"));
+            }
+            Ok(Command::Every(period, parse_frame(frame, channel)?))
+        }
+        "jobs" => none(Command::Jobs),
+        "stop" => match rest {
+            "" | "all" => Ok(Command::Stop(None)),
+            n => n
+                .parse()
+                .map(|n| Command::Stop(Some(n)))
+                .map_err(|_| format!("invalid job '{}'", n)),
+        },
+        "rx" => match rest {
+            "" => Ok(Command::Rx(None)),
+            s => Ok(Command::Rx(Some(parse_bool(s)?))),
+        },
+        "filter" => match rest {
+            "" => Ok(Command::Filter(None)),
+            "off" => Ok(Command::Filter(Some(None))),
+            expr => Ok(Command::Filter(Some(Some(String::from(expr))))),
+        },
+        "channel" => match rest {
+            "" => Ok(Command::Channel(None)),
+            n => n
+                .parse()
+                .map(|n| Command::Channel(Some(n)))
+                .map_err(|_| format!("invalid channel '{}'", n)),
+        },
+        "set" => {
+            let (option, value) = required("an option and a value")?
+                .split_once(char::is_whitespace)
+                .ok_or_else(|| String::from("set requires an option and a value"))?;
+            Ok(Command::Set(
+                String::from(option),
+                String::from(value.trim()),
+            ))
+        }
+        "config" => none(Command::Config),
+        "macro" => match rest.split_once(char::is_whitespace) {
+            _ if rest.is_empty() => Ok(Command::Macro(None)),
+            None => Ok(Command::Macro(Some((String::from(rest), None)))),
+            Some((name, _)) if COMMANDS.contains(&name) => {
+                Err(format!("{} is a command, and cannot be a macro", name))
+            }
+            Some((name, body)) => Ok(Command::Macro(Some((
+                String::from(name),
+                Some(String::from(body.trim())),
+            )))),
+        },
+        "unmacro" => Ok(Command::Unmacro(String::from(required("a macro name")?))),
+        "wait" => Ok(Command::Wait(
+            helpers::parse_duration(required("a duration")?).map_err(|e| e.to_string())?,
+        )),
+        "quit" | "exit" => none(Command::Quit),
+        name if rest.is_empty() => Ok(Command::Run(String::from(name))),
+        name => Err(format!("unknown command '{}', try help", name)),
+    }
+}
+
+// changes one option of a channel's configuration
+fn set_option(ch: &mut Channel, option: &str, value: &str) -> Result<(), String> {
+    let number = || {
+        value
+            .parse::<u32>()
+            .map_err(|_| format!("invalid {} value '{}'", option, value))
+    };
+    match option {
+        "bitrate" => ch.bitrate = number()?,
+        "data-bitrate" | "data_bitrate" => ch.data_bitrate = number()?,
+        "sample-point" => {
+            ch.sample_point = match value {
+                "auto" => None,
+                s => Some(
+                    crate::cfg::parse_sample_point(s)
+                        .ok_or_else(|| format!("invalid sample-point value '{}'", s))?,
+                ),
+            }
+        }
+        "sjw" => {
+            ch.sjw = match value {
+                "auto" => None,
+                _ => match number()? {
+                    0 => return Err(String::from("sjw must be greater than 0")),
+                    sjw => Some(sjw),
+                },
+            }
+        }
+        "enabled" => ch.enabled = parse_bool(value)?,
+        "monitor" => ch.monitor = parse_bool(value)?,
+        "loopback" => ch.loopback = parse_bool(value)?,
+        "fd" => ch.fd = parse_bool(value)?,
+        "one-shot" => ch.one_shot = parse_bool(value)?,
+        "triple-sample" => ch.triple_sample = parse_bool(value)?,
+        o => return Err(format!("unknown option '{}'", o)),
+    }
+    Ok(())
+}
+
+struct Shell {
+    i: Interface,
+    config: Config,
+    // channel used by send, every and set
+    channel: usize,
+    // periodic jobs, by the number shown to the user
+    jobs: BTreeMap<usize, (JobId, Frame, Duration)>,
+    next_job: usize,
+    macros: BTreeMap<String, String>,
+    rx: Arc<AtomicBool>,
+    filter: Arc<Mutex<Option<Filter>>>,
+    // the expression the filter was parsed from
+    filter_expr: Option<String>,
+}
+
+impl Shell {
+    // returns why a frame can't be sent, if it can't
+    fn check(&self, f: &Frame) -> Result<(), String> {
+        match self.config.channels.get(f.channel as usize) {
+            None => Err(format!("channel {} does not exist", f.channel)),
+            Some(c) if !c.enabled => Err(format!(
+                "channel {} is disabled, use set enabled on",
+                f.channel
+            )),
+            Some(c) if f.fd && !c.fd => Err(format!(
+                "channel {} is not configured for CAN-FD, use set fd on",
+                f.channel
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    // runs a line of commands separated by ';', returning false to quit
+    fn execute(&mut self, line: &str, depth: usize) -> Result<bool, String> {
+        if depth > MAX_MACRO_DEPTH {
+            return Err(String::from("macros are nested too deeply"));
+        }
+        // the body of a macro definition is not split
+        let trimmed = line.trim_start();
+        if trimmed.starts_with("macro ") || trimmed == "macro" {
+            return self.run(parse_command(line, self.channel)?, depth);
+        }
+        for part in line.split(';').filter(|p| !p.trim().is_empty()) {
+            if !self.run(parse_command(part, self.channel)?, depth)? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    fn run(&mut self, cmd: Command, depth: usize) -> Result<bool, String> {
+        let err = |e: cantact::Error| e.to_string();
+        match cmd {
+            Command::Help => println!("{}", HELP),
+            Command::Send(f) => {
+                self.check(&f)?;
+                self.i.send(f).map_err(err)?;
+            }
+            Command::Every(period, f) => {
+                self.check(&f)?;
+                let id = self
+                    .i
+                    .scheduler()
+                    .add(Job::new(f.clone(), period))
+                    .map_err(err)?;
+                self.next_job += 1;
+                self.jobs.insert(self.next_job, (id, f, period));
+                println!("job {}", self.next_job);
+            }
+            Command::Jobs => {
+                let mut printer = Printer::new(Format::Log, Timestamps::None, false);
+                for (n, (id, f, period)) in self.jobs.iter() {
+                    let stats = self.i.scheduler().stats(*id).unwrap_or_default();
+                    println!(
+                        "{:3}  every {:?}  {}  sent {}, failed {}",
+                        n,
+                        period,
+                        printer.format(f),
+                        stats.sent,
+                        stats.failed
+                    );
+                }
+            }
+            Command::Stop(None) => {
+                for (id, _, _) in self.jobs.values() {
+                    self.i.scheduler().remove(*id);
+                }
+                self.jobs.clear();
+            }
+            Command::Stop(Some(n)) => match self.jobs.remove(&n) {
+                Some((id, _, _)) => {
+                    self.i.scheduler().remove(id);
+                }
+                None => return Err(format!("job {} does not exist", n)),
+            },
+            Command::Rx(on) => {
+                let on = on.unwrap_or(!self.rx.load(Ordering::SeqCst));
+                self.rx.store(on, Ordering::SeqCst);
+                println!("rx {}", if on { "on" } else { "off" });
+            }
+            Command::Filter(None) => match &self.filter_expr {
+                Some(expr) => println!("filter: {}", expr),
+                None => println!("no filter"),
+            },
+            Command::Filter(Some(expr)) => {
+                let filter = match &expr {
+                    Some(expr) => {
+                        Some(Filter::parse(expr).map_err(|e| format!("invalid filter: {}", e))?)
+                    }
+                    None => None,
+                };
+                *self.filter.lock().unwrap() = filter;
+                self.filter_expr = expr;
+            }
+            Command::Channel(None) => println!("channel {}", self.channel),
+            Command::Channel(Some(ch)) => {
+                if ch >= self.config.channels.len() {
+                    return Err(format!("channel {} does not exist", ch));
+                }
+                self.channel = ch;
+            }
+            Command::Set(option, value) => {
+                let mut ch = self.config.channels[self.channel].clone();
+                set_option(&mut ch, &option, &value)?;
+                if ch.fd && !self.i.supports_fd() {
+                    return Err(String::from("CAN-FD is not supported by this device"));
+                }
+                self.i.reconfigure(self.channel, ch.clone()).map_err(err)?;
+                self.config.channels[self.channel] = ch;
+            }
+            Command::Config => print!("{}", self.config),
+            Command::Macro(None) => {
+                for (name, body) in self.macros.iter() {
+                    println!("{}: {}", name, body);
+                }
+            }
+            Command::Macro(Some((name, None))) => match self.macros.get(&name) {
+                Some(body) => println!("{}: {}", name, body),
+                None => return Err(format!("macro {} does not exist", name)),
+            },
+            Command::Macro(Some((name, Some(body)))) => {
+                self.macros.insert(name, body);
+            }
+            Command::Unmacro(name) => {
+                if self.macros.remove(&name).is_none() {
+                    return Err(format!("macro {} does not exist", name));
+                }
+            }
+            Command::Wait(d) => thread::sleep(d),
+            Command::Quit => return Ok(false),
+            Command::Run(name) => match self.macros.get(&name) {
+                Some(body) => return self.execute(&body.clone(), depth + 1),
+                None => return Err(format!("unknown command '{}', try help", name)),
+            },
+        }
+        Ok(true)
+    }
+}
+
+pub fn cmd(matches: &ArgMatches) -> Result<(), Error> {
+    let mut config = Config::read(matches)?;
+    let channel = helpers::parse_channel(matches)?.unwrap_or(0);
+
+    // initialize the interface
+    let mut i = Interface::new()?;
+    config.fit_to_interface(&i)?;
+    if channel >= config.channels.len() {
+        return Err(Error::InvalidArgument(format!(
+            "channel {} does not exist",
+            channel
+        )));
+    }
+    info!("config: {:?}", config);
+    config.apply_to_interface(&mut i)?;
+
+    let mut editor = DefaultEditor::new()
+        .map_err(|e| Error::IoError(format!("could not open terminal: {}", e)))?;
+    let history = crate::config::config_file(HISTORY_FILE);
+    if let Some(path) = &history {
+        // there is no history the first time the shell is used
+        let _ = editor.load_history(path);
+    }
+
+    // received frames are printed above the prompt, or directly if the
+    // input is not a terminal
+    let (lines, printed) = mpsc::channel::<String>();
+    match editor.create_external_printer() {
+        Ok(mut printer) => thread::spawn(move || {
+            for line in printed {
+                let _ = printer.print(format!("{}\n", line));
+            }
+        }),
+        Err(_) => thread::spawn(move || {
+            for line in printed {
+                println!("{}", line);
+            }
+        }),
+    };
+
+    let rx = Arc::new(AtomicBool::new(false));
+    let filter: Arc<Mutex<Option<Filter>>> = Arc::new(Mutex::new(None));
+    let mut printer = Printer::new(Format::Human, Timestamps::Relative, false);
+    {
+        let rx = rx.clone();
+        let filter = filter.clone();
+        i.start(move |f: Frame| {
+            // frames sent by the shell are echoed back
+            if f.loopback || !rx.load(Ordering::SeqCst) {
+                return;
+            }
+            if filter
+                .lock()
+                .unwrap()
+                .as_ref()
+                .is_none_or(|x| x.matches(&f))
+            {
+                let _ = lines.send(printer.format(&f));
+            }
+        })?;
+    }
+
+    let mut shell = Shell {
+        i,
+        config,
+        channel,
+        jobs: BTreeMap::new(),
+        next_job: 0,
+        macros: BTreeMap::new(),
+        rx,
+        filter,
+        filter_expr: None,
+    };
+    println!("type help for a list of commands, ctrl-d to quit");
+
+    loop {
+        let line = match editor.readline(&format!("can{}> ", shell.channel)) {
+            Ok(line) => line,
+            // ctrl-c clears the line
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(e) => {
+                warn!("could not read input: {}", e);
+                break;
+            }
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+        let _ = editor.add_history_entry(line.as_str());
+        match shell.execute(&line, 0) {
+            Ok(true) => {}
+            Ok(false) => break,
+            Err(e) => println!("error: {}", e),
+        }
+    }
+
+    if let Some(path) = &history {
+        if let Some(dir) = path.parent() {
+            let _ = fs::create_dir_all(dir);
+        }
+        if let Err(e) = editor.save_history(path) {
+            warn!("could not save history to {}: {}", path.display(), e);
+        }
+    }
+    shell.i.stop()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::DEFAULT_CONFIG;
+
+    #[test]
+    fn test_parse_command() {
+        let send = parse_command("send 123#DEAD", 2).unwrap();
+        match send {
+            Command::Send(f) => {
+                assert_eq!((f.can_id, f.channel, f.data_len()), (0x123, 2, 2));
+            }
+            c => panic!("unexpected command {:?}", c),
+        }
+        match parse_command("send can1 123#00", 2).unwrap() {
+            Command::Send(f) => assert_eq!(f.channel, 1),
+            c => panic!("unexpected command {:?}", c),
+        }
+        match parse_command("every 100ms 7DF#0201", 0).unwrap() {
+            Command::Every(period, f) => {
+                assert_eq!(period, Duration::from_millis(100));
+                assert_eq!(f.can_id, 0x7DF);
+            }
+            c => panic!("unexpected command {:?}", c),
+        }
+        let parse = |line| parse_command(line, 0).unwrap();
+        assert!(matches!(parse("stop"), Command::Stop(None)));
+        assert!(matches!(parse("stop 3"), Command::Stop(Some(3))));
+        assert!(matches!(parse("rx"), Command::Rx(None)));
+        assert!(matches!(parse("rx off"), Command::Rx(Some(false))));
+        assert!(matches!(parse("filter off"), Command::Filter(Some(None))));
+        match parse("set bitrate 250000") {
+            Command::Set(option, value) => assert_eq!((&*option, &*value), ("bitrate", "250000")),
+            c => panic!("unexpected command {:?}", c),
+        }
+        match parse("macro wake send 100#01; wait 10ms") {
+            Command::Macro(Some((name, Some(body)))) => {
+                assert_eq!((&*name, &*body), ("wake", "send 100#01; wait 10ms"))
+            }
+            c => panic!("unexpected command {:?}", c),
+        }
+        assert!(matches!(parse("wake"), Command::Run(name) if name == "wake"));
+
+        assert!(parse_command("send", 0).is_err());
+        assert!(parse_command("send 20000123#00", 0).is_err());
+        assert!(parse_command("every 0 123#00", 0).is_err());
+        assert!(parse_command("jobs 1", 0).is_err());
+        assert!(parse_command("rx maybe", 0).is_err());
+        assert!(parse_command("macro send send 100#01", 0).is_err());
+    }
+
+    #[test]
+    fn test_set_option() {
+        let mut ch = DEFAULT_CONFIG;
+        set_option(&mut ch, "bitrate", "250000").unwrap();
+        set_option(&mut ch, "monitor", "on").unwrap();
+        set_option(&mut ch, "sample-point", "80%").unwrap();
+        set_option(&mut ch, "sjw", "2").unwrap();
+        assert_eq!(ch.bitrate, 250000);
+        assert!(ch.monitor);
+        assert_eq!(ch.sample_point, Some(0.8));
+        assert_eq!(ch.sjw, Some(2));
+
+        set_option(&mut ch, "sjw", "auto").unwrap();
+        assert_eq!(ch.sjw, None);
+        assert!(set_option(&mut ch, "bitrate", "fast").is_err());
+        assert!(set_option(&mut ch, "monitor", "maybe").is_err());
+        assert!(set_option(&mut ch, "speed", "1").is_err());
+    }
+}
@@ -0,0 +1,742 @@
+use crate::Error;
+use cantact::{Frame, Interface};
+use clap::ArgMatches;
+use crossbeam_channel::{unbounded, Receiver, RecvTimeoutError};
+use log::info;
+use serde::Deserialize;
+use std::fmt::Write;
+use std::fs;
+use std::path::Path;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::config::Config;
+use crate::format::{self, Format, Printer, Timestamps};
+use crate::helpers;
+
+// all bits of an extended identifier
+const ID_MASK: u32 = 0x1FFF_FFFF;
+
+fn default_timeout() -> u64 {
+    100
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ScriptFile {
+    // channel used by the tests, unless one is given on the command line
+    #[serde(default)]
+    channel: Option<u8>,
+    // timeout of expectations that don't set one
+    #[serde(default = "default_timeout")]
+    timeout_ms: u64,
+    #[serde(rename = "test", default)]
+    tests: Vec<TestFile>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TestFile {
+    name: String,
+    #[serde(rename = "step", default)]
+    steps: Vec<StepFile>,
+}
+
+// a step as written in a script, exactly one of send, expect, expect_none,
+// wait_ms and repeat must be set
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct StepFile {
+    name: Option<String>,
+    send: Option<String>,
+    expect: Option<String>,
+    expect_none: Option<String>,
+    id_mask: Option<u32>,
+    data_mask: Option<Vec<u8>>,
+    timeout_ms: Option<u64>,
+    wait_ms: Option<u64>,
+    repeat: Option<u32>,
+    #[serde(rename = "step")]
+    steps: Vec<StepFile>,
+}
+
+// frames matched by an expectation
+#[derive(Debug, Clone, PartialEq)]
+struct Pattern {
+    id: u32,
+    id_mask: u32,
+    ext: bool,
+    // bytes compared with the frame's data, frames with less data don't match
+    data: Vec<u8>,
+    data_mask: Vec<u8>,
+}
+
+impl Pattern {
+    // a frame in candump notation, only the bytes given are compared
+    fn parse(s: &str, id_mask: Option<u32>, data_mask: Option<Vec<u8>>) -> Result<Pattern, String> {
+        let f = format::parse_log_line(s)?;
+        let data = f.data[..f.data_len()].to_vec();
+        let data_mask = match data_mask {
+            Some(m) if m.len() != data.len() => {
+                return Err(format!(
+                    "data_mask has {} bytes, but the frame has {}",
+                    m.len(),
+                    data.len()
+                ))
+            }
+            Some(m) => m,
+            None => vec![0xFF; data.len()],
+        };
+        Ok(Pattern {
+            id: f.can_id,
+            id_mask: id_mask.unwrap_or(ID_MASK),
+            ext: f.ext,
+            data,
+            data_mask,
+        })
+    }
+
+    fn matches(&self, f: &Frame) -> bool {
+        if f.err || f.ext != self.ext || (f.can_id ^ self.id) & self.id_mask != 0 {
+            return false;
+        }
+        let data = &f.data[..f.data_len()];
+        data.len() >= self.data.len()
+            && self
+                .data
+                .iter()
+                .zip(data)
+                .zip(&self.data_mask)
+                .all(|((p, d), m)| (p ^ d) & m == 0)
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Action {
+    Send(Frame),
+    Expect(Pattern, Duration),
+    ExpectNone(Pattern, Duration),
+    Wait(Duration),
+    Repeat(u32, Vec<Step>),
+}
+
+#[derive(Debug, Clone)]
+struct Step {
+    name: String,
+    action: Action,
+}
+
+#[derive(Debug, Clone)]
+struct Test {
+    name: String,
+    steps: Vec<Step>,
+}
+
+#[derive(Debug, Clone)]
+struct Script {
+    channel: Option<u8>,
+    tests: Vec<Test>,
+}
+
+impl Step {
+    fn from_file(s: StepFile, timeout: u64) -> Result<Step, String> {
+        let kinds = [
+            s.send.is_some(),
+            s.expect.is_some(),
+            s.expect_none.is_some(),
+            s.wait_ms.is_some(),
+            s.repeat.is_some(),
+        ];
+        if kinds.iter().filter(|k| **k).count() != 1 {
+            return Err(String::from(
+                "a step must have exactly one of send, expect, expect_none, wait_ms and repeat",
+            ));
+        }
+        let expectation = s.expect.is_some() || s.expect_none.is_some();
+        if !expectation && (s.id_mask.is_some() || s.data_mask.is_some() || s.timeout_ms.is_some())
+        {
+            return Err(String::from(
+                "id_mask, data_mask and timeout_ms can only be used with expect and expect_none",
+            ));
+        }
+        if s.repeat.is_none() && !s.steps.is_empty() {
+            return Err(String::from("only repeat steps can contain steps"));
+        }
+        let timeout = Duration::from_millis(s.timeout_ms.unwrap_or(timeout));
+
+        let (name, action) = if let Some(send) = s.send {
+            let f = format::parse_log_line(&send)?;
+            if f.err {
+                return Err(String::from("error frames cannot be sent"));
+            }
+            (format!("send {}", send), Action::Send(f))
+        } else if let Some(expect) = s.expect {
+            let p = Pattern::parse(&expect, s.id_mask, s.data_mask)?;
+            (format!("expect {}", expect), Action::Expect(p, timeout))
+        } else if let Some(none) = s.expect_none {
+            let p = Pattern::parse(&none, s.id_mask, s.data_mask)?;
+            (
+                format!("expect none {}", none),
+                Action::ExpectNone(p, timeout),
+            )
+        } else if let Some(ms) = s.wait_ms {
+            (
+                format!("wait {} ms", ms),
+                Action::Wait(Duration::from_millis(ms)),
+            )
+        } else {
+            let count = s.repeat.unwrap_or_default();
+            let steps = s
+                .steps
+                .into_iter()
+                .enumerate()
+                .map(|(n, s)| {
+                    Step::from_file(s, timeout.as_millis() as u64)
+                        .map_err(|e| format!("step {}: {}", n + 1, e))
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            (format!("repeat {}", count), Action::Repeat(count, steps))
+        };
+        Ok(Step {
+            name: s.name.unwrap_or(name),
+            action,
+        })
+    }
+}
+
+fn parse_script(s: &str) -> Result<Script, String> {
+    let file: ScriptFile = toml::from_str(s).map_err(|e| e.to_string())?;
+    let mut tests = Vec::new();
+    for TestFile { name, steps: raw } in file.tests {
+        let mut steps = Vec::new();
+        for (n, s) in raw.into_iter().enumerate() {
+            steps.push(
+                Step::from_file(s, file.timeout_ms)
+                    .map_err(|e| format!("test '{}' step {}: {}", name, n + 1, e))?,
+            );
+        }
+        tests.push(Test { name, steps });
+    }
+    Ok(Script {
+        channel: file.channel,
+        tests,
+    })
+}
+
+#[derive(Debug, Clone)]
+struct StepResult {
+    name: String,
+    passed: bool,
+    // measurement if the step passed, or why it failed
+    message: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+struct TestResult {
+    name: String,
+    steps: Vec<StepResult>,
+    time: Duration,
+}
+impl TestResult {
+    fn failure(&self) -> Option<&StepResult> {
+        self.steps.iter().find(|s| !s.passed)
+    }
+}
+
+struct SuiteResult {
+    name: String,
+    tests: Vec<TestResult>,
+}
+
+fn millis(d: Duration) -> String {
+    format!("{:.1} ms", d.as_secs_f64() * 1000.0)
+}
+
+fn describe(f: &Frame) -> String {
+    Printer::new(Format::Log, Timestamps::None, false).format(f)
+}
+
+// runs tests, sending frames with `send` and receiving frames from `recv`
+struct Runner<'a, S> {
+    send: S,
+    recv: Receiver<(Instant, Frame)>,
+    channel: u8,
+    // expectations are timed from the last frame sent
+    last_send: Instant,
+    // frames received since then that no expectation has matched
+    received: Vec<(Instant, Frame)>,
+    flag: &'a Arc<AtomicBool>,
+}
+
+impl<'a, S: FnMut(Frame) -> Result<(), String>> Runner<'a, S> {
+    // discards frames received so far
+    fn drain(&mut self) {
+        while self.recv.try_recv().is_ok() {}
+        self.received.clear();
+    }
+
+    // returns the first frame matching `p` received within `timeout` on the
+    // channel under test, frames received on other channels are ignored
+    fn wait_for(
+        &mut self,
+        p: &Pattern,
+        timeout: Duration,
+    ) -> Result<Option<(Instant, Frame)>, String> {
+        let deadline = Instant::now() + timeout;
+        if let Some(n) = self.received.iter().position(|(_, f)| p.matches(f)) {
+            return Ok(Some(self.received.remove(n)));
+        }
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            match self.recv.recv_timeout(remaining) {
+                Ok((t, f)) if f.channel != self.channel => {
+                    if t > deadline {
+                        return Ok(None);
+                    }
+                }
+                Ok((t, f)) if t <= deadline && p.matches(&f) => return Ok(Some((t, f))),
+                Ok((t, f)) => {
+                    self.received.push((t, f));
+                    if t > deadline {
+                        return Ok(None);
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) => return Ok(None),
+                Err(RecvTimeoutError::Disconnected) => return Err(String::from("device stopped")),
+            }
+        }
+    }
+
+    fn run(&mut self, test: &Test) -> TestResult {
+        let start = Instant::now();
+        self.drain();
+        self.last_send = start;
+        let mut steps = Vec::new();
+        self.run_steps(&test.steps, "", &mut steps);
+        TestResult {
+            name: test.name.clone(),
+            steps,
+            time: start.elapsed(),
+        }
+    }
+
+    // returns false when a step fails, the remaining steps are not run
+    fn run_steps(&mut self, steps: &[Step], suffix: &str, results: &mut Vec<StepResult>) -> bool {
+        for step in steps {
+            let name = format!("{}{}", step.name, suffix);
+            if helpers::check_ctrlc(self.flag) {
+                results.push(StepResult {
+                    name,
+                    passed: false,
+                    message: Some(String::from("interrupted")),
+                });
+                return false;
+            }
+            if let Action::Repeat(count, inner) = &step.action {
+                for n in 1..=*count {
+                    let suffix = format!("{} ({}/{})", suffix, n, count);
+                    if !self.run_steps(inner, &suffix, results) {
+                        return false;
+                    }
+                }
+                continue;
+            }
+            let result = self.run_step(&step.action);
+            let passed = result.is_ok();
+            results.push(StepResult {
+                name,
+                passed,
+                message: result.unwrap_or_else(Some),
+            });
+            if !passed {
+                return false;
+            }
+        }
+        true
+    }
+
+    fn run_step(&mut self, action: &Action) -> Result<Option<String>, String> {
+        match action {
+            Action::Send(f) => {
+                let mut f = f.clone();
+                f.channel = self.channel;
+                // responses to earlier frames are not expected any more
+                self.drain();
+                self.last_send = Instant::now();
+                (self.send)(f)?;
+                Ok(None)
+            }
+            Action::Expect(p, timeout) => match self.wait_for(p, *timeout)? {
+                Some((t, _)) => {
+                    let latency = t.saturating_duration_since(self.last_send);
+                    Ok(Some(format!("received after {}", millis(latency))))
+                }
+                None => Err(format!("no matching frame within {}", millis(*timeout))),
+            },
+            Action::ExpectNone(p, timeout) => match self.wait_for(p, *timeout)? {
+                Some((t, f)) => {
+                    let latency = t.saturating_duration_since(self.last_send);
+                    Err(format!(
+                        "received {} after {}",
+                        describe(&f),
+                        millis(latency)
+                    ))
+                }
+                None => Ok(None),
+            },
+            Action::Wait(d) => {
+                if !helpers::sleep_until(Instant::now() + *d, self.flag) {
+                    return Err(String::from("interrupted"));
+                }
+                Ok(None)
+            }
+            // repeats are expanded by run_steps
+            Action::Repeat(_, _) => Ok(None),
+        }
+    }
+}
+
+fn print_result(t: &TestResult) {
+    let status = if t.failure().is_some() { "FAIL" } else { "ok" };
+    println!("{:4}  {} ({})", status, t.name, millis(t.time));
+    for s in t.steps.iter() {
+        let status = if s.passed { "ok" } else { "FAIL" };
+        match &s.message {
+            Some(m) => println!("      {:4}  {}: {}", status, s.name, m),
+            None => println!("      {:4}  {}", status, s.name),
+        }
+    }
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+// JUnit XML report, with a test case for each test and the steps in its output
+fn junit(suites: &[SuiteResult]) -> String {
+    let count = |s: &SuiteResult| s.tests.len();
+    let failures = |s: &SuiteResult| s.tests.iter().filter(|t| t.failure().is_some()).count();
+    let time = |s: &SuiteResult| s.tests.iter().map(|t| t.time.as_secs_f64()).sum::<f64>();
+
+    let mut x = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    let _ = writeln!(
+        x,
+        "<testsuites tests=\"{}\" failures=\"{}\" time=\"{:.3}\">",
+        suites.iter().map(count).sum::<usize>(),
+        suites.iter().map(failures).sum::<usize>(),
+        suites.iter().map(time).sum::<f64>()
+    );
+    for s in suites {
+        let _ = writeln!(
+            x,
+            "  <testsuite name=\"{}\" tests=\"{}\" failures=\"{}\" time=\"{:.3}\">",
+            escape(&s.name),
+            count(s),
+            failures(s),
+            time(s)
+        );
+        for t in s.tests.iter() {
+            let _ = writeln!(
+                x,
+                "    <testcase name=\"{}\" classname=\"{}\" time=\"{:.3}\">",
+                escape(&t.name),
+                escape(&s.name),
+                t.time.as_secs_f64()
+            );
+            if let Some(f) = t.failure() {
+                let _ = writeln!(
+                    x,
+                    "      <failure message=\"{}: {}\"/>",
+                    escape(&f.name),
+                    escape(f.message.as_deref().unwrap_or("failed"))
+                );
+            }
+            x.push_str("      <system-out>");
+            for step in t.steps.iter() {
+                let status = if step.passed { "ok" } else { "FAIL" };
+                let _ = write!(x, "\n{} {}", status, escape(&step.name));
+                if let Some(m) = &step.message {
+                    let _ = write!(x, ": {}", escape(m));
+                }
+            }
+            x.push_str("\n      </system-out>\n    </testcase>\n");
+        }
+        x.push_str("  </testsuite>\n");
+    }
+    x.push_str("</testsuites>\n");
+    x
+}
+
+fn read_script(path: &Path) -> Result<Script, Error> {
+    let s = fs::read_to_string(path)
+        .map_err(|e| Error::IoError(format!("could not read {}: {}", path.display(), e)))?;
+    parse_script(&s).map_err(|e| Error::InvalidArgument(format!("{}: {}", path.display(), e)))
+}
+
+pub fn cmd(matches: &ArgMatches) -> Result<(), Error> {
+    let flag = helpers::initialize_ctrlc()?;
+    let mut config = Config::read(matches)?;
+
+    // check every script before starting the device
+    let mut scripts = Vec::new();
+    for path in matches.values_of("scripts").into_iter().flatten() {
+        let path = Path::new(path);
+        scripts.push((path, read_script(path)?));
+    }
+    let channel = helpers::parse_channel(matches)?;
+
+    let mut i = Interface::new()?;
+    config.fit_to_interface(&i)?;
+    for (path, script) in scripts.iter() {
+        let ch = channel.unwrap_or(script.channel.unwrap_or(0) as usize);
+        match config.channels.get(ch) {
+            Some(c) if c.enabled => {}
+            _ => {
+                return Err(Error::InvalidArgument(format!(
+                    "{}: channel {} is not enabled",
+                    path.display(),
+                    ch
+                )))
+            }
+        }
+    }
+    info!("config: {:?}", config);
+    config.apply_to_interface(&mut i)?;
+
+    let (send, recv) = unbounded();
+    i.start(move |f: Frame| {
+        // frames sent by the tests are echoed back
+        if !f.loopback {
+            let _ = send.send((Instant::now(), f));
+        }
+    })?;
+
+    let mut suites = Vec::new();
+    {
+        let mut runner = Runner {
+            send: |f: Frame| i.send(f).map_err(|e| e.to_string()),
+            recv,
+            channel: 0,
+            last_send: Instant::now(),
+            received: Vec::new(),
+            flag: &flag,
+        };
+        for (path, script) in scripts.iter() {
+            runner.channel = channel.unwrap_or(script.channel.unwrap_or(0) as usize) as u8;
+            println!("{}", path.display());
+            let mut tests = Vec::new();
+            for test in script.tests.iter() {
+                let result = runner.run(test);
+                print_result(&result);
+                tests.push(result);
+            }
+            suites.push(SuiteResult {
+                name: path.display().to_string(),
+                tests,
+            });
+        }
+    }
+    i.stop()?;
+
+    let total: usize = suites.iter().map(|s| s.tests.len()).sum();
+    let failed = suites
+        .iter()
+        .flat_map(|s| s.tests.iter())
+        .filter(|t| t.failure().is_some())
+        .count();
+    println!("{} passed, {} failed", total - failed, failed);
+
+    if let Some(path) = matches.value_of("junit") {
+        fs::write(path, junit(&suites))
+            .map_err(|e| Error::IoError(format!("could not write {}: {}", path, e)))?;
+    }
+    match failed {
+        0 => Ok(()),
+        n => Err(Error::TestFailed(format!(
+            "{} of {} tests failed",
+            n, total
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crossbeam_channel::Sender;
+
+    const SCRIPT: &str = r#"
+        channel = 1
+        timeout_ms = 50
+
+        [[test]]
+        name = "diagnostic session"
+
+        [[test.step]]
+        send = "7E0#0210030000000000"
+
+        [[test.step]]
+        expect = "7E8#065003"
+        data_mask = [0xFF, 0xFF, 0x00]
+        timeout_ms = 20
+
+        [[test.step]]
+        expect_none = "7DF#"
+
+        [[test.step]]
+        repeat = 2
+
+        [[test.step.step]]
+        wait_ms = 1
+    "#;
+
+    fn frame(s: &str) -> Frame {
+        format::parse_log_line(s).unwrap()
+    }
+
+    // answers 7E0 with 7E8 on the same channel, after also sending `extra`
+    fn ecu(
+        responses: Sender<(Instant, Frame)>,
+        extra: Option<Frame>,
+    ) -> impl FnMut(Frame) -> Result<(), String> {
+        move |f: Frame| {
+            if let Some(e) = &extra {
+                responses.send((Instant::now(), e.clone())).unwrap();
+            }
+            if f.can_id == 0x7E0 {
+                let r = Frame {
+                    channel: f.channel,
+                    ..frame("7E8#0650FF")
+                };
+                responses.send((Instant::now(), r)).unwrap();
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_parse_script() {
+        let script = parse_script(SCRIPT).unwrap();
+        assert_eq!(script.channel, Some(1));
+        let steps = &script.tests[0].steps;
+        assert_eq!(steps.len(), 4);
+        assert_eq!(steps[1].name, "expect 7E8#065003");
+        match &steps[2].action {
+            Action::ExpectNone(p, timeout) => {
+                assert_eq!(p.id, 0x7DF);
+                assert_eq!(*timeout, Duration::from_millis(50));
+            }
+            a => panic!("unexpected action {:?}", a),
+        }
+        match &steps[3].action {
+            Action::Repeat(2, inner) => {
+                assert!(matches!(inner[0].action, Action::Wait(d) if d == Duration::from_millis(1)))
+            }
+            a => panic!("unexpected action {:?}", a),
+        }
+
+        let step = |s: &str| parse_script(&format!("[[test]]\nname = \"t\"\n[[test.step]]\n{}", s));
+        assert!(step("send = \"123#00\"\nexpect = \"123#00\"").is_err());
+        assert!(step("wait_ms = 10\ntimeout_ms = 10").is_err());
+        assert!(step("expect = \"123#0011\"\ndata_mask = [0xFF]").is_err());
+        assert!(step("send = \"123#00\"\nrepeat = 2").is_err());
+        assert!(step("sned = \"123#00\"").is_err());
+    }
+
+    #[test]
+    fn test_pattern() {
+        let p = Pattern::parse("7E8#0650", Some(0x7F0), Some(vec![0xFF, 0xF0])).unwrap();
+        assert!(p.matches(&frame("7E8#0650")));
+        assert!(p.matches(&frame("7E1#065FAA")));
+        assert!(!p.matches(&frame("7E8#06")));
+        assert!(!p.matches(&frame("7E8#0640")));
+        assert!(!p.matches(&frame("7F8#0650")));
+        assert!(!p.matches(&frame("000007E8#0650")));
+    }
+
+    #[test]
+    fn test_runner() {
+        let flag = Arc::new(AtomicBool::new(false));
+        let script = parse_script(SCRIPT).unwrap();
+
+        let (send, recv) = unbounded();
+        let mut runner = Runner {
+            send: ecu(send, None),
+            recv,
+            channel: 1,
+            last_send: Instant::now(),
+            received: Vec::new(),
+            flag: &flag,
+        };
+        let result = runner.run(&script.tests[0]);
+        assert!(result.failure().is_none(), "{:?}", result);
+        // the repeated step is run twice
+        assert_eq!(result.steps.len(), 5);
+        assert_eq!(result.steps[4].name, "wait 1 ms (2/2)");
+
+        // an unexpected frame fails the test, and later steps are not run
+        let unexpected = Frame {
+            channel: 1,
+            ..frame("7DF#01")
+        };
+        let (send, recv) = unbounded();
+        let mut runner = Runner {
+            send: ecu(send, Some(unexpected)),
+            recv,
+            channel: 1,
+            last_send: Instant::now(),
+            received: Vec::new(),
+            flag: &flag,
+        };
+        let result = runner.run(&script.tests[0]);
+        let failure = result.failure().unwrap();
+        assert_eq!(failure.name, "expect none 7DF#");
+        assert_eq!(result.steps.len(), 3);
+
+        let report = junit(&[SuiteResult {
+            name: String::from("ecu.toml"),
+            tests: vec![result],
+        }]);
+        assert!(report.contains("<testsuite name=\"ecu.toml\" tests=\"1\" failures=\"1\""));
+        assert!(report.contains("<failure message=\"expect none 7DF#: received can1 7DF#01"));
+    }
+
+    #[test]
+    fn test_runner_other_channels() {
+        let flag = Arc::new(AtomicBool::new(false));
+        let script = parse_script(SCRIPT).unwrap();
+
+        // a frame expected not to be received is ignored on other channels
+        let (send, recv) = unbounded();
+        let mut runner = Runner {
+            send: ecu(send, Some(frame("7DF#01"))),
+            recv,
+            channel: 1,
+            last_send: Instant::now(),
+            received: Vec::new(),
+            flag: &flag,
+        };
+        let result = runner.run(&script.tests[0]);
+        assert!(result.failure().is_none(), "{:?}", result);
+
+        // so is the expected response
+        let (send, recv) = unbounded();
+        let mut runner = Runner {
+            send: move |_: Frame| {
+                send.send((Instant::now(), frame("7E8#0650FF"))).unwrap();
+                Ok(())
+            },
+            recv,
+            channel: 1,
+            last_send: Instant::now(),
+            received: Vec::new(),
+            flag: &flag,
+        };
+        let result = runner.run(&script.tests[0]);
+        let failure = result.failure().unwrap();
+        assert!(failure.name.starts_with("expect 7E8"), "{:?}", failure);
+    }
+}
@@ -0,0 +1,285 @@
+use crate::Error;
+use cantact::{Frame, Interface, TimeBase};
+use clap::ArgMatches;
+use crossbeam_channel::{unbounded, RecvTimeoutError};
+use log::info;
+use std::fmt::Write;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use crate::analysis::{self, Analyzer, Event, Message, Nominal};
+use crate::config::Config;
+use crate::helpers;
+use crate::replay;
+
+// how often the main loop checks for missing messages and ctrl-c
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+// parse a nominal period given as ID=PERIOD
+fn parse_period(s: &str) -> Result<(u32, Duration), Error> {
+    let (id, period) = s.split_once('=').ok_or_else(|| {
+        Error::InvalidArgument(format!("invalid period {}, expected ID=PERIOD", s))
+    })?;
+    let (id, _) = helpers::parse_id(id.trim())?;
+    let period = helpers::parse_duration(period)?;
+    if period == Duration::from_secs(0) {
+        return Err(Error::InvalidArgument(format!(
+            "invalid period {}, must be greater than 0",
+            s
+        )));
+    }
+    Ok((id, period))
+}
+
+fn report(t: Duration, e: &Event) {
+    match e {
+        Event::Learned { .. } | Event::Aperiodic { .. } => info!("{}", e),
+        _ => println!("{:>10.3}  {}", t.as_secs_f64(), e),
+    }
+}
+
+fn ms(d: Option<Duration>) -> String {
+    d.map_or_else(|| String::from("-"), analysis::format_ms)
+}
+
+fn status(m: &Message) -> &'static str {
+    if m.missing {
+        return "missing";
+    }
+    match m.nominal {
+        Nominal::Learning(_) => "learning",
+        Nominal::Aperiodic => "not cyclic",
+        _ if m.violations() > 0 => "VIOLATION",
+        _ => "ok",
+    }
+}
+
+fn header() -> String {
+    format!(
+        "{:>3}  {:>8}  {:>8}  {:>10}  {:>10}  {:>10}  {:>10}  {:>10}  {:>5}  {:>5}  {:>7}  status",
+        "ch", "id", "count", "expected", "mean", "min", "max", "jitter", "late", "early", "missing"
+    )
+}
+
+fn row(key: &analysis::Key, m: &Message) -> String {
+    format!(
+        "{:>3}  {:>8}  {:>8}  {:>10}  {:>10}  {:>10}  {:>10}  {:>10}  {:>5}  {:>5}  {:>7}  {}",
+        key.0,
+        format!("{:03X}", key.1),
+        m.count,
+        ms(m.expected()),
+        ms(m.mean()),
+        ms(m.min),
+        ms(m.max),
+        ms(m.jitter()),
+        m.late,
+        m.early,
+        m.timeouts,
+        status(m)
+    )
+}
+
+fn histograms(a: &Analyzer) -> String {
+    let labels = analysis::histogram_labels();
+    let mut s = String::new();
+    for (key, m) in a.messages() {
+        let total: u64 = m.histogram.iter().sum();
+        if total == 0 {
+            continue;
+        }
+        let _ = writeln!(
+            s,
+            "ch:{} {:03X}, deviation from {}:",
+            key.0,
+            key.1,
+            ms(m.expected())
+        );
+        for (label, n) in labels.iter().zip(m.histogram.iter()) {
+            let bar = "#".repeat((n * 40).div_ceil(total) as usize);
+            let line = format!("  {:>10}  {:>8}  {}", label, n, bar);
+            let _ = writeln!(s, "{}", line.trim_end());
+        }
+    }
+    s
+}
+
+fn print_results(a: &Analyzer, show_histograms: bool) {
+    println!("{}", header());
+    for (key, m) in a.messages() {
+        println!("{}", row(key, m));
+    }
+    for id in a.unseen() {
+        println!("{:03X} has a nominal period but was never received", id);
+    }
+    if show_histograms {
+        print!("{}", histograms(a));
+    }
+}
+
+// analyze the frames of a log file, reporting messages missing at the end of
+// the log
+fn analyze_log(a: &mut Analyzer, path: &Path, channels: Option<Vec<usize>>) -> Result<(), Error> {
+    let mut first = None;
+    let mut last = None;
+    for f in replay::open(path)? {
+        let f = f.map_err(|e| Error::InvalidArgument(format!("{}: {}", path.display(), e)))?;
+        if channels
+            .as_ref()
+            .is_some_and(|c| !c.contains(&(f.channel as usize)))
+        {
+            continue;
+        }
+        let t = match f.timestamp {
+            Some(t) => t,
+            None => continue,
+        };
+        let t0 = *first.get_or_insert(t);
+        for e in a.check(t) {
+            report(t.saturating_sub(t0), &e);
+        }
+        if let Some(e) = a.add(&f) {
+            report(t.saturating_sub(t0), &e);
+        }
+        last = Some(t);
+    }
+    if let (Some(t0), Some(t)) = (first, last) {
+        for e in a.check(t) {
+            report(t.saturating_sub(t0), &e);
+        }
+    }
+    Ok(())
+}
+
+fn analyze_live(
+    a: &mut Analyzer,
+    matches: &ArgMatches,
+    channels: Option<Vec<usize>>,
+    duration: Option<Duration>,
+) -> Result<(), Error> {
+    let flag = helpers::initialize_ctrlc()?;
+    let mut config = Config::read(matches)?;
+
+    // initialize the interface
+    let mut i = Interface::new()?;
+    config.fit_to_interface(&i)?;
+    if let Some(channels) = channels {
+        config.select_channels(&channels)?;
+    }
+    info!("config: {:?}", config);
+    config.apply_to_interface(&mut i)?;
+    // frames are checked against the time of the same clock
+    let base = TimeBase::now();
+    i.set_time_base(base);
+
+    let (send, recv) = unbounded();
+    i.start(move |f: Frame| {
+        let _ = send.send(f);
+    })?;
+
+    let deadline = duration.map(|d| Instant::now() + d);
+    while !helpers::check_ctrlc(&flag) && deadline.is_none_or(|d| Instant::now() < d) {
+        // handle every frame received before checking for missing messages
+        let mut next = recv.recv_timeout(POLL_INTERVAL);
+        while let Ok(f) = next {
+            if let (Some(e), Some(t)) = (a.add(&f), f.timestamp) {
+                report(t, &e);
+            }
+            next = recv.try_recv().map_err(|_| RecvTimeoutError::Timeout);
+        }
+        if let Err(RecvTimeoutError::Disconnected) = next {
+            break;
+        }
+        let now = base.elapsed(Instant::now());
+        for e in a.check(now) {
+            report(now, &e);
+        }
+    }
+
+    i.stop()?;
+    Ok(())
+}
+
+pub fn cmd(matches: &ArgMatches) -> Result<(), Error> {
+    let channels = helpers::parse_channels(matches)?;
+    let tolerance = match helpers::parse_arg::<f64>(matches, "tolerance")? {
+        Some(t) if t >= 0.0 && t.is_finite() => t / 100.0,
+        Some(_) => {
+            return Err(Error::InvalidArgument(String::from(
+                "invalid tolerance value",
+            )))
+        }
+        None => 0.1,
+    };
+    let timeout = match helpers::parse_arg::<f64>(matches, "timeout")? {
+        Some(t) if t >= 1.0 && t.is_finite() => t,
+        Some(_) => {
+            return Err(Error::InvalidArgument(String::from(
+                "timeout must be at least 1 period",
+            )))
+        }
+        None => 3.0,
+    };
+    let duration = match matches.value_of("duration") {
+        Some(s) => Some(helpers::parse_duration(s)?),
+        None => None,
+    };
+
+    let mut a = Analyzer::new(tolerance, timeout);
+    for p in matches.values_of("period").into_iter().flatten() {
+        let (id, period) = parse_period(p)?;
+        a.expect(id, period);
+    }
+
+    match matches.value_of("file") {
+        Some(path) => analyze_log(&mut a, Path::new(path), channels)?,
+        None => analyze_live(&mut a, matches, channels, duration)?,
+    }
+
+    print_results(&a, matches.is_present("histogram"));
+    match a.violations() {
+        0 => Ok(()),
+        n => Err(Error::TimingViolation(format!("{} timing violations", n))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_period() {
+        assert_eq!(
+            parse_period("0x123=100ms").unwrap(),
+            (0x123, Duration::from_millis(100))
+        );
+        assert_eq!(
+            parse_period("18FEF100 = 1s").unwrap(),
+            (0x18FE_F100, Duration::from_secs(1))
+        );
+        assert!(parse_period("123").is_err());
+        assert!(parse_period("123=0").is_err());
+        assert!(parse_period("XYZ=10ms").is_err());
+    }
+
+    #[test]
+    fn test_table() {
+        let mut a = Analyzer::new(0.1, 3.0);
+        a.expect(0x100, Duration::from_millis(10));
+        for t in [0, 10, 20, 35] {
+            a.add(&Frame {
+                can_id: 0x100,
+                timestamp: Some(Duration::from_millis(t)),
+                ..Frame::default()
+            });
+        }
+        let (key, m) = a.messages().next().unwrap();
+        let line = row(key, m);
+        assert_eq!(
+            line.len(),
+            header().len() - "status".len() + "VIOLATION".len()
+        );
+        assert!(line.contains("  10.0 ms  "));
+        assert!(line.ends_with("1      0        0  VIOLATION"));
+        assert!(histograms(&a).contains("ch:0 100, deviation from 10.0 ms:"));
+    }
+}
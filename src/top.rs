@@ -0,0 +1,514 @@
+use crate::Error;
+use cantact::{Frame, Interface};
+use clap::ArgMatches;
+use crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use crossterm::style::{Attribute, Color, Print, ResetColor, SetAttribute, SetForegroundColor};
+use crossterm::{cursor, queue, terminal};
+use log::info;
+use std::collections::{BTreeMap, HashSet};
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::config::Config;
+use crate::filter::Filter;
+use crate::helpers;
+
+// time between screen updates
+const REFRESH: Duration = Duration::from_millis(100);
+// bytes and bits that changed more recently than this are highlighted
+const HIGHLIGHT: Duration = Duration::from_secs(2);
+
+type Key = (u8, u32);
+
+// the latest frame and timing statistics for one (channel, id)
+struct Row {
+    frame: Frame,
+    count: u64,
+    last: Duration,
+    // period statistics over time between frames, in seconds
+    periods: u64,
+    mean: f64,
+    m2: f64,
+    // time of the last change, and the bits that changed, for each byte
+    changed: Vec<Option<(Instant, u8)>>,
+}
+
+impl Row {
+    fn new(frame: Frame, t: Duration) -> Row {
+        Row {
+            frame,
+            count: 1,
+            last: t,
+            periods: 0,
+            mean: 0.0,
+            m2: 0.0,
+            changed: vec![None; 64],
+        }
+    }
+
+    fn update(&mut self, f: Frame, t: Duration, now: Instant) {
+        let period = t.saturating_sub(self.last).as_secs_f64();
+        // Welford's method, so the variance is stable over long captures
+        self.periods += 1;
+        let delta = period - self.mean;
+        self.mean += delta / self.periods as f64;
+        self.m2 += delta * (period - self.mean);
+
+        let len = std::cmp::max(f.data_len(), self.frame.data_len());
+        for n in 0..len {
+            let bits = f.data[n] ^ self.frame.data[n];
+            if bits != 0 {
+                self.changed[n] = Some((now, bits));
+            }
+        }
+
+        self.count += 1;
+        self.last = t;
+        self.frame = f;
+    }
+
+    fn period(&self) -> Option<f64> {
+        if self.periods > 0 {
+            Some(self.mean)
+        } else {
+            None
+        }
+    }
+
+    // standard deviation of the period
+    fn jitter(&self) -> Option<f64> {
+        if self.periods > 0 {
+            Some((self.m2 / self.periods as f64).sqrt())
+        } else {
+            None
+        }
+    }
+
+    // time since any byte changed
+    fn last_change(&self) -> Option<Instant> {
+        self.changed.iter().flatten().map(|(t, _)| *t).max()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Sort {
+    Id,
+    Count,
+    Period,
+    Jitter,
+    Changed,
+}
+impl Sort {
+    fn next(self) -> Sort {
+        match self {
+            Sort::Id => Sort::Count,
+            Sort::Count => Sort::Period,
+            Sort::Period => Sort::Jitter,
+            Sort::Jitter => Sort::Changed,
+            Sort::Changed => Sort::Id,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Sort::Id => "id",
+            Sort::Count => "count",
+            Sort::Period => "period",
+            Sort::Jitter => "jitter",
+            Sort::Changed => "changed",
+        }
+    }
+}
+
+// user interface state, separate from the received data
+struct View {
+    sort: Sort,
+    reverse: bool,
+    bits: bool,
+    selected: usize,
+    scroll: usize,
+    filter: Option<Filter>,
+    noise: HashSet<Key>,
+    show_noise: bool,
+    // text of a filter being entered
+    input: Option<String>,
+    status: String,
+}
+
+impl View {
+    fn new(filter: Option<Filter>) -> View {
+        View {
+            sort: Sort::Id,
+            reverse: false,
+            bits: false,
+            selected: 0,
+            scroll: 0,
+            filter,
+            noise: HashSet::new(),
+            show_noise: false,
+            input: None,
+            status: String::new(),
+        }
+    }
+
+    // returns the keys of the rows to display, in order
+    fn rows(&self, rows: &BTreeMap<Key, Row>) -> Vec<Key> {
+        let mut keys: Vec<Key> = rows
+            .iter()
+            .filter(|(k, _)| self.show_noise || !self.noise.contains(k))
+            .filter(|(_, r)| self.filter.as_ref().is_none_or(|f| f.matches(&r.frame)))
+            .map(|(k, _)| *k)
+            .collect();
+
+        let cmp_f64 = |a: Option<f64>, b: Option<f64>| {
+            a.unwrap_or(f64::MAX)
+                .partial_cmp(&b.unwrap_or(f64::MAX))
+                .unwrap_or(std::cmp::Ordering::Equal)
+        };
+        match self.sort {
+            Sort::Id => {}
+            Sort::Count => keys.sort_by_key(|k| std::cmp::Reverse(rows[k].count)),
+            Sort::Period => keys.sort_by(|a, b| cmp_f64(rows[a].period(), rows[b].period())),
+            Sort::Jitter => keys.sort_by(|a, b| cmp_f64(rows[a].jitter(), rows[b].jitter())),
+            Sort::Changed => {
+                keys.sort_by_key(|k| std::cmp::Reverse(rows[k].last_change()));
+            }
+        }
+        if self.reverse {
+            keys.reverse();
+        }
+        keys
+    }
+
+    // handle a key press, returning false to quit
+    fn key(&mut self, key: KeyEvent, shown: &[Key]) -> bool {
+        if let Some(input) = self.input.as_mut() {
+            match key.code {
+                KeyCode::Enter => {
+                    let s = self.input.take().unwrap();
+                    if s.trim().is_empty() {
+                        self.filter = None;
+                        self.status.clear();
+                    } else {
+                        match Filter::parse(&s) {
+                            Ok(f) => {
+                                self.filter = Some(f);
+                                self.status = format!("filter: {}", s);
+                            }
+                            Err(e) => self.status = format!("invalid filter: {}", e),
+                        }
+                    }
+                }
+                KeyCode::Esc => self.input = None,
+                KeyCode::Backspace => {
+                    input.pop();
+                }
+                KeyCode::Char(c) => input.push(c),
+                _ => {}
+            }
+            return true;
+        }
+
+        let ctrl = key.modifiers.contains(KeyModifiers::CONTROL);
+        match key.code {
+            KeyCode::Char('c') if ctrl => return false,
+            KeyCode::Char('q') | KeyCode::Esc => return false,
+            KeyCode::Up => self.selected = self.selected.saturating_sub(1),
+            KeyCode::Down => self.selected += 1,
+            KeyCode::PageUp => self.selected = self.selected.saturating_sub(20),
+            KeyCode::PageDown => self.selected += 20,
+            KeyCode::Home => self.selected = 0,
+            KeyCode::End => self.selected = shown.len().saturating_sub(1),
+            KeyCode::Char('s') => self.sort = self.sort.next(),
+            KeyCode::Char('r') => self.reverse = !self.reverse,
+            KeyCode::Char('b') => self.bits = !self.bits,
+            KeyCode::Char('/') => self.input = Some(String::new()),
+            KeyCode::Char('n') => {
+                if let Some(k) = shown.get(self.selected) {
+                    if !self.noise.remove(k) {
+                        self.noise.insert(*k);
+                    }
+                }
+            }
+            KeyCode::Char('h') => self.show_noise = !self.show_noise,
+            KeyCode::Char('u') => self.noise.clear(),
+            _ => {}
+        }
+        true
+    }
+}
+
+fn format_ms(v: Option<f64>) -> String {
+    match v {
+        Some(v) => format!("{:10.3}", v * 1000.0),
+        None => format!("{:>10}", "-"),
+    }
+}
+
+fn draw(
+    out: &mut impl Write,
+    rows: &BTreeMap<Key, Row>,
+    keys: &[Key],
+    view: &mut View,
+    now: Instant,
+) -> io::Result<()> {
+    let (_, height) = terminal::size()?;
+    // title, column headers, and status lines
+    let visible = (height as usize).saturating_sub(3).max(1);
+    view.selected = view.selected.min(keys.len().saturating_sub(1));
+    if view.selected < view.scroll {
+        view.scroll = view.selected;
+    } else if view.selected >= view.scroll + visible {
+        view.scroll = view.selected + 1 - visible;
+    }
+
+    queue!(
+        out,
+        cursor::MoveTo(0, 0),
+        terminal::Clear(terminal::ClearType::All),
+        SetAttribute(Attribute::Bold),
+        Print(format!(
+            "can top: {} ids, {} shown, {} noise, sort by {}{}",
+            rows.len(),
+            keys.len(),
+            view.noise.len(),
+            view.sort.name(),
+            if view.reverse { " (reversed)" } else { "" }
+        )),
+        cursor::MoveTo(0, 1),
+        Print("ch       id  dlc      count period(ms) jitter(ms)  data"),
+        SetAttribute(Attribute::Reset),
+    )?;
+
+    for (line, k) in keys.iter().skip(view.scroll).take(visible).enumerate() {
+        let r = &rows[k];
+        let f = &r.frame;
+        queue!(out, cursor::MoveTo(0, line as u16 + 2))?;
+        if view.scroll + line == view.selected {
+            queue!(out, SetAttribute(Attribute::Reverse))?;
+        }
+        if view.noise.contains(k) {
+            queue!(out, SetAttribute(Attribute::Dim))?;
+        }
+        let id = if f.ext {
+            format!("{:08X}", f.can_id)
+        } else {
+            format!("{:03X}", f.can_id)
+        };
+        queue!(
+            out,
+            Print(format!(
+                "{:2} {:>8}  {:3} {:10} {} {}  ",
+                f.channel,
+                id,
+                f.can_dlc,
+                r.count,
+                format_ms(r.period()),
+                format_ms(r.jitter())
+            ))
+        )?;
+
+        if f.rtr {
+            queue!(out, Print("remote request"))?;
+        }
+        let len = if f.rtr { 0 } else { f.data_len() };
+        for n in 0..len {
+            let recent = r.changed[n].filter(|(t, _)| now.duration_since(*t) < HIGHLIGHT);
+            if view.bits {
+                for bit in (0..8).rev() {
+                    let changed = recent.is_some_and(|(_, bits)| bits & (1 << bit) != 0);
+                    if changed {
+                        queue!(out, SetForegroundColor(Color::Red))?;
+                    }
+                    queue!(out, Print((f.data[n] >> bit) & 1))?;
+                    if changed {
+                        queue!(out, SetForegroundColor(Color::Reset))?;
+                    }
+                }
+                queue!(out, Print(" "))?;
+            } else {
+                if recent.is_some() {
+                    queue!(out, SetForegroundColor(Color::Red))?;
+                }
+                queue!(out, Print(format!("{:02X}", f.data[n])))?;
+                if recent.is_some() {
+                    queue!(out, SetForegroundColor(Color::Reset))?;
+                }
+                queue!(out, Print(" "))?;
+            }
+        }
+        queue!(out, SetAttribute(Attribute::Reset), ResetColor)?;
+    }
+
+    let status = match &view.input {
+        Some(s) => format!("filter: {}", s),
+        None if !view.status.is_empty() => view.status.clone(),
+        None => String::from(
+            "q quit  s sort  r reverse  b bits  / filter  n mark noise  h show noise  u unmark all",
+        ),
+    };
+    queue!(
+        out,
+        cursor::MoveTo(0, height.saturating_sub(1)),
+        Print(status)
+    )?;
+    out.flush()
+}
+
+// restores the terminal when dropped, including on errors
+struct Screen;
+impl Screen {
+    fn enter() -> io::Result<Screen> {
+        terminal::enable_raw_mode()?;
+        let mut out = io::stdout();
+        if let Err(e) = crossterm::execute!(
+            out,
+            terminal::EnterAlternateScreen,
+            terminal::DisableLineWrap,
+            cursor::Hide
+        ) {
+            let _ = terminal::disable_raw_mode();
+            return Err(e);
+        }
+        Ok(Screen)
+    }
+}
+impl Drop for Screen {
+    fn drop(&mut self) {
+        let _ = crossterm::execute!(
+            io::stdout(),
+            cursor::Show,
+            terminal::EnableLineWrap,
+            terminal::LeaveAlternateScreen
+        );
+        let _ = terminal::disable_raw_mode();
+    }
+}
+
+fn run(rows: &Mutex<BTreeMap<Key, Row>>, mut view: View) -> io::Result<()> {
+    let _screen = Screen::enter()?;
+    let mut out = io::stdout();
+    loop {
+        let now = Instant::now();
+        let keys = {
+            let rows = rows.lock().unwrap();
+            let keys = view.rows(&rows);
+            draw(&mut out, &rows, &keys, &mut view, now)?;
+            keys
+        };
+
+        if event::poll(REFRESH)? {
+            if let Event::Key(key) = event::read()? {
+                if key.kind != KeyEventKind::Release && !view.key(key, &keys) {
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+pub fn cmd(matches: &ArgMatches) -> Result<(), Error> {
+    let mut config = Config::read(matches)?;
+
+    let channels = helpers::parse_channels(matches)?;
+
+    let filter = match matches.value_of("filter") {
+        Some(s) => Some(
+            Filter::parse(s)
+                .map_err(|e| Error::InvalidArgument(format!("invalid filter: {}", e)))?,
+        ),
+        None => None,
+    };
+
+    // initialize the interface
+    let mut i = Interface::new()?;
+    config.fit_to_interface(&i)?;
+    if let Some(channels) = channels {
+        // channels specified, disable all others
+        config.select_channels(&channels)?;
+    }
+    info!("config: {:?}", config);
+    config.apply_to_interface(&mut i)?;
+
+    let rows: Arc<Mutex<BTreeMap<Key, Row>>> = Arc::new(Mutex::new(BTreeMap::new()));
+    let r = Arc::clone(&rows);
+    let start = Instant::now();
+
+    info!("starting top");
+    i.start(move |f: Frame| {
+        let now = Instant::now();
+        let t = f.timestamp.unwrap_or_else(|| now.duration_since(start));
+        let key = (f.channel, f.can_id | if f.ext { 0x8000_0000 } else { 0 });
+        let mut rows = r.lock().unwrap();
+        match rows.get_mut(&key) {
+            Some(row) => row.update(f, t, now),
+            None => {
+                rows.insert(key, Row::new(f, t));
+            }
+        }
+    })?;
+
+    let result = run(&rows, View::new(filter));
+
+    i.stop()?;
+    result.map_err(|e| Error::InvalidArgument(format!("terminal error: {}", e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(can_id: u32, data: &[u8]) -> Frame {
+        let mut f = Frame {
+            can_id,
+            can_dlc: data.len() as u8,
+            ..Frame::default()
+        };
+        f.data[..data.len()].copy_from_slice(data);
+        f
+    }
+
+    #[test]
+    fn test_row_statistics() {
+        let now = Instant::now();
+        let mut row = Row::new(frame(0x100, &[0x00, 0xFF]), Duration::from_millis(0));
+        assert_eq!(row.period(), None);
+
+        row.update(frame(0x100, &[0x00, 0xFF]), Duration::from_millis(10), now);
+        assert!(row.last_change().is_none());
+        row.update(frame(0x100, &[0x01, 0xFF]), Duration::from_millis(30), now);
+        row.update(frame(0x100, &[0x01, 0x7F]), Duration::from_millis(40), now);
+
+        assert_eq!(row.count, 4);
+        assert!((row.period().unwrap() - 0.040 / 3.0).abs() < 1e-9);
+        // periods of 10, 20 and 10 ms
+        let expected = ((2.0 * (1.0f64 / 3.0).powi(2) + (2.0f64 / 3.0).powi(2)) / 3.0).sqrt();
+        assert!((row.jitter().unwrap() - expected / 100.0).abs() < 1e-9);
+        assert_eq!(row.changed[0].unwrap().1, 0x01);
+        assert_eq!(row.changed[1].unwrap().1, 0x80);
+    }
+
+    #[test]
+    fn test_view_rows() {
+        let mut rows = BTreeMap::new();
+        for (id, count) in &[(0x300, 1), (0x100, 3), (0x200, 2)] {
+            let mut row = Row::new(frame(*id, &[*id as u8]), Duration::from_millis(0));
+            row.count = *count;
+            rows.insert((0, *id), row);
+        }
+        let mut view = View::new(None);
+        let ids = |view: &View| -> Vec<u32> { view.rows(&rows).iter().map(|k| k.1).collect() };
+        assert_eq!(ids(&view), vec![0x100, 0x200, 0x300]);
+
+        view.sort = Sort::Count;
+        view.reverse = true;
+        assert_eq!(ids(&view), vec![0x300, 0x200, 0x100]);
+
+        view.noise.insert((0, 0x200));
+        assert_eq!(ids(&view), vec![0x300, 0x100]);
+        view.show_noise = true;
+        view.filter = Some(Filter::parse("id >= 0x200").unwrap());
+        assert_eq!(ids(&view), vec![0x300, 0x200]);
+    }
+}